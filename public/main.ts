@@ -1,19 +1,52 @@
 let ws: WebSocket;
 
-const LOGIN_KEY = "zz";
+const CMD_HELLO = 4;
+const CMD_VERSION = 6;
+const CMD_EDIT_CELLS = 9;
+const CMD_STAMP = 10;
+// Protocol version and capability flags of the page (1: applies delta grids)
+const PROTOCOL_VERSION = 6;
+// Grids carry their generation and the server time from this version on
+const GENERATION_PROTOCOL_VERSION = 4;
+// Servers take cell edits and stamps from this version on
+const EDIT_PROTOCOL_VERSION = 5;
+// Delta grid cells are varints from this version on, u16 before
+const VARINT_DELTA_PROTOCOL_VERSION = 6;
+const EDIT_TOGGLE = 2;
+const GLIDER_RLE = "bo$2bo$3o!";
+// Version the server answered with
+let serverVersion = PROTOCOL_VERSION;
+let generation = 0;
+const CAPABILITIES = 0b1;
+// Encoding ids the page can decode, preferred first (varint RLE, RLE, binary, none)
+const SUPPORTED_ENCODINGS = [3, 2, 1, 0];
 
 let total_msgs = 0;
+// The server picks it if it speaks it, older ones ignore it
+const WS_SUBPROTOCOL = "gol-multi";
+// Server to use when the page is opened from a file rather than served by the server itself
+const FALLBACK_WS_URL = "ws://localhost:42069/";
+
+// The server serves the page on its WebSocket port, so the WebSocket is wherever the page came from
+function webSocketUrl(): string {
+    if (location.protocol === "http:" || location.protocol === "https:") {
+        const scheme = location.protocol === "https:" ? "wss:" : "ws:";
+        return `${scheme}//${location.host}/`;
+    }
+    return FALLBACK_WS_URL;
+}
 
 function setUpWebSocket() {
     console.info("Starting ws connection");
-    ws = new WebSocket("ws://0.0.0.0:42069");
+    ws = new WebSocket(webSocketUrl(), [WS_SUBPROTOCOL]);
     ws.addEventListener("open", (_e) => {
         console.debug(`Connected`);
-        let utf8Encode = new TextEncoder();
-        let data = utf8Encode.encode(LOGIN_KEY);
-        // console.debug(data.byteLength);
-        // console.debug(data);
-        ws.send(data);
+        // The version has to come first, both go in the same frame
+        const handshake = new Uint8Array([
+            CMD_VERSION, 0, 2, PROTOCOL_VERSION, CAPABILITIES,
+            CMD_HELLO, 0, SUPPORTED_ENCODINGS.length, ...SUPPORTED_ENCODINGS,
+        ]);
+        ws.send(handshake);
     });
 
     ws.addEventListener("message", (e) => {
@@ -54,6 +87,9 @@ const CMD_SIZE = 1;
 const SIZE_IDX = 1;
 const SIZE_SIZE = 2;
 const MSG_IDX = 3;
+// A 0xFFFF size means the actual size is in the next 4 bytes
+const EXTENDED_SIZE = 0xffff;
+const EXTENDED_SIZE_SIZE = 4;
 
 function parseData(data: ArrayBuffer) {
     console.debug("------------------");
@@ -61,22 +97,75 @@ function parseData(data: ArrayBuffer) {
     const view = new DataView(data);
 
     const cmd = view.getUint8(CMD_IDX);
-    const size = view.getUint16(SIZE_IDX);
-    const msg_view = new DataView(data, 3, size);
+    let size = view.getUint16(SIZE_IDX);
+    let msg_idx = MSG_IDX;
+    if (size == EXTENDED_SIZE) {
+        size = view.getUint32(MSG_IDX);
+        msg_idx += EXTENDED_SIZE_SIZE;
+    }
+    const msg_view = new DataView(data, msg_idx, size);
 
     console.debug(`Received cmd[${cmd}]`);
 
-    msgProcessor[cmd](msg_view);
+    const processor = msgProcessor[cmd];
+    if (processor === undefined) {
+        console.error(`Unsupported cmd: ${cmd}`);
+        return;
+    }
+    processor(msg_view);
     console.debug("------------------");
 }
 
 const msgProcessor = {
-    //0: newGridBinary,
-    0: newGridRLE,
+    0: newGrid,
     1: debugMsg,
     2: dimensions,
+    3: deltaGrid,
+    5: encoding,
+    6: version,
+    7: serverError,
 };
 
+function version(data: DataView) {
+    serverVersion = data.getUint8(0);
+    console.info(`Server speaks protocol version ${serverVersion} with capabilities ${data.getUint8(1)}`);
+}
+
+// LEB128 varint at `i`, along with the index right after it
+function readVarint(data: DataView, i: number): [number, number] {
+    let value = 0;
+    let shift = 0;
+    let byte;
+    do {
+        byte = data.getUint8(i++);
+        value += (byte & 0x7f) * 2 ** shift;
+        shift += 7;
+    } while (byte & 0x80);
+    return [value, i];
+}
+
+// Generation and server timestamp at the start of a grid, returns what follows them
+function readFrame(data: DataView): DataView {
+    if (serverVersion < GENERATION_PROTOCOL_VERSION) {
+        return data;
+    }
+    let i = 0;
+    [generation, i] = readVarint(data, i);
+    const [timestamp, start] = readVarint(data, i);
+    console.debug(`Generation ${generation} sent at ${new Date(timestamp).toISOString()}`);
+    return new DataView(data.buffer, data.byteOffset + start, data.byteLength - start);
+}
+
+// The server closes the connection right after
+function serverError(data: DataView) {
+    const reason = new TextDecoder().decode(new DataView(data.buffer, data.byteOffset + 1, data.byteLength - 1));
+    console.error(`Server refused the connection (${data.getUint8(0)}): ${reason}`);
+}
+
+function encoding(data: DataView) {
+    console.info(`Server will send grids with encoding ${data.getUint8(0)}`);
+}
+
 function debugMsg(data: DataView) {
     console.log(new TextDecoder().decode(data));
 }
@@ -110,6 +199,34 @@ function dimensions(data: DataView) {
     ctx.fillRect(0, 0, canvas.width, canvas.height);
 }
 
+// First byte of a new grid is the encoding the server used for it
+const gridDecoders = {
+    0: newGridNone,
+    1: newGridBinary,
+    2: newGridRLE,
+    3: newGridVarintRLE,
+};
+
+function newGrid(data: DataView) {
+    data = readFrame(data);
+    const encoding = data.getUint8(0);
+    const decoder = gridDecoders[encoding];
+    if (decoder === undefined) {
+        console.error(`Unsupported grid encoding: ${encoding}`);
+        return;
+    }
+    decoder(new DataView(data.buffer, data.byteOffset + 1, data.byteLength - 1));
+}
+
+function newGridNone(data: DataView) {
+    console.debug(`New grid received`);
+    for (let i = 0; i < data.byteLength; i++) {
+        GRID[i] = data.getUint8(i);
+    }
+    logGrid();
+    drawGrid();
+}
+
 function newGridBinary(data: DataView) {
     console.debug(`New grid received`);
     for (let i = 0; i < data.byteLength; i++) {
@@ -158,6 +275,63 @@ function newGridRLE(data: DataView) {
     drawGrid();
 }
 
+// Indexes of the cells that flipped since the last grid, each a varint of its distance from the one before (every
+// 2 bytes from older servers)
+function deltaGrid(data: DataView) {
+    console.debug(`Delta grid received`);
+    data = readFrame(data);
+    if (serverVersion < VARINT_DELTA_PROTOCOL_VERSION) {
+        for (let i = 0; i + 1 < data.byteLength; i += 2) {
+            GRID[data.getUint16(i)] ^= 1;
+        }
+    } else {
+        let cell = 0;
+        let i = 0;
+        while (i < data.byteLength) {
+            let gap;
+            [gap, i] = readVarint(data, i);
+            cell = (cell + gap) % 2 ** 32;
+            GRID[cell] ^= 1;
+        }
+    }
+    logGrid();
+    drawGrid();
+}
+
+// Runs alternate between dead and alive cells (starting with dead), each length a LEB128 varint
+function newGridVarintRLE(data: DataView) {
+    console.debug(`New grid received`);
+    let i = 0;
+    let grid_idx = 0;
+    let value = 0;
+    while (i < data.byteLength) {
+        let count = 0;
+        let shift = 0;
+        let byte;
+        do {
+            if (i >= data.byteLength) {
+                console.error(`Grid ended in the middle of a run length`);
+                return;
+            }
+            byte = data.getUint8(i++);
+            count += (byte & 0x7f) * 2 ** shift;
+            shift += 7;
+        } while (byte & 0x80);
+
+        if (grid_idx + count > GRID.length) {
+            console.error(`Run of ${count} cells goes past the grid`);
+            return;
+        }
+        for (let j = 0; j < count; j++) {
+            GRID[grid_idx++] = value;
+        }
+        value ^= 1;
+    }
+
+    logGrid();
+    drawGrid();
+}
+
 function logGrid() {
     let gridStr = "\n";
     for (let h = 0; h < canvas.height / CELL_HEIGHT; h++) {
@@ -183,4 +357,45 @@ function drawGrid() {
             }
         }
     }
+    drawGeneration();
+}
+
+// Click toggles a cell, shift-click places a glider with its top-left corner there. The grid isn't changed here,
+// the server applies the edit between two generations and it comes back with a later grid
+canvas.addEventListener("click", (e) => {
+    if (serverVersion < EDIT_PROTOCOL_VERSION || ws.readyState !== WebSocket.OPEN) {
+        return;
+    }
+    const x = Math.floor(e.offsetX / CELL_WIDTH);
+    const y = Math.floor(e.offsetY / CELL_HEIGHT);
+    if (x >= GRID_WIDTH || y >= GRID_HEIGHT) {
+        return;
+    }
+    const rle = new TextEncoder().encode(GLIDER_RLE);
+    // [cmd][size u16][content], the content starting with the action or the position
+    const msg = e.shiftKey ? new Uint8Array(3 + 4 + rle.length) : new Uint8Array(3 + 5);
+    const view = new DataView(msg.buffer);
+    if (e.shiftKey) {
+        view.setUint8(0, CMD_STAMP);
+        view.setUint16(1, 4 + rle.length);
+        view.setUint16(3, x);
+        view.setUint16(5, y);
+        msg.set(rle, 7);
+    } else {
+        view.setUint8(0, CMD_EDIT_CELLS);
+        view.setUint16(1, 5);
+        view.setUint8(3, EDIT_TOGGLE);
+        view.setUint16(4, x);
+        view.setUint16(6, y);
+    }
+    ws.send(msg);
+});
+
+function drawGeneration() {
+    if (serverVersion < GENERATION_PROTOCOL_VERSION) {
+        return;
+    }
+    ctx.font = "16px monospace";
+    ctx.fillStyle = "gray";
+    ctx.fillText(`generation ${generation}`, 8, canvas.height - 8);
 }
@@ -0,0 +1,443 @@
+use crate::checkpoint::{RULE, TOPOLOGY};
+use crate::game::{GRID_HEIGHT, GRID_WIDTH};
+use crate::http::{Request, Response};
+use crate::json::Json;
+use crate::metrics::METRICS_PATH;
+use crate::pattern::Pattern;
+use crate::protocol::{Message, EDIT_CLEAR, EDIT_SET, EDIT_TOGGLE};
+
+// JSON API served on the WebSocket port, to watch and drive the game without the server terminal:
+//
+//   GET  /api/state                  dimensions, rule, generation, population, connections and whether it's paused
+//   GET  /api/grid?format=cells|rle  live cells as [x, y] pairs (the default), or the whole board as RLE
+//   POST /api/cells    {"set": [[x, y], ...], "clear": [...], "toggle": [...]}
+//   POST /api/pause    {"paused": true|false}, pauses when the body is empty
+//   POST /api/step     {"generations": n}, one when the body is empty. Only while paused, and stops early if it
+//                      takes more than half a frame
+//   POST /api/pattern  {"rle": "...", "x": 0, "y": 0} places an RLE pattern with its top-left corner at (x, y)
+//   GET  /metrics                    statistics in the Prometheus text format, see the metrics module
+//
+// Requests are answered by the game loop between two generations, so what they see and change is a whole
+// generation. POSTs answer with the state after the change. Errors are {"error": "..."} with a 4xx status.
+//
+// Cell edits and stamps that clients send over the game protocol go through the same calls, see client_call.
+
+pub const API_PREFIX: &str = "/api/";
+// Generations a single step call can ask for, so a typo can't hold the game loop for minutes
+pub const MAX_STEPS: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GridView {
+    Cells,
+    Rle,
+}
+
+/// Cells to set alive, kill and toggle, in that order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CellEdit {
+    pub set: Vec<(usize, usize)>,
+    pub clear: Vec<(usize, usize)>,
+    pub toggle: Vec<(usize, usize)>,
+}
+
+impl CellEdit {
+    /// Applies the edit to a GRID_WIDTH * GRID_HEIGHT board.
+    pub fn apply(&self, board: &mut [u8]) {
+        for &(x, y) in &self.set {
+            board[x + GRID_WIDTH * y] = 1;
+        }
+        for &(x, y) in &self.clear {
+            board[x + GRID_WIDTH * y] = 0;
+        }
+        for &(x, y) in &self.toggle {
+            board[x + GRID_WIDTH * y] ^= 1;
+        }
+    }
+}
+
+/// What an API request asks the game loop for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Call {
+    State,
+    Grid(GridView),
+    Metrics,
+    Cells(CellEdit),
+    Pause(bool),
+    Step(u64),
+    // already moved to where it goes
+    Pattern(Pattern),
+}
+
+/// What /api/state tells besides the board, which is the same for the whole run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Status {
+    pub generation: u64,
+    pub population: usize,
+    pub connections: u64,
+    pub paused: bool,
+}
+
+fn json_response(status: u16, json: &Json) -> Response {
+    return Response::new(status, "application/json", json.to_string().into_bytes())
+        .header("Cache-Control", "no-store");
+}
+
+pub fn api_error(status: u16, reason: &str) -> Response {
+    return json_response(status, &Json::object([("error", Json::from(reason))]));
+}
+
+pub fn state_response(status: &Status) -> Response {
+    return json_response(
+        200,
+        &Json::object([
+            ("width", Json::from(GRID_WIDTH)),
+            ("height", Json::from(GRID_HEIGHT)),
+            ("rule", Json::from(RULE)),
+            ("topology", Json::from(TOPOLOGY)),
+            ("generation", Json::from(status.generation)),
+            ("population", Json::from(status.population)),
+            ("connections", Json::from(status.connections)),
+            ("paused", Json::from(status.paused)),
+        ]),
+    );
+}
+
+/// The board of `generation`, as asked for.
+pub fn grid_response(view: GridView, generation: u64, board: &[u8]) -> Response {
+    let live = Pattern::from_board(board, GRID_WIDTH, GRID_HEIGHT);
+    return match view {
+        GridView::Cells => {
+            let cells: Vec<Vec<isize>> = live.cells().iter().map(|&(x, y)| vec![x, y]).collect();
+            json_response(
+                200,
+                &Json::object([
+                    ("generation", Json::from(generation)),
+                    ("width", Json::from(GRID_WIDTH)),
+                    ("height", Json::from(GRID_HEIGHT)),
+                    ("cells", Json::from(cells)),
+                ]),
+            )
+        }
+        GridView::Rle => {
+            let rle = format!(
+                "#C generation {generation}\n{}",
+                live.to_board_rle(GRID_WIDTH, GRID_HEIGHT)
+            );
+            Response::new(200, "text/plain; charset=utf-8", rle.into_bytes()).header("Cache-Control", "no-store")
+        }
+    };
+}
+
+// Body of a POST as a JSON object, an empty one if there is no body. Keys other than `allowed` are refused, they
+// are most likely typos
+fn body_object(request: &Request, allowed: &[&str]) -> Result<Json, Response> {
+    if request.body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Json::Object(Vec::new()));
+    }
+    let text = std::str::from_utf8(&request.body).map_err(|_| api_error(400, "body is not valid utf8"))?;
+    let json = Json::parse(text).map_err(|e| api_error(400, &e.to_string()))?;
+    let Json::Object(fields) = &json else {
+        return Err(api_error(400, "body must be a JSON object"));
+    };
+    if let Some((key, _)) = fields.iter().find(|(key, _)| !allowed.contains(&key.as_str())) {
+        return Err(api_error(400, &format!("unknown field \"{key}\"")));
+    }
+    return Ok(json);
+}
+
+// Whole number field, `default` if it's missing
+fn int_field(body: &Json, name: &str, default: i64) -> Result<i64, Response> {
+    return match body.get(name) {
+        None => Ok(default),
+        Some(value) => value
+            .as_i64()
+            .ok_or_else(|| api_error(400, &format!("\"{name}\" must be a whole number"))),
+    };
+}
+
+// [[x, y], ...] of cells that have to be on the board
+fn cell_list(body: &Json, name: &str) -> Result<Vec<(usize, usize)>, Response> {
+    let Some(value) = body.get(name) else {
+        return Ok(Vec::new());
+    };
+    let invalid = || api_error(400, &format!("\"{name}\" must be a list of [x, y] cells on the board"));
+    let mut cells = Vec::new();
+    for cell in value.as_array().ok_or_else(invalid)? {
+        let [x, y] = cell.as_array().ok_or_else(invalid)? else {
+            return Err(invalid());
+        };
+        let (x, y) = (x.as_i64().ok_or_else(invalid)?, y.as_i64().ok_or_else(invalid)?);
+        if !(0..GRID_WIDTH as i64).contains(&x) || !(0..GRID_HEIGHT as i64).contains(&y) {
+            return Err(invalid());
+        }
+        cells.push((x as usize, y as usize));
+    }
+    return Ok(cells);
+}
+
+// An RLE pattern with its top-left corner moved to (x, y), or why it can't go on the board
+fn place_pattern(rle: &str, x: i64, y: i64) -> Result<Pattern, String> {
+    let pattern = Pattern::from_rle_within(rle, GRID_WIDTH, GRID_HEIGHT).map_err(|e| e.to_string())?;
+    // positions wrap around the board like the pattern does
+    return Ok(pattern.crop().translate(x as isize, y as isize));
+}
+
+fn pattern_call(request: &Request) -> Result<Call, Response> {
+    let body = body_object(request, &["rle", "x", "y"])?;
+    let Some(rle) = body.get("rle").and_then(Json::as_str) else {
+        return Err(api_error(400, "\"rle\" must be the pattern in RLE"));
+    };
+    let (x, y) = (int_field(&body, "x", 0)?, int_field(&body, "y", 0)?);
+    let pattern = place_pattern(rle, x, y).map_err(|reason| api_error(400, &reason))?;
+    return Ok(Call::Pattern(pattern));
+}
+
+/// The call for a cell edit or stamp a client sent, checked like the POST that does the same. Err is why it can't
+/// be done.
+pub fn client_call(msg: &Message) -> Result<Call, String> {
+    return match msg {
+        Message::EditCells { action, cells } => {
+            if let Some((x, y)) = cells
+                .iter()
+                .find(|&&(x, y)| x as usize >= GRID_WIDTH || y as usize >= GRID_HEIGHT)
+            {
+                return Err(format!("cell ({x}, {y}) is not on the board"));
+            }
+            let cells = cells.iter().map(|&(x, y)| (x as usize, y as usize)).collect();
+            let edit = match *action {
+                EDIT_SET => CellEdit {
+                    set: cells,
+                    ..CellEdit::default()
+                },
+                EDIT_CLEAR => CellEdit {
+                    clear: cells,
+                    ..CellEdit::default()
+                },
+                EDIT_TOGGLE => CellEdit {
+                    toggle: cells,
+                    ..CellEdit::default()
+                },
+                _ => return Err(format!("unknown edit action {action}")),
+            };
+            Ok(Call::Cells(edit))
+        }
+        Message::Stamp { x, y, rle } => place_pattern(rle, *x as i64, *y as i64).map(Call::Pattern),
+        _ => Err(format!("{msg:?} is not an edit")),
+    };
+}
+
+/// Whether a request is for the API rather than for a file of the web page.
+pub fn is_api(path: &str) -> bool {
+    return path.starts_with(API_PREFIX) || path == METRICS_PATH;
+}
+
+/// What an API request asks for, or the error response if it can't be done.
+pub fn route(request: &Request) -> Result<Call, Response> {
+    let (get, post) = match request.method.as_str() {
+        "GET" | "HEAD" => (true, false),
+        "POST" => (false, true),
+        _ => (false, false),
+    };
+    let only_get = || api_error(405, "only GET and HEAD are served").header("Allow", "GET, HEAD");
+    let only_post = || api_error(405, "only POST is served").header("Allow", "POST");
+    return match request.path.as_str() {
+        "/api/state" if get => Ok(Call::State),
+        "/api/grid" if get => match request.query_param("format") {
+            None | Some("cells") => Ok(Call::Grid(GridView::Cells)),
+            Some("rle") => Ok(Call::Grid(GridView::Rle)),
+            Some(_) => Err(api_error(400, "format must be cells or rle")),
+        },
+        METRICS_PATH if get => Ok(Call::Metrics),
+        "/api/state" | "/api/grid" | METRICS_PATH => Err(only_get()),
+        "/api/cells" if post => {
+            let body = body_object(request, &["set", "clear", "toggle"])?;
+            Ok(Call::Cells(CellEdit {
+                set: cell_list(&body, "set")?,
+                clear: cell_list(&body, "clear")?,
+                toggle: cell_list(&body, "toggle")?,
+            }))
+        }
+        "/api/pause" if post => {
+            let body = body_object(request, &["paused"])?;
+            match body.get("paused") {
+                None => Ok(Call::Pause(true)),
+                Some(paused) => paused
+                    .as_bool()
+                    .map(Call::Pause)
+                    .ok_or_else(|| api_error(400, "\"paused\" must be true or false")),
+            }
+        }
+        "/api/step" if post => {
+            let body = body_object(request, &["generations"])?;
+            match int_field(&body, "generations", 1)? {
+                n if (1..=MAX_STEPS as i64).contains(&n) => Ok(Call::Step(n as u64)),
+                _ => Err(api_error(
+                    400,
+                    &format!("\"generations\" must be from 1 to {MAX_STEPS}"),
+                )),
+            }
+        }
+        "/api/pattern" if post => pattern_call(request),
+        "/api/cells" | "/api/pause" | "/api/step" | "/api/pattern" => Err(only_post()),
+        _ => Err(api_error(404, "no such endpoint")),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::{client_call, grid_response, route, state_response, Call, CellEdit, GridView, Status};
+    use crate::game::{GRID_HEIGHT, GRID_WIDTH};
+    use crate::http::{parse_request, Request};
+    use crate::json::Json;
+    use crate::pattern::Pattern;
+    use crate::protocol::{Message, EDIT_CLEAR, EDIT_TOGGLE};
+
+    fn request(method: &str, target: &str, body: &str) -> Request {
+        let mut request = parse_request(&format!("{method} {target} HTTP/1.1\r\nHost: x\r\n\r\n")).unwrap();
+        request.body = body.as_bytes().to_vec();
+        return request;
+    }
+
+    fn status(method: &str, target: &str, body: &str) -> u16 {
+        return route(&request(method, target, body)).map_or_else(|e| e.status, |_| 200);
+    }
+
+    #[test]
+    fn test_route() {
+        let call = |method, target, body| route(&request(method, target, body)).unwrap();
+        assert_eq!(call("GET", "/api/state", ""), Call::State);
+        assert_eq!(call("HEAD", "/api/grid", ""), Call::Grid(GridView::Cells));
+        assert_eq!(call("GET", "/api/grid?x=1&format=rle", ""), Call::Grid(GridView::Rle));
+        assert_eq!(call("GET", "/metrics", ""), Call::Metrics);
+        assert_eq!(call("POST", "/api/pause", ""), Call::Pause(true));
+        assert_eq!(call("POST", "/api/pause", r#"{"paused": false}"#), Call::Pause(false));
+        assert_eq!(call("POST", "/api/step", "\r\n"), Call::Step(1));
+        assert_eq!(call("POST", "/api/step", r#"{"generations": 10}"#), Call::Step(10));
+        assert_eq!(
+            call(
+                "POST",
+                "/api/cells",
+                r#"{"set": [[0, 0], [47, 30]], "toggle": [[1, 2]]}"#
+            ),
+            Call::Cells(CellEdit {
+                set: vec![(0, 0), (47, 30)],
+                clear: vec![],
+                toggle: vec![(1, 2)],
+            })
+        );
+        assert_eq!(
+            call(
+                "POST",
+                "/api/pattern",
+                r#"{"rle": "x = 3, y = 3\nbo$2bo$3o!", "x": 46, "y": -1}"#
+            ),
+            Call::Pattern(Pattern::glider().translate(46, -1))
+        );
+
+        assert_eq!(status("GET", "/api/nope", ""), 404);
+        assert_eq!(status("GET", "/api/grid?format=png", ""), 400);
+        assert_eq!(status("POST", "/api/state", ""), 405);
+        assert_eq!(status("POST", "/metrics", ""), 405);
+        assert_eq!(status("GET", "/api/step", ""), 405);
+        assert_eq!(status("DELETE", "/api/cells", ""), 405);
+        for (target, body) in [
+            ("/api/pause", "true"),
+            ("/api/pause", r#"{"paused": 1}"#),
+            ("/api/pause", r#"{"pause": true}"#),
+            ("/api/step", r#"{"generations": 0}"#),
+            ("/api/step", r#"{"generations": 10001}"#),
+            ("/api/step", r#"{"generations": 1.5}"#),
+            ("/api/step", "{"),
+            ("/api/cells", r#"{"set": [[48, 0]]}"#),
+            ("/api/cells", r#"{"set": [[0, -1]]}"#),
+            ("/api/cells", r#"{"set": [[0, 0, 0]]}"#),
+            ("/api/cells", r#"{"set": [0, 0]}"#),
+            ("/api/pattern", "{}"),
+            ("/api/pattern", r#"{"rle": "2q!"}"#),
+            ("/api/pattern", r#"{"rle": "49o!"}"#),
+            ("/api/pattern", r#"{"rle": "99999999999999o!"}"#),
+            ("/api/pattern", r#"{"rle": "30o$31$o!"}"#),
+        ] {
+            assert_eq!(status("POST", target, body), 400, "{target} {body}");
+        }
+        let error = route(&request("POST", "/api/cells", r#"{"sett": []}"#)).unwrap_err();
+        assert_eq!(error.body, br#"{"error":"unknown field \"sett\""}"#);
+    }
+
+    #[test]
+    fn test_cell_edit() {
+        let mut board = vec![0; GRID_WIDTH * GRID_HEIGHT];
+        board[1] = 1;
+        board[2] = 1;
+        CellEdit {
+            set: vec![(0, 0), (1, 0)],
+            clear: vec![(2, 0), (0, 0)],
+            toggle: vec![(1, 0), (3, 1)],
+        }
+        .apply(&mut board);
+        assert_eq!(Pattern::from_board(&board, GRID_WIDTH, GRID_HEIGHT).cells(), [(3, 1)]);
+    }
+
+    #[test]
+    fn test_client_call() {
+        assert_eq!(
+            client_call(&Message::EditCells {
+                action: EDIT_TOGGLE,
+                cells: vec![(0, 0), (47, 30)],
+            }),
+            Ok(Call::Cells(CellEdit {
+                toggle: vec![(0, 0), (47, 30)],
+                ..CellEdit::default()
+            }))
+        );
+        assert_eq!(
+            client_call(&Message::Stamp {
+                x: 46,
+                y: 30,
+                rle: String::from("bo$2bo$3o!"),
+            }),
+            Ok(Call::Pattern(Pattern::glider().translate(46, 30)))
+        );
+        assert!(client_call(&Message::EditCells {
+            action: EDIT_CLEAR,
+            cells: vec![(1, 1), (48, 0)],
+        })
+        .is_err());
+        assert!(client_call(&Message::Stamp {
+            x: 0,
+            y: 0,
+            rle: String::from("49o!"),
+        })
+        .is_err());
+        assert!(client_call(&Message::KeyframeRequest).is_err());
+    }
+
+    #[test]
+    fn test_responses() {
+        let state = state_response(&Status {
+            generation: 7,
+            population: 10,
+            connections: 2,
+            paused: true,
+        });
+        assert_eq!(
+            String::from_utf8(state.body).unwrap(),
+            r#"{"width":48,"height":31,"rule":"B3/S23","topology":"torus","generation":7,"population":10,"connections":2,"paused":true}"#
+        );
+
+        let mut board = vec![0; GRID_WIDTH * GRID_HEIGHT];
+        Pattern::glider()
+            .translate(1, 1)
+            .union(&mut board, GRID_WIDTH, GRID_HEIGHT);
+        let cells = Json::parse(std::str::from_utf8(&grid_response(GridView::Cells, 3, &board).body).unwrap()).unwrap();
+        assert_eq!(cells.get("generation").and_then(Json::as_i64), Some(3));
+        assert_eq!(
+            cells.get("cells").unwrap().to_string(),
+            "[[2,1],[3,2],[1,3],[2,3],[3,3]]"
+        );
+
+        let rle = String::from_utf8(grid_response(GridView::Rle, 3, &board).body).unwrap();
+        assert!(rle.starts_with("#C generation 3\nx = 48, y = 31, rule = B3/S23\n"));
+        assert_eq!(Pattern::from_rle(&rle).unwrap(), Pattern::glider().translate(1, 1));
+    }
+}
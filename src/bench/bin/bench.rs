@@ -0,0 +1,95 @@
+use std::{env::args, process::exit};
+
+use gol_multi::{
+    game::step,
+    net::{grid_delta, write_varint, ENCODINGS},
+    pattern::Pattern,
+    soup::{generate_soup, SOUP_SIZE},
+};
+
+fn print_usage() {
+    println!("Prints the average encoded size of the built-in patterns for every grid encoding, as a markdown table");
+    println!();
+    println!("    --help  print this help");
+    println!("    -g  amount of generations to average over (default 100)");
+}
+
+// (element, width, height, board)
+fn boards() -> Vec<(&'static str, usize, usize, Vec<u8>)> {
+    let board = |width: usize, height: usize, patterns: &[Pattern]| {
+        let mut cells = vec![0; width * height];
+        for pattern in patterns {
+            pattern.union(&mut cells, width, height);
+        }
+        return cells;
+    };
+    let glider = Pattern::glider().translate(1, 1);
+    let glider2 = Pattern::glider().translate(6, 2);
+    let soup = |width: usize, height: usize| {
+        let soup = Pattern::from_board(&generate_soup(0), SOUP_SIZE, SOUP_SIZE);
+        let (x, y) = ((width - SOUP_SIZE) / 2, (height - SOUP_SIZE) / 2);
+        return board(width, height, &[soup.translate(x as isize, y as isize)]);
+    };
+
+    return vec![
+        ("stick", 48, 31, board(48, 31, &[Pattern::stick()])),
+        ("glider", 48, 10, board(48, 10, &[glider.clone()])),
+        ("glider", 48, 31, board(48, 31, &[glider.clone()])),
+        ("2xglider", 48, 31, board(48, 31, &[glider, glider2])),
+        ("soup", 48, 31, soup(48, 31)),
+        ("soup", 256, 256, soup(256, 256)),
+    ];
+}
+
+fn main() {
+    let mut args = args().skip(1);
+    let mut generations: usize = 100;
+    while let Some(next) = args.next() {
+        match next.as_str() {
+            "--help" => {
+                print_usage();
+                exit(0);
+            }
+            "-g" => match args.next().map(|g| g.parse::<usize>()) {
+                Some(Ok(g)) if g > 0 => generations = g,
+                _ => {
+                    eprintln!("ERROR - Number of generations expected after flag -g");
+                    exit(1);
+                }
+            },
+            _ => {
+                eprintln!("Unrecognized arg: {}", next);
+                print_usage();
+                exit(1)
+            }
+        }
+    }
+
+    println!("|element|grid dimensions|encoding|avg encoded size (bytes)|");
+    println!("|---|---|---|---|");
+    for (element, width, height, mut cells) in boards() {
+        let mut totals = vec![0; ENCODINGS.len()];
+        let mut delta_total = 0;
+        let mut next = vec![0; cells.len()];
+        for _ in 0..generations {
+            for (total, encoding) in totals.iter_mut().zip(ENCODINGS) {
+                *total += encoding.compress_cells(&cells).len();
+            }
+            step(&cells, &mut next, width, height);
+            // each cell as a varint of its distance from the one before, like the protocol sends them
+            let mut delta = Vec::new();
+            let mut prev = 0;
+            for cell in grid_delta(&cells, &next) {
+                write_varint(&mut delta, (cell - prev) as u64);
+                prev = cell;
+            }
+            delta_total += delta.len();
+            std::mem::swap(&mut cells, &mut next);
+        }
+        for (total, encoding) in totals.iter().zip(ENCODINGS) {
+            let name = format!("{encoding:?}").to_lowercase();
+            println!("|{element}|{width}x{height}|{name}|{}|", total / generations);
+        }
+        println!("|{element}|{width}x{height}|delta|{}|", delta_total / generations);
+    }
+}
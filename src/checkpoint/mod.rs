@@ -0,0 +1,252 @@
+#![allow(static_mut_refs)]
+
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+
+use crate::game::{State, GRID, GRID_HEIGHT, GRID_WIDTH, PREV_GRID};
+use crate::pattern::Pattern;
+
+// Checkpoint file. A line per field followed by the live cells in RLE format, which keeps it readable and lets
+// the cells be opened with any Life software:
+//
+//   #GOL checkpoint 1
+//   width 48
+//   height 31
+//   rule B3/S23
+//   topology torus
+//   generation 1234
+//   frames 1234
+//   total_bytes_sent 56789
+//   total_messages_sent 1234
+//   encoded_grid_lengths 25,25,26
+//   full_grid_bytes 80
+//   origin 12 7
+//   x = 3, y = 3, rule = B3/S23
+//   bo$2bo$3o!
+//
+// `origin` is where the top-left corner of the RLE pattern goes on the board.
+pub const CHECKPOINT_HEADER: &str = "#GOL checkpoint 1";
+pub const DEFAULT_CHECKPOINT_FILE: &str = "gol.checkpoint";
+
+// The game only knows how to run Conway's rules on a board that wraps around
+pub const RULE: &str = "B3/S23";
+pub const TOPOLOGY: &str = "torus";
+
+#[derive(Debug, PartialEq)]
+pub struct Checkpoint {
+    pub width: usize,
+    pub height: usize,
+    pub rule: String,
+    pub topology: String,
+    pub generation: u64,
+    pub frames: usize,
+    pub total_bytes_sent: usize,
+    pub total_messages_sent: usize,
+    pub encoded_grid_lengths: Vec<usize>,
+    pub full_grid_bytes: usize,
+    pub cells: Pattern,
+}
+
+fn invalid(msg: String) -> Error {
+    return Error::new(ErrorKind::InvalidData, msg);
+}
+
+impl Checkpoint {
+    pub unsafe fn capture(state: &State) -> Checkpoint {
+        return Checkpoint {
+            width: GRID_WIDTH,
+            height: GRID_HEIGHT,
+            rule: RULE.to_string(),
+            topology: TOPOLOGY.to_string(),
+            generation: state.generation,
+            frames: state.frames,
+            total_bytes_sent: state.total_bytes_sent,
+            total_messages_sent: state.total_messages_sent,
+            encoded_grid_lengths: state.encoded_grid_lengths.clone(),
+            full_grid_bytes: state.full_grid_bytes,
+            cells: Pattern::from_board(&GRID, GRID_WIDTH, GRID_HEIGHT),
+        };
+    }
+
+    /// Puts the checkpoint cells on the grid and its counters in the state. Fails if the checkpoint was made
+    /// with a board this server can't run.
+    pub unsafe fn restore(&self, state: &mut State) -> Result<()> {
+        if self.width != GRID_WIDTH || self.height != GRID_HEIGHT {
+            return Err(invalid(format!(
+                "Checkpoint board is {}x{} but the server board is {GRID_WIDTH}x{GRID_HEIGHT}",
+                self.width, self.height
+            )));
+        }
+        if self.rule != RULE || self.topology != TOPOLOGY {
+            return Err(invalid(format!(
+                "Checkpoint uses rule {} on a {} but only {RULE} on a {TOPOLOGY} is supported",
+                self.rule, self.topology
+            )));
+        }
+
+        GRID.fill(0);
+        self.cells.union(&mut GRID, GRID_WIDTH, GRID_HEIGHT);
+        PREV_GRID.copy_from_slice(&GRID);
+
+        state.generation = self.generation;
+        state.frames = self.frames;
+        state.total_bytes_sent = self.total_bytes_sent;
+        state.total_messages_sent = self.total_messages_sent;
+        state.encoded_grid_lengths = self.encoded_grid_lengths.clone();
+        state.sent_grid_bytes = self.encoded_grid_lengths.iter().sum();
+        state.full_grid_bytes = self.full_grid_bytes;
+        return Ok(());
+    }
+
+    pub fn serialize(&self) -> String {
+        let (origin_x, origin_y) = self.cells.bounding_box().map_or((0, 0), |(x, y, _, _)| (x, y));
+        let lengths: Vec<String> = self.encoded_grid_lengths.iter().map(|l| l.to_string()).collect();
+        let mut checkpoint = String::new();
+        checkpoint.push_str(&format!("{CHECKPOINT_HEADER}\n"));
+        checkpoint.push_str(&format!("width {}\n", self.width));
+        checkpoint.push_str(&format!("height {}\n", self.height));
+        checkpoint.push_str(&format!("rule {}\n", self.rule));
+        checkpoint.push_str(&format!("topology {}\n", self.topology));
+        checkpoint.push_str(&format!("generation {}\n", self.generation));
+        checkpoint.push_str(&format!("frames {}\n", self.frames));
+        checkpoint.push_str(&format!("total_bytes_sent {}\n", self.total_bytes_sent));
+        checkpoint.push_str(&format!("total_messages_sent {}\n", self.total_messages_sent));
+        checkpoint.push_str(&format!("encoded_grid_lengths {}\n", lengths.join(",")));
+        checkpoint.push_str(&format!("full_grid_bytes {}\n", self.full_grid_bytes));
+        checkpoint.push_str(&format!("origin {origin_x} {origin_y}\n"));
+        checkpoint.push_str(&self.cells.to_rle());
+        return checkpoint;
+    }
+
+    pub fn parse(checkpoint: &str) -> Result<Checkpoint> {
+        let mut lines = checkpoint.lines();
+        if lines.next() != Some(CHECKPOINT_HEADER) {
+            return Err(invalid(format!("Checkpoint must start with \"{CHECKPOINT_HEADER}\"")));
+        }
+
+        let mut field = |name: &str| -> Result<String> {
+            return next_field(&mut lines, name).ok_or_else(|| {
+                let line = lines.clone().next().unwrap_or_default();
+                invalid(format!("Expected checkpoint field {name}, found \"{line}\""))
+            });
+        };
+        fn number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T> {
+            return value
+                .parse::<T>()
+                .map_err(|_| invalid(format!("Checkpoint field {name} is not a number: \"{value}\"")));
+        }
+
+        let width = number("width", &field("width")?)?;
+        let height = number("height", &field("height")?)?;
+        let rule = field("rule")?;
+        let topology = field("topology")?;
+        let generation = number("generation", &field("generation")?)?;
+        let frames = number("frames", &field("frames")?)?;
+        let total_bytes_sent = number("total_bytes_sent", &field("total_bytes_sent")?)?;
+        let total_messages_sent = number("total_messages_sent", &field("total_messages_sent")?)?;
+        let encoded_grid_lengths = field("encoded_grid_lengths")?
+            .split(',')
+            .filter(|l| !l.is_empty())
+            .map(|l| number("encoded_grid_lengths", l))
+            .collect::<Result<Vec<usize>>>()?;
+        // added after the first version, so it may be missing
+        let full_grid_bytes = match next_field(&mut lines, "full_grid_bytes") {
+            Some(value) => number("full_grid_bytes", &value)?,
+            None => encoded_grid_lengths.iter().sum(),
+        };
+        let origin = next_field(&mut lines, "origin")
+            .ok_or_else(|| invalid(String::from("Expected checkpoint field origin")))?;
+        let (origin_x, origin_y) = origin
+            .split_once(' ')
+            .ok_or_else(|| invalid(format!("Checkpoint origin must be \"x y\", found \"{origin}\"")))?;
+        let (origin_x, origin_y) = (number("origin", origin_x)?, number("origin", origin_y)?);
+
+        let rle: Vec<&str> = lines.collect();
+        let cells = Pattern::from_rle(&rle.join("\n"))?.translate(origin_x, origin_y);
+
+        return Ok(Checkpoint {
+            width,
+            height,
+            rule,
+            topology,
+            generation,
+            frames,
+            total_bytes_sent,
+            total_messages_sent,
+            encoded_grid_lengths,
+            full_grid_bytes,
+            cells,
+        });
+    }
+}
+
+// Value of the next line if it is the `name` field, without consuming the line otherwise
+fn next_field(lines: &mut std::str::Lines, name: &str) -> Option<String> {
+    let line = lines.clone().next().unwrap_or_default();
+    let value = match line.split_once(' ') {
+        Some((key, value)) if key == name => value.to_string(),
+        // empty lists are written without a trailing space
+        None if line == name => String::new(),
+        _ => return None,
+    };
+    lines.next();
+    return Some(value);
+}
+
+/// Writes the current board and counters to `path`. The file is written next to it first and then renamed, so
+/// a crash mid-write doesn't leave a broken checkpoint behind.
+pub unsafe fn save_checkpoint(path: &str, state: &State) -> Result<()> {
+    let tmp_path = format!("{path}.tmp");
+    fs::write(&tmp_path, Checkpoint::capture(state).serialize())?;
+    return fs::rename(tmp_path, path);
+}
+
+pub unsafe fn load_checkpoint(path: &str, state: &mut State) -> Result<()> {
+    return Checkpoint::parse(&fs::read_to_string(path)?)?.restore(state);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::checkpoint::Checkpoint;
+    use crate::pattern::Pattern;
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let checkpoint = Checkpoint {
+            width: 48,
+            height: 31,
+            rule: String::from("B3/S23"),
+            topology: String::from("torus"),
+            generation: 1234,
+            frames: 1200,
+            total_bytes_sent: 56789,
+            total_messages_sent: 1234,
+            encoded_grid_lengths: vec![25, 25, 26],
+            full_grid_bytes: 80,
+            cells: Pattern::glider().translate(12, 7),
+        };
+
+        let serialized = checkpoint.serialize();
+        assert!(serialized.contains("origin 12 7\nx = 3, y = 3, rule = B3/S23\nbo$2bo$3o!\n"));
+        assert_eq!(Checkpoint::parse(&serialized).unwrap(), checkpoint);
+
+        let empty = Checkpoint {
+            encoded_grid_lengths: Vec::new(),
+            full_grid_bytes: 0,
+            cells: Pattern::default(),
+            ..checkpoint
+        };
+        assert_eq!(Checkpoint::parse(&empty.serialize()).unwrap(), empty);
+
+        // checkpoints from before full_grid_bytes existed
+        let old = serialized.replace("full_grid_bytes 80\n", "");
+        assert_eq!(Checkpoint::parse(&old).unwrap().full_grid_bytes, 76);
+    }
+
+    #[test]
+    fn test_checkpoint_parse_errors() {
+        assert!(Checkpoint::parse("width 48\n").is_err());
+        assert!(Checkpoint::parse("#GOL checkpoint 1\nwidth 48\nrule B3/S23\n").is_err());
+        assert!(Checkpoint::parse("#GOL checkpoint 1\nwidth abc\n").is_err());
+    }
+}
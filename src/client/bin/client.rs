@@ -1,27 +1,46 @@
+use crossterm::event::{read, Event, KeyCode, KeyModifiers};
 use gol_multi::{
     game::{GRID_HEIGHT, GRID_WIDTH},
     net::{
-        uncompress_grid_binary, uncompress_grid_rle, CMD_HEADER_SIZE, CMD_LOG_MSG, CMD_NEW_GRID, MAX_CONTENT_SIZE,
-        SIZE_HEADER_SIZE,
+        apply_grid_delta, close_payload, connect_ws, send_ws_frame_masked, Encoding, WsMessage, WsReader,
+        WS_OPCODE_BINARY, WS_OPCODE_CLOSE, WS_OPCODE_PONG,
     },
-    term::{clear_terminal, render},
+    pattern::Pattern,
+    protocol::{
+        Message, ProtocolError, CAP_CRC32, CAP_DELTA_GRID, EDIT_TOGGLE, GENERATION_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    term::{render, render_selection, render_status, reset_terminal, start_terminal},
 };
 use std::{
     env::args,
-    io::{Read, Result},
+    io::{Error, ErrorKind, Result, Write},
     net::TcpStream,
     process::exit,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
 };
 
+// Encodings the client can decode, preferred first
+const SUPPORTED_ENCODINGS: [Encoding; 5] = [
+    Encoding::VRLE,
+    Encoding::RANGE,
+    Encoding::RLE,
+    Encoding::BINARY,
+    Encoding::NONE,
+];
+
 fn main() -> Result<()> {
     let mut args = args().skip(1);
     let mut host = String::from("0.0.0.0");
-    let mut port = String::from("42068");
+    let mut port = None;
+    let mut encodings = SUPPORTED_ENCODINGS.to_vec();
+    let mut ws = false;
     while let Some(next) = args.next() {
         match next.as_str() {
             "-p" => match args.next() {
                 Some(p) => {
-                    port = p;
+                    port = Some(p);
                 }
                 None => {
                     eprintln!("ERROR - Port expected after flag -p");
@@ -37,53 +56,329 @@ fn main() -> Result<()> {
                     exit(1);
                 }
             },
+            "-e" => match args.next() {
+                Some(e) => {
+                    encodings = vec![Encoding::from_str(&e)?];
+                }
+                None => {
+                    eprintln!("ERROR - Encoding expected after flag -e");
+                    exit(1);
+                }
+            },
+            "--ws" => ws = true,
             _ => {
                 eprintln!("Unrecognized arg: {}", next);
                 exit(1)
             }
         }
     }
-    unsafe {
-        let stream = TcpStream::connect(format!("{host}:{port}")).unwrap();
-        handle_connection(stream)?;
+    // web clients connect to the WebSocket port
+    let port = port.unwrap_or(String::from(if ws { "42069" } else { "42068" }));
+    let address = format!("{host}:{port}");
+    let stream = TcpStream::connect(&address).unwrap();
+    let mut connection = if ws {
+        let connection = connect_ws(stream, &address)?;
+        Connection::new(Reader::Ws(connection.reader), connection.stream, true)
+    } else {
+        Connection::new(Reader::Tcp(stream.try_clone()?), stream, false)
+    };
+    send_hello(&mut connection, &encodings)?;
+
+    start_terminal()?;
+    // where the player edits the board, drawn by both threads. Its lock is held while drawing or changing the grid
+    let cursor = Arc::new(Mutex::new((0, 0)));
+    let writer = Arc::clone(&connection.writer);
+    let keys_cursor = Arc::clone(&cursor);
+    thread::spawn(move || {
+        if let Err(e) = read_keys(&writer, &keys_cursor) {
+            eprintln!("Unable to send edit: {e}");
+        }
+    });
+    let result = unsafe { handle_connection(connection, &cursor) };
+    reset_terminal()?;
+    return result;
+}
+
+// The server, over raw TCP or a WebSocket where every binary message is a whole protocol message
+struct Connection {
+    reader: Reader,
+    // the keyboard thread sends edits while this one reads, the lock keeps their messages whole
+    writer: Arc<Mutex<Writer>>,
+}
+
+enum Reader {
+    Tcp(TcpStream),
+    Ws(WsReader<TcpStream>),
+}
+
+struct Writer {
+    stream: TcpStream,
+    ws: bool,
+}
+
+impl Writer {
+    fn send(&mut self, msg: &[u8]) -> Result<()> {
+        if self.ws {
+            return self.send_frame(WS_OPCODE_BINARY, msg);
+        }
+        return self.stream.write_all(msg);
+    }
+
+    // WebSocket only
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<()> {
+        return send_ws_frame_masked(&mut self.stream, opcode, payload).map(|_| ());
+    }
+}
+
+impl Connection {
+    fn new(reader: Reader, stream: TcpStream, ws: bool) -> Connection {
+        return Connection {
+            reader,
+            writer: Arc::new(Mutex::new(Writer { stream, ws })),
+        };
+    }
+
+    fn send(&mut self, msg: &[u8]) -> Result<()> {
+        return self.writer.lock().unwrap().send(msg);
+    }
+
+    // Next message of the server. Over WebSocket pings are answered on the way and a close is echoed back
+    fn read(&mut self, version: u8, checksum: bool) -> std::result::Result<Message, ProtocolError> {
+        let reader = match &mut self.reader {
+            Reader::Tcp(stream) if checksum => return Message::read_checked(stream, version),
+            Reader::Tcp(stream) => return Message::read_for(stream, version),
+            Reader::Ws(reader) => reader,
+        };
+        loop {
+            let msg = match reader.read_message() {
+                Ok(WsMessage::Binary(msg)) => msg,
+                Ok(WsMessage::Text(text)) => text.into_bytes(),
+                Ok(WsMessage::Ping(payload)) => {
+                    self.writer.lock().unwrap().send_frame(WS_OPCODE_PONG, &payload)?;
+                    continue;
+                }
+                Ok(WsMessage::Pong(_)) => continue,
+                Ok(WsMessage::Close(close)) => {
+                    let code = close.as_ref().map(|(code, _)| (*code, ""));
+                    let _ = self
+                        .writer
+                        .lock()
+                        .unwrap()
+                        .send_frame(WS_OPCODE_CLOSE, &close_payload(code));
+                    return Err(ProtocolError::Io(Error::new(
+                        ErrorKind::ConnectionAborted,
+                        format!("Server closed the connection: {close:?}"),
+                    )));
+                }
+                Err(e) => {
+                    if let Some(code) = e.close_code() {
+                        let close = close_payload(Some((code, &e.to_string())));
+                        let _ = self.writer.lock().unwrap().send_frame(WS_OPCODE_CLOSE, &close);
+                    }
+                    return Err(ProtocolError::Io(e.into()));
+                }
+            };
+            return if checksum {
+                Message::decode_checked(&msg, version)
+            } else {
+                Message::decode_for(&msg, version)
+            };
+        }
+    }
+}
+
+fn send_hello(connection: &mut Connection, encodings: &[Encoding]) -> Result<()> {
+    let version = Message::Version {
+        version: PROTOCOL_VERSION,
+        capabilities: CAP_DELTA_GRID | CAP_CRC32,
+    };
+    let ids: Vec<u8> = encodings.iter().map(|e| e.id()).collect();
+    // both in the same WebSocket message, like the web page sends them
+    return connection.send(&[version.encode(), Message::Hello(ids).encode()].concat());
+}
+
+// Whether a delta grid of `generation` can go on top of the grid of generation `last`. Otherwise the client has
+// missed (or never got) the generation before it
+fn follows(last: Option<u64>, generation: u64) -> bool {
+    return last.is_some_and(|last| last + 1 == generation);
+}
+
+// Deltas for generations the client already has, e.g. sent before the keyframe it asked for
+fn stale(last: Option<u64>, generation: u64) -> bool {
+    return last.is_some_and(|last| generation <= last);
+}
+
+// Asks for a full grid after a message that couldn't be used, unless one is on its way. Servers before
+// GENERATION_PROTOCOL_VERSION send one every few seconds anyway
+fn request_keyframe(connection: &mut Connection, version: u8, keyframe_requested: &mut bool) -> Result<()> {
+    if version >= GENERATION_PROTOCOL_VERSION && !*keyframe_requested {
+        connection.send(&Message::KeyframeRequest.encode())?;
+        *keyframe_requested = true;
     }
+    return Ok(());
+}
 
-    Ok(())
+// Keys of the player: arrows move the cursor, space toggles the cell under it and g places a glider there. The
+// server applies the edits between two generations (servers before EDIT_PROTOCOL_VERSION skip them), so they show
+// up with a later grid. q or ctrl-c quits
+fn read_keys(writer: &Mutex<Writer>, cursor: &Mutex<(usize, usize)>) -> Result<()> {
+    loop {
+        let Event::Key(event) = read()? else {
+            continue;
+        };
+        let mut cursor = cursor.lock().unwrap();
+        let (x, y) = *cursor;
+        let edit = match event.code {
+            KeyCode::Char('q') => None,
+            KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => None,
+            KeyCode::Left | KeyCode::Right | KeyCode::Up | KeyCode::Down => {
+                // wrapping around the board
+                let (dx, dy) = match event.code {
+                    KeyCode::Left => (GRID_WIDTH - 1, 0),
+                    KeyCode::Right => (1, 0),
+                    KeyCode::Up => (0, GRID_HEIGHT - 1),
+                    _ => (0, 1),
+                };
+                *cursor = ((x + dx) % GRID_WIDTH, (y + dy) % GRID_HEIGHT);
+                // the game may be paused, the next grid could be a while
+                unsafe {
+                    render()?;
+                    render_selection(*cursor, None)?;
+                }
+                continue;
+            }
+            KeyCode::Char(' ') => Some(Message::EditCells {
+                action: EDIT_TOGGLE,
+                cells: vec![(x as u16, y as u16)],
+            }),
+            KeyCode::Char('g') => Some(Message::Stamp {
+                x: x as u16,
+                y: y as u16,
+                rle: Pattern::glider().to_rle(),
+            }),
+            _ => continue,
+        };
+        let Some(edit) = edit else {
+            reset_terminal()?;
+            exit(0);
+        };
+        writer.lock().unwrap().send(&edit.encode())?;
+    }
 }
 
-unsafe fn handle_connection(mut stream: TcpStream) -> Result<()> {
-    let mut header_buffer: [u8; CMD_HEADER_SIZE] = [0; CMD_HEADER_SIZE];
-    let mut size_buffer: [u8; SIZE_HEADER_SIZE] = [0; SIZE_HEADER_SIZE];
-    let mut content_buffer: [u8; MAX_CONTENT_SIZE] = [0; MAX_CONTENT_SIZE];
-    let mut content_size: u16;
-    let mut log: String;
-    clear_terminal()?;
+// Draws the board with the cursor on top, and the status line if there's a new one. The keyboard thread draws
+// the cursor too, the lock keeps them from drawing at the same time
+unsafe fn draw(cursor: &Mutex<(usize, usize)>, status: Option<&str>) -> Result<()> {
+    let cursor = cursor.lock().unwrap();
+    render()?;
+    render_selection(*cursor, None)?;
+    if let Some(status) = status {
+        render_status(&format!("{status} | arrows: move space: toggle g: glider q: quit"))?;
+    }
+    return Ok(());
+}
+
+// Changes the grid with the drawing lock held, so the keyboard thread never draws it halfway through
+fn update_grid<T>(cursor: &Mutex<(usize, usize)>, update: impl FnOnce() -> T) -> T {
+    let _drawing = cursor.lock().unwrap();
+    return update();
+}
+
+unsafe fn handle_connection(mut connection: Connection, cursor: &Mutex<(usize, usize)>) -> Result<()> {
+    // assumed until the server answers with the version it speaks
+    let mut version = PROTOCOL_VERSION;
+    let mut last_generation: Option<u64> = None;
+    let mut keyframe_requested = false;
+    // whether the server agreed to end its messages with a CRC-32, known from its version reply
+    let mut checksum = false;
     loop {
-        // Read cmd header (1 byte)
-        stream.read_exact(&mut header_buffer)?;
-        eprintln!("Header: {:#04x}", header_buffer[0]);
-        // Read size header (2 bytes) big-endian
-        stream.read_exact(&mut size_buffer)?;
-        content_size = (size_buffer[0] as u16) << 8 | size_buffer[1] as u16;
-        eprintln!("Size: {}B", content_size);
-
-        // Read to fill slice of max-sized content buffer based on content_size
-        stream.read_exact(&mut content_buffer[0..content_size.into()])?;
-
-        match header_buffer[0] {
-            CMD_NEW_GRID => {
-                eprintln!("Grid: {:?}", &content_buffer[0..((GRID_WIDTH * GRID_HEIGHT) / 8)]);
-                //uncompress_grid_binary(&content_buffer[0..((GRID_WIDTH * GRID_HEIGHT) / 8)]);
-                uncompress_grid_rle(&content_buffer[0..(content_size as usize)]);
-                render()?;
+        // Content is as big as the size header says (up to MAX_CONTENT_SIZE), the 16 bit one or the extended one
+        let msg = match connection.read(version, checksum) {
+            Ok(msg) => msg,
+            // whatever it was, the deltas after it won't go on top of the grid we have
+            Err(e @ ProtocolError::ChecksumMismatch { .. }) => {
+                eprintln!("Dropping corrupted message: {e}");
+                request_keyframe(&mut connection, version, &mut keyframe_requested)?;
+                continue;
+            }
+            // the whole message was read, so the next one can still be parsed
+            Err(
+                e @ (ProtocolError::UnknownCommand(_)
+                | ProtocolError::UnknownEncoding(_)
+                | ProtocolError::InvalidContent { .. }),
+            ) => {
+                eprintln!("Skipping message: {e}");
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        eprintln!("Header: {:#04x}", msg.cmd());
+
+        match msg {
+            Message::NewGrid {
+                generation,
+                encoding,
+                cgrid,
+                ..
+            } => {
+                eprintln!("Grid ({encoding:?}): {:?}", cgrid);
+                if let Err(e) = update_grid(cursor, || encoding.uncompress(&cgrid)) {
+                    eprintln!("Unable to decode grid: {e}");
+                    request_keyframe(&mut connection, version, &mut keyframe_requested)?;
+                    continue;
+                }
+                last_generation = Some(generation);
+                keyframe_requested = false;
+                draw(cursor, Some(&format!("generation {generation}")))?;
+            }
+            // servers before GENERATION_PROTOCOL_VERSION don't say which generation a delta is for
+            Message::DeltaGrid { cells, .. } if version < GENERATION_PROTOCOL_VERSION => {
+                if let Err(e) = update_grid(cursor, || apply_grid_delta(&cells)) {
+                    eprintln!("Unable to apply delta grid: {e}");
+                    continue;
+                }
+                draw(cursor, None)?;
+            }
+            Message::DeltaGrid { generation, cells, .. } => {
+                if follows(last_generation, generation) {
+                    if let Err(e) = update_grid(cursor, || apply_grid_delta(&cells)) {
+                        eprintln!("Unable to apply delta grid: {e}");
+                        request_keyframe(&mut connection, version, &mut keyframe_requested)?;
+                        continue;
+                    }
+                    last_generation = Some(generation);
+                    draw(cursor, Some(&format!("generation {generation}")))?;
+                } else if !stale(last_generation, generation) && !keyframe_requested {
+                    eprintln!("Missed generations {last_generation:?} to {generation}, asking for a keyframe");
+                    request_keyframe(&mut connection, version, &mut keyframe_requested)?;
+                }
+            }
+            // only sent over WebSocket, the terminal grid has the server's size already
+            Message::Dimensions { width, height } => {
+                eprintln!("Grid is {width}x{height}");
+            }
+            Message::Encoding(encoding) => {
+                eprintln!("Server will send grids as {encoding:?}");
+            }
+            Message::Version {
+                version: server_version,
+                capabilities,
+            } => {
+                eprintln!("Server speaks protocol version {server_version} with capabilities {capabilities:#010b}");
+                version = server_version;
+                checksum = capabilities & CAP_CRC32 != 0;
+            }
+            Message::Error { code, reason } => {
+                return Err(Error::new(
+                    ErrorKind::ConnectionRefused,
+                    format!("Server refused the connection ({code}): {reason}"),
+                ));
             }
-            CMD_LOG_MSG => {
-                log = String::from_utf8(content_buffer[0..content_size.into()].to_vec())
-                    .expect("Log message to be valid utf8");
+            Message::Log(log) => {
                 eprintln!("Received log msg: {log}");
             }
             _ => {
-                eprintln!("header_buffer[0] = {} did not match anything", header_buffer[0]);
+                eprintln!("{msg:?} is not meant for clients");
             }
         }
     }
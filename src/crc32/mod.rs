@@ -0,0 +1,49 @@
+// CRC-32 as used by zlib, PNG and ethernet (reflected, polynomial 0xEDB88320), so any other implementation can check
+// it. Computed a byte at a time with a 256 entry table built at compile time.
+
+const POLYNOMIAL: u32 = 0xEDB88320;
+
+const TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    return table;
+}
+
+/// CRC-32 of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc = TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    return !crc;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crc32::crc32;
+
+    #[test]
+    fn test_crc32() {
+        // check values from the CRC catalogue and zlib
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414FA339);
+        assert_ne!(crc32(&[1, 2, 3]), crc32(&[1, 2, 2]));
+    }
+}
@@ -0,0 +1,625 @@
+use std::io::{Error, ErrorKind, Result};
+
+// DEFLATE (RFC 1951), as much of it as permessage-deflate (RFC 7692) needs.
+//
+// The compressor finds repeats with LZ77 (hash chains over the last `1 << window_bits` bytes) and writes them in a
+// single block with the fixed Huffman codes. Grids are mostly long runs of the same byte, which turn into a few
+// back-references, so dynamic codes aren't worth it. Every message ends with an empty stored block (a sync flush),
+// which byte aligns it; permessage-deflate drops its last 4 bytes (00 00 FF FF) and the receiver adds them back.
+//
+// The decompressor reads any DEFLATE stream (stored, fixed and dynamic blocks), since that's what browsers send.
+//
+// With context takeover both sides keep the last 32 KiB of the previous messages, so back-references can reach into
+// them.
+
+pub const MAX_WINDOW_BITS: u8 = 15;
+pub const MIN_WINDOW_BITS: u8 = 8;
+const WINDOW_SIZE: usize = 1 << MAX_WINDOW_BITS;
+// ends every message, and is left out of it by permessage-deflate
+pub const SYNC_FLUSH_TAIL: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// how many earlier positions with the same hash are tried for each match
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+const END_OF_BLOCK: u16 = 256;
+const BLOCK_STORED: u64 = 0;
+const BLOCK_FIXED: u64 = 1;
+const BLOCK_DYNAMIC: u32 = 2;
+
+// length symbols 257..285 and distance symbols 0..29: base value and extra bits
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+// order in which dynamic blocks list the code lengths of the code length alphabet
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn invalid(reason: &str) -> Error {
+    return Error::new(ErrorKind::InvalidData, format!("Invalid DEFLATE data: {reason}"));
+}
+
+// Keeps the last WINDOW_SIZE bytes of `data`
+fn slide(window: &mut Vec<u8>, data: &[u8]) {
+    window.extend_from_slice(data);
+    if window.len() > WINDOW_SIZE {
+        window.drain(..window.len() - WINDOW_SIZE);
+    }
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        return BitWriter {
+            out: Vec::new(),
+            acc: 0,
+            bits: 0,
+        };
+    }
+
+    // `n` bits of `value`, least significant first
+    fn write(&mut self, value: u64, n: u32) {
+        self.acc |= value << self.bits;
+        self.bits += n;
+        while self.bits >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    // Huffman codes go most significant bit first
+    fn write_code(&mut self, code: u16, len: u32) {
+        self.write((code.reverse_bits() >> (16 - len)) as u64, len);
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+
+    fn write_literal(&mut self, symbol: u16) {
+        match symbol {
+            0..=143 => self.write_code(0x30 + symbol, 8),
+            144..=255 => self.write_code(0x190 + symbol - 144, 9),
+            256..=279 => self.write_code(symbol - 256, 7),
+            _ => self.write_code(0xC0 + symbol - 280, 8),
+        }
+    }
+
+    fn write_match(&mut self, length: usize, distance: usize) {
+        let l = LENGTH_BASE.iter().rposition(|base| *base as usize <= length).unwrap();
+        self.write_literal(257 + l as u16);
+        self.write((length - LENGTH_BASE[l] as usize) as u64, LENGTH_EXTRA[l] as u32);
+        let d = DIST_BASE.iter().rposition(|base| *base as usize <= distance).unwrap();
+        self.write_code(d as u16, 5);
+        self.write((distance - DIST_BASE[d] as usize) as u64, DIST_EXTRA[d] as u32);
+    }
+}
+
+fn hash(data: &[u8]) -> usize {
+    let value = u32::from_le_bytes([data[0], data[1], data[2], 0]);
+    return (value.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize;
+}
+
+/// Compresses the messages of one side of a connection.
+pub struct Deflater {
+    // previous messages, when context takeover is on
+    window: Vec<u8>,
+    context_takeover: bool,
+    max_distance: usize,
+}
+
+impl Deflater {
+    pub fn new(context_takeover: bool, window_bits: u8) -> Deflater {
+        return Deflater {
+            window: Vec::new(),
+            context_takeover,
+            max_distance: 1 << window_bits.clamp(MIN_WINDOW_BITS, MAX_WINDOW_BITS),
+        };
+    }
+
+    /// Raw DEFLATE of `data`, ending with a sync flush. Back-references reach into the previous messages when
+    /// context takeover is on.
+    pub fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        let history = self.window.len().min(self.max_distance);
+        let input = [&self.window[self.window.len() - history..], data].concat();
+        let mut chains = HashChains::new(input.len());
+        for pos in 0..history {
+            chains.insert(&input, pos);
+        }
+
+        let mut out = BitWriter::new();
+        // not the final block, more messages follow
+        out.write(0, 1);
+        out.write(BLOCK_FIXED, 2);
+        let mut pos = history;
+        while pos < input.len() {
+            let (length, distance) = chains.longest_match(&input, pos, self.max_distance);
+            if length >= MIN_MATCH {
+                out.write_match(length, distance);
+                for p in pos..pos + length {
+                    chains.insert(&input, p);
+                }
+                pos += length;
+            } else {
+                out.write_literal(input[pos] as u16);
+                chains.insert(&input, pos);
+                pos += 1;
+            }
+        }
+        out.write_literal(END_OF_BLOCK);
+        // sync flush: empty stored block
+        out.write(0, 1);
+        out.write(BLOCK_STORED, 2);
+        out.align();
+        out.write(0xFFFF_0000, 32);
+
+        if self.context_takeover {
+            slide(&mut self.window, data);
+        }
+        return out.out;
+    }
+}
+
+// Earlier positions of every 3 byte sequence, most recent first
+struct HashChains {
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl HashChains {
+    fn new(len: usize) -> HashChains {
+        return HashChains {
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            prev: vec![usize::MAX; len],
+        };
+    }
+
+    fn insert(&mut self, input: &[u8], pos: usize) {
+        if pos + MIN_MATCH <= input.len() {
+            let h = hash(&input[pos..]);
+            self.prev[pos] = self.head[h];
+            self.head[h] = pos;
+        }
+    }
+
+    // Length and distance of the longest earlier repeat of what starts at `pos`
+    fn longest_match(&self, input: &[u8], pos: usize, max_distance: usize) -> (usize, usize) {
+        if pos + MIN_MATCH > input.len() {
+            return (0, 0);
+        }
+        let max_length = (input.len() - pos).min(MAX_MATCH);
+        let mut best = (0, 0);
+        let mut candidate = self.head[hash(&input[pos..])];
+        let mut chain = 0;
+        while candidate != usize::MAX && pos - candidate <= max_distance && chain < MAX_CHAIN {
+            let length = input[candidate..]
+                .iter()
+                .zip(&input[pos..pos + max_length])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length > best.0 {
+                best = (length, pos - candidate);
+                if length == max_length {
+                    break;
+                }
+            }
+            candidate = self.prev[candidate];
+            chain += 1;
+        }
+        return best;
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u32,
+}
+
+impl BitReader<'_> {
+    fn bits_left(&self) -> usize {
+        return (self.data.len() - self.pos) * 8 - self.bit as usize;
+    }
+
+    // `n` bits, least significant first
+    fn read(&mut self, n: u32) -> Result<u32> {
+        let mut value = 0;
+        for i in 0..n {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| invalid("stream ends in a block"))?;
+            value |= (((byte >> self.bit) & 1) as u32) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        return Ok(value);
+    }
+
+    fn align(&mut self) {
+        if self.bit > 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+// Canonical Huffman code, as the number of codes of each length and the symbols sorted by code
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman> {
+        let mut counts = [0; 16];
+        for len in lengths {
+            counts[*len as usize] += 1;
+        }
+        counts[0] = 0;
+        // more codes of a length than there is room for
+        let mut left: i32 = 1;
+        for count in &counts[1..] {
+            left = (left << 1) - *count as i32;
+            if left < 0 {
+                return Err(invalid("over-subscribed Huffman code"));
+            }
+        }
+        let mut symbols = Vec::new();
+        for len in 1..16 {
+            symbols.extend((0..lengths.len() as u16).filter(|s| lengths[*s as usize] == len));
+        }
+        return Ok(Huffman { counts, symbols });
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16> {
+        let (mut code, mut first, mut index) = (0_i32, 0_i32, 0_i32);
+        for len in 1..16 {
+            code |= reader.read(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        return Err(invalid("no such Huffman code"));
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [8; 288];
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    return (Huffman::new(&lengths).unwrap(), Huffman::new(&[5; 30]).unwrap());
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman)> {
+    let literals = reader.read(5)? as usize + 257;
+    let distances = reader.read(5)? as usize + 1;
+    let code_lengths = reader.read(4)? as usize + 4;
+    let mut lengths = [0; 19];
+    for i in CODE_LENGTH_ORDER.iter().take(code_lengths) {
+        lengths[*i] = reader.read(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&lengths)?;
+
+    let mut lengths = Vec::with_capacity(literals + distances);
+    while lengths.len() < literals + distances {
+        let (value, repeat) = match code_length_code.decode(reader)? {
+            len @ 0..=15 => (len as u8, 1),
+            16 => (
+                *lengths.last().ok_or_else(|| invalid("nothing to repeat"))?,
+                3 + reader.read(2)?,
+            ),
+            17 => (0, 3 + reader.read(3)?),
+            _ => (0, 11 + reader.read(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    if lengths.len() > literals + distances {
+        return Err(invalid("code lengths go past the alphabets"));
+    }
+    if lengths[END_OF_BLOCK as usize] == 0 {
+        return Err(invalid("no end of block code"));
+    }
+    return Ok((Huffman::new(&lengths[..literals])?, Huffman::new(&lengths[literals..])?));
+}
+
+/// Decompresses the messages of the other side of a connection.
+pub struct Inflater {
+    window: Vec<u8>,
+    context_takeover: bool,
+}
+
+impl Inflater {
+    pub fn new(context_takeover: bool) -> Inflater {
+        return Inflater {
+            window: Vec::new(),
+            context_takeover,
+        };
+    }
+
+    /// Decompresses a message (with the sync flush tail added back), refusing to output more than `max_size` bytes
+    /// so a small message can't make it allocate gigabytes.
+    pub fn decompress(&mut self, data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+        let mut reader = BitReader { data, pos: 0, bit: 0 };
+        // back-references can reach into the window, which goes first and is cut off at the end
+        let start = self.window.len();
+        let mut out = std::mem::take(&mut self.window);
+        loop {
+            if reader.bits_left() == 0 {
+                break;
+            }
+            let last = reader.read(1)? == 1;
+            match reader.read(2)? {
+                0 => {
+                    reader.align();
+                    let header = reader
+                        .data
+                        .get(reader.pos..reader.pos + 4)
+                        .ok_or_else(|| invalid("stored block header is cut short"))?;
+                    let len = u16::from_le_bytes([header[0], header[1]]);
+                    if len != !u16::from_le_bytes([header[2], header[3]]) {
+                        return Err(invalid("stored block length doesn't match its complement"));
+                    }
+                    reader.pos += 4;
+                    let stored = reader
+                        .data
+                        .get(reader.pos..reader.pos + len as usize)
+                        .ok_or_else(|| invalid("stored block is cut short"))?;
+                    if out.len() - start + stored.len() > max_size {
+                        return Err(Error::new(ErrorKind::InvalidData, "Decompressed message is too big"));
+                    }
+                    out.extend_from_slice(stored);
+                    reader.pos += len as usize;
+                }
+                kind @ (1 | 2) => {
+                    let (literals, distances) = match kind {
+                        BLOCK_DYNAMIC => dynamic_codes(&mut reader)?,
+                        _ => fixed_codes(),
+                    };
+                    inflate_block(&mut reader, &literals, &distances, &mut out, start + max_size)?;
+                }
+                _ => return Err(invalid("reserved block type")),
+            }
+            if last {
+                break;
+            }
+        }
+        let message = out.split_off(start);
+        if self.context_takeover {
+            self.window = out;
+            slide(&mut self.window, &message);
+        }
+        return Ok(message);
+    }
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    literals: &Huffman,
+    distances: &Huffman,
+    out: &mut Vec<u8>,
+    max_len: usize,
+) -> Result<()> {
+    loop {
+        let symbol = literals.decode(reader)?;
+        if symbol == END_OF_BLOCK {
+            return Ok(());
+        }
+        if out.len() >= max_len {
+            return Err(Error::new(ErrorKind::InvalidData, "Decompressed message is too big"));
+        }
+        if symbol < END_OF_BLOCK {
+            out.push(symbol as u8);
+            continue;
+        }
+        let l = (symbol - 257) as usize;
+        if l >= LENGTH_BASE.len() {
+            return Err(invalid("bad length symbol"));
+        }
+        let length = LENGTH_BASE[l] as usize + reader.read(LENGTH_EXTRA[l] as u32)? as usize;
+        let d = distances.decode(reader)? as usize;
+        if d >= DIST_BASE.len() {
+            return Err(invalid("bad distance symbol"));
+        }
+        let distance = DIST_BASE[d] as usize + reader.read(DIST_EXTRA[d] as u32)? as usize;
+        if distance > out.len() {
+            return Err(invalid("distance goes before the start of the stream"));
+        }
+        if out.len() + length > max_len {
+            return Err(Error::new(ErrorKind::InvalidData, "Decompressed message is too big"));
+        }
+        // byte by byte, the repeat can overlap what it writes
+        for _ in 0..length {
+            out.push(out[out.len() - distance]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use miniz_oxide::deflate::core::{compress, create_comp_flags_from_zip_params, CompressorOxide, TDEFLFlush};
+    use miniz_oxide::inflate::core::{decompress, inflate_flags, DecompressorOxide};
+    use miniz_oxide::inflate::TINFLStatus;
+    use proptest::prelude::*;
+
+    use crate::deflate::{Deflater, Inflater, SYNC_FLUSH_TAIL};
+
+    const MAX_SIZE: usize = 1 << 20;
+
+    // A 48x31 board with a couple of gliders, one byte per cell
+    fn board() -> Vec<u8> {
+        let mut cells = vec![0; 48 * 31];
+        for idx in [50, 99, 145, 146, 147, 300, 349, 395, 396, 397] {
+            cells[idx] = 1;
+        }
+        return cells;
+    }
+
+    // Reference inflater: decompresses the messages of one connection in turn, keeping the history between them
+    struct Reference {
+        decompressor: DecompressorOxide,
+        out: Vec<u8>,
+        pos: usize,
+    }
+
+    impl Reference {
+        fn new() -> Reference {
+            return Reference {
+                decompressor: DecompressorOxide::new(),
+                out: vec![0; 1 << 22],
+                pos: 0,
+            };
+        }
+
+        fn inflate(&mut self, msg: &[u8]) -> Vec<u8> {
+            let input = [msg, &SYNC_FLUSH_TAIL].concat();
+            let flags = inflate_flags::TINFL_FLAG_HAS_MORE_INPUT;
+            let (status, read, written) = decompress(&mut self.decompressor, &input, &mut self.out, self.pos, flags);
+            assert!(matches!(status, TINFLStatus::NeedsMoreInput), "{status:?}");
+            assert_eq!(read, input.len());
+            self.pos += written;
+            return self.out[self.pos - written..self.pos].to_vec();
+        }
+    }
+
+    // Reference deflater, with a sync flush after every message like browsers do
+    fn reference_deflate(compressor: &mut CompressorOxide, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0; data.len() * 2 + 64];
+        let (_, read, written) = compress(compressor, data, &mut out, TDEFLFlush::Sync);
+        assert_eq!(read, data.len());
+        return out[..written].to_vec();
+    }
+
+    fn strip_tail(msg: &[u8]) -> &[u8] {
+        assert!(msg.ends_with(&SYNC_FLUSH_TAIL));
+        return &msg[..msg.len() - SYNC_FLUSH_TAIL.len()];
+    }
+
+    #[test]
+    fn test_deflate_reference_inflater() {
+        let mut deflater = Deflater::new(true, 15);
+        let mut reference = Reference::new();
+        let board = board();
+        let compressed = deflater.compress(&board);
+        // runs of dead cells are a handful of back-references
+        assert!(compressed.len() < board.len() / 10, "{} B", compressed.len());
+        assert_eq!(reference.inflate(strip_tail(&compressed)), board);
+
+        // a message seen before is a few references into the previous one
+        let noise: Vec<u8> = (0..1000_u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let first = deflater.compress(&noise);
+        assert_eq!(reference.inflate(strip_tail(&first)), noise);
+        let again = deflater.compress(&noise);
+        assert!(
+            again.len() < first.len() / 10,
+            "{} B then {} B",
+            first.len(),
+            again.len()
+        );
+        assert_eq!(reference.inflate(strip_tail(&again)), noise);
+
+        let text = "This is a test log message, a test log message ✓".repeat(20);
+        assert_eq!(
+            reference.inflate(strip_tail(&deflater.compress(text.as_bytes()))),
+            text.as_bytes()
+        );
+        assert_eq!(reference.inflate(strip_tail(&deflater.compress(&[]))), b"");
+    }
+
+    #[test]
+    fn test_inflate_reference_deflater() {
+        // dynamic Huffman blocks, which our compressor never writes
+        let flags = create_comp_flags_from_zip_params(6, -15, 0);
+        let mut compressor = CompressorOxide::new(flags);
+        let mut inflater = Inflater::new(true);
+        let board = board();
+        let noise: Vec<u8> = (0..5000_u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        for msg in [&board, &noise, &board, &Vec::new()] {
+            let compressed = reference_deflate(&mut compressor, msg);
+            assert_eq!(&inflater.decompress(&compressed, MAX_SIZE).unwrap(), msg);
+        }
+        // level 0 writes stored blocks
+        let mut compressor = CompressorOxide::new(create_comp_flags_from_zip_params(0, -15, 0));
+        let compressed = reference_deflate(&mut compressor, &noise);
+        assert_eq!(Inflater::new(false).decompress(&compressed, MAX_SIZE).unwrap(), noise);
+    }
+
+    #[test]
+    fn test_inflate_limits() {
+        let zeros = Deflater::new(false, 15).compress(&[0; 100_000]);
+        assert!(zeros.len() < 1000);
+        assert!(Inflater::new(false).decompress(&zeros, 50_000).is_err());
+        assert!(Inflater::new(false).decompress(&zeros, 100_000).is_ok());
+        // reserved block type, and a back-reference before the start
+        assert!(Inflater::new(false).decompress(&[0b111], MAX_SIZE).is_err());
+        assert!(Inflater::new(false)
+            .decompress(&[0x02, 0x02, 0x00, 0x00, 0xFF, 0xFF], MAX_SIZE)
+            .is_err());
+    }
+
+    #[test]
+    fn test_no_context_takeover() {
+        let board = board();
+        let mut deflater = Deflater::new(false, 15);
+        let first = deflater.compress(&board);
+        assert_eq!(deflater.compress(&board), first);
+        // every message stands on its own
+        let mut inflater = Inflater::new(false);
+        assert_eq!(inflater.decompress(&first, MAX_SIZE).unwrap(), board);
+        assert_eq!(Reference::new().inflate(strip_tail(&first)), board);
+
+        // a smaller window keeps references within it
+        let mut small = Deflater::new(true, 8);
+        small.compress(&board);
+        assert_eq!(Reference::new().inflate(strip_tail(&small.compress(&board))), board);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_deflate_round_trip(msgs in prop::collection::vec(prop::collection::vec(0u8..4, 0..3000), 1..5)) {
+            let mut deflater = Deflater::new(true, 15);
+            let mut inflater = Inflater::new(true);
+            let mut reference = Reference::new();
+            for msg in msgs {
+                let compressed = deflater.compress(&msg);
+                prop_assert_eq!(&inflater.decompress(&compressed, MAX_SIZE).unwrap(), &msg);
+                prop_assert_eq!(&reference.inflate(strip_tail(&compressed)), &msg);
+            }
+        }
+
+        #[test]
+        fn prop_inflate_never_panics(data in prop::collection::vec(any::<u8>(), 0..256)) {
+            let _ = Inflater::new(true).decompress(&data, 10_000);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::io::{Error, ErrorKind, Result};
+
+// Entropy coding of the dead/alive runs of a grid (the same runs as the varint RLE) with a binary range coder like
+// the one in LZMA. Every run length n is written as v = n + 1 in Elias gamma style:
+//
+//   - k = bits(v) - 1 as k 1s followed by a 0
+//   - the k bits of v below the top one, most significant first
+//
+// and each of those bits is coded with a probability that adapts to the bits seen so far in the same position.
+// Dead and alive runs have separate probabilities, since alive runs are almost always short. Nothing but the coded
+// bits is sent (no code table), which matters for boards as small as ours.
+
+// probabilities are out of 1 << PROB_BITS and move 1/32 of the way towards each coded bit
+const PROB_BITS: u32 = 11;
+const PROB_INIT: u16 = 1 << (PROB_BITS - 1);
+const MOVE_BITS: u32 = 5;
+const TOP: u32 = 1 << 24;
+
+// run lengths are u64, so v has at most 64 bits
+const MAX_K: usize = 64;
+
+struct Model {
+    // [dead, alive][position in the unary k]
+    k: [[u16; MAX_K]; 2],
+    // [dead, alive][k][bit below the top one]
+    bits: Vec<u16>,
+}
+
+impl Model {
+    fn new() -> Model {
+        return Model {
+            k: [[PROB_INIT; MAX_K]; 2],
+            bits: vec![PROB_INIT; 2 * MAX_K * MAX_K],
+        };
+    }
+
+    fn bit(&mut self, alive: usize, k: usize, i: usize) -> &mut u16 {
+        return &mut self.bits[(alive * MAX_K + k) * MAX_K + i];
+    }
+}
+
+pub struct RangeEncoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    cache_size: u64,
+    out: Vec<u8>,
+}
+
+impl Default for RangeEncoder {
+    fn default() -> Self {
+        return RangeEncoder::new();
+    }
+}
+
+impl RangeEncoder {
+    pub fn new() -> RangeEncoder {
+        return RangeEncoder {
+            low: 0,
+            range: u32::MAX,
+            cache: 0,
+            cache_size: 1,
+            out: Vec::new(),
+        };
+    }
+
+    pub fn encode_bit(&mut self, prob: &mut u16, bit: bool) {
+        let bound = (self.range >> PROB_BITS) * (*prob as u32);
+        if bit {
+            self.low += bound as u64;
+            self.range -= bound;
+            *prob -= *prob >> MOVE_BITS;
+        } else {
+            self.range = bound;
+            *prob += ((1 << PROB_BITS) - *prob) >> MOVE_BITS;
+        }
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    // Outputs the top byte of low, holding back 0xFF bytes until it's known whether a carry reaches them
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xFF00_0000 || (self.low >> 32) != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            loop {
+                self.out.push(byte.wrapping_add(carry));
+                byte = 0xFF;
+                self.cache_size -= 1;
+                if self.cache_size == 0 {
+                    break;
+                }
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FF_FFFF) << 8;
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        // the first byte is always the initial (zero) cache
+        self.out.remove(0);
+        // the decoder reads zeros past the end
+        while self.out.last() == Some(&0) {
+            self.out.pop();
+        }
+        return self.out;
+    }
+}
+
+pub struct RangeDecoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    range: u32,
+    code: u32,
+}
+
+impl RangeDecoder<'_> {
+    pub fn new(data: &[u8]) -> RangeDecoder<'_> {
+        let mut decoder = RangeDecoder {
+            data,
+            pos: 0,
+            range: u32::MAX,
+            code: 0,
+        };
+        for _ in 0..4 {
+            decoder.code = (decoder.code << 8) | decoder.next_byte() as u32;
+        }
+        return decoder;
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        let byte = self.data.get(self.pos).copied().unwrap_or(0);
+        self.pos += 1;
+        return byte;
+    }
+
+    pub fn decode_bit(&mut self, prob: &mut u16) -> bool {
+        let bound = (self.range >> PROB_BITS) * (*prob as u32);
+        let bit = if self.code < bound {
+            self.range = bound;
+            *prob += ((1 << PROB_BITS) - *prob) >> MOVE_BITS;
+            false
+        } else {
+            self.code -= bound;
+            self.range -= bound;
+            *prob -= *prob >> MOVE_BITS;
+            true
+        };
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = (self.code << 8) | self.next_byte() as u32;
+        }
+        return bit;
+    }
+}
+
+pub fn compress_runs_range(data: &[u8]) -> Vec<u8> {
+    let mut encoder = RangeEncoder::new();
+    let mut model = Model::new();
+    let mut alive = false;
+    let mut count: u64 = 0;
+    let mut encode_run = |encoder: &mut RangeEncoder, alive: bool, count: u64| {
+        let v = count + 1;
+        let k = (63 - v.leading_zeros()) as usize;
+        for i in 0..=k {
+            encoder.encode_bit(&mut model.k[alive as usize][i.min(MAX_K - 1)], i < k);
+        }
+        for i in (0..k).rev() {
+            encoder.encode_bit(model.bit(alive as usize, k, i), (v >> i) & 1 == 1);
+        }
+    };
+
+    for cell in data {
+        if (*cell != 0) != alive {
+            encode_run(&mut encoder, alive, count);
+            alive = !alive;
+            count = 0;
+        }
+        count += 1;
+    }
+    if count > 0 {
+        encode_run(&mut encoder, alive, count);
+    }
+    return encoder.finish();
+}
+
+/// Decodes `compress_runs_range` data that should have exactly `len` cells.
+pub fn uncompress_runs_range(crange: &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut decoder = RangeDecoder::new(crange);
+    let mut model = Model::new();
+    let mut cells = Vec::with_capacity(len);
+    let mut alive = 0;
+    let mut first = true;
+    while cells.len() < len {
+        let mut k = 0;
+        while decoder.decode_bit(&mut model.k[alive][k.min(MAX_K - 1)]) {
+            k += 1;
+            if k >= MAX_K {
+                return Err(Error::new(ErrorKind::InvalidData, "Run length does not fit in 64 bits"));
+            }
+        }
+        let mut v: u64 = 1;
+        for i in (0..k).rev() {
+            v = (v << 1) | decoder.decode_bit(model.bit(alive, k, i)) as u64;
+        }
+        let count = v - 1;
+        // only the first run can be empty, which also means bad data can't loop forever
+        if count == 0 && !first {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Empty run in the middle of the grid",
+            ));
+        }
+        if count > (len - cells.len()) as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Run of {count} cells goes past the {len} cells of the grid"),
+            ));
+        }
+        cells.resize(cells.len() + count as usize, alive as u8);
+        alive ^= 1;
+        first = false;
+    }
+    return Ok(cells);
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use crate::entropy::{compress_runs_range, uncompress_runs_range, RangeDecoder, RangeEncoder, PROB_INIT};
+
+    #[test]
+    fn test_range_coder_bits() {
+        let bits: Vec<bool> = (0..1000).map(|i| i % 7 == 0 || i % 11 == 0).collect();
+        let mut encoder = RangeEncoder::new();
+        let mut prob = PROB_INIT;
+        for bit in &bits {
+            encoder.encode_bit(&mut prob, *bit);
+        }
+        let coded = encoder.finish();
+        // skewed bits take less than a bit each
+        assert!(coded.len() < 1000 / 8);
+
+        let mut decoder = RangeDecoder::new(&coded);
+        let mut prob = PROB_INIT;
+        let decoded: Vec<bool> = (0..1000).map(|_| decoder.decode_bit(&mut prob)).collect();
+        assert_eq!(decoded, bits);
+    }
+
+    #[test]
+    fn test_runs_range() {
+        for cells in [vec![], vec![0], vec![1], vec![0, 0, 0, 1, 1, 0, 0], vec![1; 100_000]] {
+            let crange = compress_runs_range(&cells);
+            assert_eq!(uncompress_runs_range(&crange, cells.len()).unwrap(), cells);
+        }
+        // 1000 runs of 37 dead and 2 alive cells are 2000 bytes of varint RLE
+        let repeated: Vec<u8> = (0..39_000).map(|i| (i % 39 >= 37) as u8).collect();
+        assert!(compress_runs_range(&repeated).len() < 100);
+        assert!(uncompress_runs_range(&[0xFF; 16], 10).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_runs_range_round_trip(cells in prop::collection::vec(0u8..2, 0..5000)) {
+            let crange = compress_runs_range(&cells);
+            prop_assert_eq!(uncompress_runs_range(&crange, cells.len()).unwrap(), cells);
+        }
+
+        #[test]
+        fn prop_runs_range_long_runs(runs in prop::collection::vec(1usize..200_000, 0..20)) {
+            let mut cells = Vec::new();
+            for (i, run) in runs.iter().enumerate() {
+                cells.resize(cells.len() + run, (i % 2) as u8);
+            }
+            let crange = compress_runs_range(&cells);
+            prop_assert_eq!(uncompress_runs_range(&crange, cells.len()).unwrap(), cells);
+        }
+
+        #[test]
+        fn prop_runs_range_never_panics(crange in prop::collection::vec(any::<u8>(), 0..64), len in 0usize..2000) {
+            if let Ok(cells) = uncompress_runs_range(&crange, len) {
+                prop_assert_eq!(cells.len(), len);
+            }
+        }
+    }
+}
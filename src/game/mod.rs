@@ -1,5 +1,8 @@
+use std::collections::BTreeMap;
 use std::{env::args, process::exit};
 
+use crate::checkpoint::{load_checkpoint, DEFAULT_CHECKPOINT_FILE};
+use crate::metrics::{Histogram, SECONDS_BUCKETS};
 use crate::term::reset_terminal;
 
 pub fn print_usage() {
@@ -8,13 +11,36 @@ pub fn print_usage() {
     println!("    --help  print this help");
     println!("    -w  width of the board");
     println!("    -h  height of the board");
+    println!("    --resume <file>  continue from a checkpoint file");
+    println!("    --checkpoint <file>  where checkpoints are written (default {DEFAULT_CHECKPOINT_FILE})");
+    println!("    --record <file>  record every message sent to clients");
+    println!("    --replay <file>  send a recording to clients instead of running the game");
+    println!("    --speed <x>  replay speed multiplier (default 1)");
+    println!("    --seek <generation>  start the replay at this generation");
 }
 
 pub struct State {
     pub total_bytes_sent: usize,
     pub total_messages_sent: usize,
     pub encoded_grid_lengths: Vec<usize>,
+    // encoded_grid_lengths added up as they come, so /metrics doesn't go through all of them on every scrape
+    pub sent_grid_bytes: usize,
+    // what encoded_grid_lengths would add up to if every frame was a full grid
+    pub full_grid_bytes: usize,
     pub frames: usize,
+    pub generation: u64,
+    pub checkpoint_path: String,
+    // the board came from a checkpoint, so the initial patterns must not be placed
+    pub resumed: bool,
+    pub record_path: Option<String>,
+    pub replay_path: Option<String>,
+    pub replay_speed: f64,
+    pub replay_seek: u64,
+    // for /metrics only, checkpoints don't keep them. Grid sizes by encoding and send times by transport (the
+    // ones of each client are kept by the server with the client)
+    pub grid_bytes: BTreeMap<String, Histogram>,
+    pub send_seconds: BTreeMap<String, Histogram>,
+    pub step_seconds: Histogram,
 }
 
 //pub const GRID_WIDTH: usize = 48;
@@ -24,12 +50,35 @@ pub const GRID_HEIGHT: usize = 31;
 
 pub const FPS: usize = 5;
 pub const MS_PER_FRAME: usize = 1000 / FPS;
+pub const CHECKPOINT_INTERVAL_FRAMES: usize = FPS * 30;
+// A full grid is sent at least this often even if delta frames are smaller
+pub const KEYFRAME_INTERVAL_FRAMES: usize = FPS * 10;
 pub const CELL: &str = "  ";
 
 pub static mut GRID: [u8; GRID_WIDTH * GRID_HEIGHT] = [0; GRID_WIDTH * GRID_HEIGHT];
 pub static mut PREV_GRID: [u8; GRID_WIDTH * GRID_HEIGHT] = [0; GRID_WIDTH * GRID_HEIGHT];
 
 pub fn create_state() -> State {
+    let mut state = State {
+        total_bytes_sent: 0,
+        encoded_grid_lengths: Vec::new(),
+        sent_grid_bytes: 0,
+        full_grid_bytes: 0,
+        total_messages_sent: 0,
+        frames: 0,
+        generation: 0,
+        checkpoint_path: String::from(DEFAULT_CHECKPOINT_FILE),
+        resumed: false,
+        record_path: None,
+        replay_path: None,
+        replay_speed: 1.0,
+        replay_seek: 0,
+        grid_bytes: BTreeMap::new(),
+        send_seconds: BTreeMap::new(),
+        step_seconds: Histogram::new(&SECONDS_BUCKETS),
+    };
+    let mut resume_path = None;
+
     let mut args = args().skip(1);
     while let Some(next) = args.next() {
         match next.as_str() {
@@ -37,6 +86,48 @@ pub fn create_state() -> State {
                 print_usage();
                 exit(0);
             }
+            "--resume" => match args.next() {
+                Some(path) => resume_path = Some(path),
+                None => {
+                    eprintln!("ERROR - File expected after flag --resume");
+                    exit(1);
+                }
+            },
+            "--checkpoint" => match args.next() {
+                Some(path) => state.checkpoint_path = path,
+                None => {
+                    eprintln!("ERROR - File expected after flag --checkpoint");
+                    exit(1);
+                }
+            },
+            "--record" => match args.next() {
+                Some(path) => state.record_path = Some(path),
+                None => {
+                    eprintln!("ERROR - File expected after flag --record");
+                    exit(1);
+                }
+            },
+            "--replay" => match args.next() {
+                Some(path) => state.replay_path = Some(path),
+                None => {
+                    eprintln!("ERROR - File expected after flag --replay");
+                    exit(1);
+                }
+            },
+            "--speed" => match args.next().map(|s| s.parse::<f64>()) {
+                Some(Ok(speed)) if speed > 0.0 => state.replay_speed = speed,
+                _ => {
+                    eprintln!("ERROR - Positive number expected after flag --speed");
+                    exit(1);
+                }
+            },
+            "--seek" => match args.next().map(|s| s.parse::<u64>()) {
+                Some(Ok(generation)) => state.replay_seek = generation,
+                _ => {
+                    eprintln!("ERROR - Generation expected after flag --seek");
+                    exit(1);
+                }
+            },
             _ => {
                 eprintln!("Unrecognized arg: {}", next);
                 print_usage();
@@ -45,12 +136,15 @@ pub fn create_state() -> State {
         }
     }
 
-    return State {
-        total_bytes_sent: 0,
-        encoded_grid_lengths: Vec::new(),
-        total_messages_sent: 0,
-        frames: 0,
-    };
+    if let Some(path) = resume_path {
+        if let Err(e) = unsafe { load_checkpoint(&path, &mut state) } {
+            eprintln!("ERROR - Unable to resume from {path}: {e}");
+            exit(1);
+        }
+        state.resumed = true;
+    }
+
+    return state;
 }
 
 pub unsafe fn next_grid() {
@@ -66,8 +160,8 @@ pub unsafe fn next_grid() {
     }
 }
 
-//              previous cell state     dead                      alive
-static CELL_STATE_MAP: [[u8; 8]; 2] = [[0, 0, 0, 1, 0, 0, 0, 0], [0, 0, 1, 1, 0, 0, 0, 0]];
+//              previous cell state     dead                         alive
+static CELL_STATE_MAP: [[u8; 9]; 2] = [[0, 0, 0, 1, 0, 0, 0, 0, 0], [0, 0, 1, 1, 0, 0, 0, 0, 0]];
 static IT_VALUES: [isize; 3] = [-1, 0, 1];
 
 unsafe fn compute_neighbors(curr_cell: u8, x: usize, y: usize) -> u8 {
@@ -93,3 +187,25 @@ unsafe fn compute_neighbors(curr_cell: u8, x: usize, y: usize) -> u8 {
 
     return CELL_STATE_MAP[curr_cell as usize][sum as usize];
 }
+
+/// Computes the next generation of `cells` into `next` without touching the global grid. Both slices are
+/// `width * height` long and the board wraps around its edges like `next_grid` does.
+pub fn step(cells: &[u8], next: &mut [u8], width: usize, height: usize) {
+    for y in 0..height {
+        let up = (y + height - 1) % height;
+        let down = (y + 1) % height;
+        for x in 0..width {
+            let left = (x + width - 1) % width;
+            let right = (x + 1) % width;
+            let sum = cells[left + width * up]
+                + cells[x + width * up]
+                + cells[right + width * up]
+                + cells[left + width * y]
+                + cells[right + width * y]
+                + cells[left + width * down]
+                + cells[x + width * down]
+                + cells[right + width * down];
+            next[x + width * y] = CELL_STATE_MAP[cells[x + width * y] as usize][sum as usize];
+        }
+    }
+}
@@ -0,0 +1,397 @@
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+// HTTP/1.1 (RFC 9112), as much of it as the WebSocket port needs: requests are read up to the empty line, plus the
+// body if they have a Content-Length (chunked bodies aren't taken), and every response closes the connection.
+//
+// Requests that aren't an upgrade to websocket get the files of the web page, so browsers can open
+// http://<host>:42069/ and connect back to where the page came from, or the JSON API under /api/.
+
+pub const DEFAULT_PUBLIC_DIR: &str = "public";
+pub const INDEX_FILE: &str = "index.html";
+// Script of the page. It's compiled from main.ts, and isn't in the repository
+pub const PAGE_SCRIPT: &str = "main.js";
+// Only API calls take a body, and the biggest of them is a pattern
+pub const MAX_BODY_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HttpError {
+    pub status: u16,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{} {}: {}", self.status, status_text(self.status), self.reason);
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// A request line and its headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    // without the query
+    pub path: String,
+    pub query: Option<String>,
+    // major and minor
+    pub version: (u8, u8),
+    // names in lowercase, in the order they came
+    pub headers: Vec<(String, String)>,
+    // empty until read, parse_request only sees the head
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// First value of a header, the name in any case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        return self
+            .headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str());
+    }
+
+    /// Every comma separated value of every line of a header.
+    pub fn tokens(&self, name: &str) -> Vec<&str> {
+        return self
+            .headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(','))
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .collect();
+    }
+
+    /// Value of a `name=value` parameter of the query, as it came.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        return self
+            .query
+            .as_deref()?
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value);
+    }
+
+    /// Whether it asks for a WebSocket rather than a page. It can still be an invalid upgrade.
+    pub fn is_upgrade(&self) -> bool {
+        return self
+            .tokens("upgrade")
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case("websocket"));
+    }
+}
+
+/// Parses a request head (everything up to the empty line).
+pub fn parse_request(head: &str) -> Result<Request, HttpError> {
+    let bad_request = |reason| HttpError { status: 400, reason };
+    let mut lines = head.split("\r\n");
+    let request_line: Vec<&str> = lines.next().unwrap_or_default().split(' ').collect();
+    let [method, target, version] = request_line[..] else {
+        return Err(bad_request("malformed request line"));
+    };
+    if method.is_empty() || !target.starts_with('/') {
+        return Err(bad_request("malformed request line"));
+    }
+    let version = version
+        .strip_prefix("HTTP/")
+        .and_then(|v| v.split_once('.'))
+        .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)))
+        .ok_or(bad_request("malformed HTTP version"))?;
+
+    let mut headers = Vec::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(bad_request("malformed header"));
+        };
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (target, None),
+    };
+    return Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        version,
+        headers,
+        body: Vec::new(),
+    });
+}
+
+pub fn status_text(status: u16) -> &'static str {
+    return match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        426 => "Upgrade Required",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Error",
+    };
+}
+
+/// A whole response, sent with a Content-Length and closing the connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Response {
+        return Response {
+            status,
+            headers: vec![(String::from("Content-Type"), content_type.to_string())],
+            body,
+        };
+    }
+
+    /// Plain text response with `reason` as the body.
+    pub fn error(status: u16, reason: &str) -> Response {
+        return Response::new(status, "text/plain; charset=utf-8", reason.as_bytes().to_vec());
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        return self;
+    }
+
+    /// Status line, headers and body. Answers to HEAD requests leave the body out but keep its length.
+    pub fn encode(&self, head_only: bool) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 {} {}\r\n", self.status, status_text(self.status));
+        if !self
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("connection"))
+        {
+            response.push_str("Connection: close\r\n");
+        }
+        for (name, value) in &self.headers {
+            response.push_str(&format!("{name}: {value}\r\n"));
+        }
+        response.push_str(&format!("Content-Length: {}\r\n\r\n", self.body.len()));
+        let mut response = response.into_bytes();
+        if !head_only {
+            response.extend_from_slice(&self.body);
+        }
+        return response;
+    }
+}
+
+/// Content-Type for a file, by its extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+    return match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "jpg" | "jpeg" => "image/jpeg",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    };
+}
+
+// Decodes %XX escapes, None if one is malformed or the result isn't utf8
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    return String::from_utf8(decoded).ok();
+}
+
+// File under `root` a request path points to, None if it tries to get out of it
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let path = percent_decode(path)?;
+    let mut file = root.to_path_buf();
+    for segment in path.split('/').filter(|s| !s.is_empty() && *s != ".") {
+        if segment == ".." || segment.contains(['\\', '\0']) {
+            return None;
+        }
+        file.push(segment);
+    }
+    if file.is_dir() {
+        file.push(INDEX_FILE);
+    }
+    return Some(file);
+}
+
+/// Why a missing `file` is missing, if it's a script whose TypeScript source is right there but wasn't compiled.
+pub fn unbuilt_script(file: &Path) -> Option<String> {
+    let source = file.with_extension("ts");
+    if file.extension()? != "js" || !source.is_file() {
+        return None;
+    }
+    return Some(format!(
+        "{} is not built, compile it with `npx tsc {}` next to it",
+        file.file_name()?.to_string_lossy(),
+        source.file_name()?.to_string_lossy()
+    ));
+}
+
+/// Answers a GET or HEAD with the file under `root` it asks for, `root/index.html` for `/`.
+pub fn serve_file(root: &Path, request: &Request) -> Response {
+    if request.method != "GET" && request.method != "HEAD" {
+        return Response::error(405, "only GET and HEAD are served").header("Allow", "GET, HEAD");
+    }
+    let Some(file) = resolve(root, &request.path) else {
+        return Response::error(404, "no such file");
+    };
+    return match fs::read(&file) {
+        // the page is served as it is on disk, so a rebuilt main.js shows up on reload
+        Ok(body) => Response::new(200, content_type(&file), body)
+            .header("Cache-Control", "no-cache")
+            .header("X-Content-Type-Options", "nosniff"),
+        Err(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::NotFound | ErrorKind::IsADirectory | ErrorKind::NotADirectory
+            ) =>
+        {
+            Response::error(404, &unbuilt_script(&file).unwrap_or(String::from("no such file")))
+        }
+        Err(e) => {
+            eprintln!("Unable to read {}: {e}", file.display());
+            Response::error(500, "unable to read the file")
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::http::{content_type, parse_request, serve_file, Request, Response};
+
+    fn get(path: &str) -> Request {
+        return parse_request(&format!("GET {path} HTTP/1.1\r\nHost: localhost:42069\r\n\r\n")).unwrap();
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request = parse_request(
+            "GET /main.js?v=2 HTTP/1.1\r\nHost: x\r\nUpgrade: h2c, WebSocket\r\naccept: */*\r\nAccept: text/html\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/main.js");
+        assert_eq!(request.query.as_deref(), Some("v=2"));
+        assert_eq!(request.query_param("v"), Some("2"));
+        assert_eq!(request.query_param("x"), None);
+        assert_eq!(request.version, (1, 1));
+        assert_eq!(request.header("ACCEPT"), Some("*/*"));
+        assert_eq!(request.tokens("accept"), ["*/*", "text/html"]);
+        assert!(request.is_upgrade());
+        assert!(!get("/").is_upgrade());
+        assert_eq!(parse_request("GET / HTTP/1.0\r\n\r\n").unwrap().version, (1, 0));
+
+        for head in [
+            "",
+            "GET /\r\n\r\n",
+            "GET  / HTTP/1.1\r\n\r\n",
+            "GET index.html HTTP/1.1\r\n\r\n",
+            "GET / HTTP/x\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost\r\n\r\n",
+        ] {
+            assert_eq!(parse_request(head).map_err(|e| e.status), Err(400), "{head:?}");
+        }
+    }
+
+    #[test]
+    fn test_response() {
+        let response = Response::new(200, "text/html; charset=utf-8", b"<p>hi</p>".to_vec()).encode(false);
+        assert_eq!(
+            String::from_utf8(response).unwrap(),
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: text/html; charset=utf-8\r\n\
+            Content-Length: 9\r\n\r\n<p>hi</p>"
+        );
+        // HEAD gets the length of what GET would get
+        let head = Response::error(404, "no such file").encode(true);
+        assert!(String::from_utf8(head).unwrap().ends_with("Content-Length: 12\r\n\r\n"));
+
+        assert_eq!(content_type(Path::new("public/index.html")), "text/html; charset=utf-8");
+        assert_eq!(content_type(Path::new("main.JS")), "text/javascript; charset=utf-8");
+        assert_eq!(content_type(Path::new("assets/demo.gif")), "image/gif");
+        assert_eq!(content_type(Path::new("main.ts")), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_serve_file() {
+        let root = std::env::temp_dir().join(format!("gol_public_test_{}", std::process::id()));
+        fs::create_dir_all(root.join("assets")).unwrap();
+        fs::write(root.join("index.html"), "<canvas>").unwrap();
+        fs::write(root.join("main.js"), "setUpWebSocket();").unwrap();
+        fs::write(root.join("assets/index.html"), "assets").unwrap();
+        fs::write(root.with_extension("secret"), "secret").unwrap();
+
+        let index = serve_file(&root, &get("/"));
+        assert_eq!((index.status, index.body.as_slice()), (200, b"<canvas>".as_slice()));
+        assert!(index
+            .headers
+            .contains(&(String::from("Content-Type"), String::from("text/html; charset=utf-8"))));
+        assert_eq!(serve_file(&root, &get("/main.js?v=3")).body, b"setUpWebSocket();");
+        assert_eq!(serve_file(&root, &get("/assets")).body, b"assets");
+        assert_eq!(serve_file(&root, &get("/%61ssets/")).body, b"assets");
+        assert_eq!(serve_file(&root, &get("/missing.js")).status, 404);
+        assert_eq!(serve_file(&root, &get("/main.js/x")).status, 404);
+        // a page script that wasn't compiled says so
+        fs::write(root.join("app.ts"), "let x: number = 1;").unwrap();
+        let unbuilt = serve_file(&root, &get("/app.js"));
+        assert_eq!(unbuilt.status, 404);
+        assert_eq!(
+            unbuilt.body,
+            b"app.js is not built, compile it with `npx tsc app.ts` next to it"
+        );
+
+        // nothing outside the directory, however it's spelled
+        let secret = format!(
+            "/../{}",
+            root.with_extension("secret").file_name().unwrap().to_str().unwrap()
+        );
+        for path in [
+            secret.replace("..", "%2e%2e"),
+            secret,
+            String::from("/..%5C..%5Cetc/passwd"),
+        ] {
+            assert_eq!(serve_file(&root, &get(&path)).status, 404, "{path}");
+        }
+        assert_eq!(serve_file(&root, &get("/%zz")).status, 404);
+
+        let post = parse_request("POST / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(serve_file(&root, &post).status, 405);
+
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_file(root.with_extension("secret")).unwrap();
+    }
+}
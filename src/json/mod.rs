@@ -0,0 +1,446 @@
+use std::fmt;
+
+// JSON (RFC 8259), as much of it as the HTTP API needs: request bodies are parsed into a Json value and responses
+// are built from one. Numbers are kept as f64, which holds every generation and coordinate the game gets to.
+//
+// Objects keep their keys in order, so responses come out the way they were built.
+
+// Bodies come from the network, arrays and objects nested deeper than this are refused instead of overflowing the
+// stack
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JsonError {
+    // byte offset in the text
+    pub offset: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "invalid JSON at byte {}: {}", self.offset, self.reason);
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json, JsonError> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        if parser.pos < parser.text.len() {
+            return Err(parser.error("unexpected data after the value"));
+        }
+        return Ok(value);
+    }
+
+    pub fn object<'a>(fields: impl IntoIterator<Item = (&'a str, Json)>) -> Json {
+        return Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect());
+    }
+
+    /// Value of a key of an object, None for a missing key or anything that isn't an object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        return match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        };
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        return match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        };
+    }
+
+    /// The number, if it is a whole one.
+    pub fn as_i64(&self) -> Option<i64> {
+        return match self {
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 2f64.powi(53) => Some(*n as i64),
+            _ => None,
+        };
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        return match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        };
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        return match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        };
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        return Json::Bool(b);
+    }
+}
+
+impl From<u64> for Json {
+    fn from(n: u64) -> Json {
+        return Json::Number(n as f64);
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Json {
+        return Json::Number(n as f64);
+    }
+}
+
+impl From<isize> for Json {
+    fn from(n: isize) -> Json {
+        return Json::Number(n as f64);
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json {
+        return Json::String(s.to_string());
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        return Json::String(s);
+    }
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+    fn from(items: Vec<T>) -> Json {
+        return Json::Array(items.into_iter().map(Into::into).collect());
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    return f.write_str("\"");
+}
+
+/// Compact JSON, without any whitespace.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{b}"),
+            // whole numbers without the ".0", and no NaN or infinity, which JSON doesn't have
+            Json::Number(n) if !n.is_finite() => f.write_str("null"),
+            Json::Number(n) => write!(f, "{n}"),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_str("}")
+            }
+        };
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, reason: &'static str) -> JsonError {
+        return JsonError {
+            offset: self.pos,
+            reason,
+        };
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.text.get(self.pos), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, literal: &str, value: Json) -> Result<Json, JsonError> {
+        if !self.text[self.pos..].starts_with(literal.as_bytes()) {
+            return Err(self.error("unknown literal"));
+        }
+        self.pos += literal.len();
+        return Ok(value);
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, JsonError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("nested too deep"));
+        }
+        self.skip_whitespace();
+        return match self.text.get(self.pos) {
+            None => Err(self.error("unexpected end")),
+            Some(b'n') => self.expect("null", Json::Null),
+            Some(b't') => self.expect("true", Json::Bool(true)),
+            Some(b'f') => self.expect("false", Json::Bool(false)),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'[') => self.array(depth),
+            Some(b'{') => self.object(depth),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+        };
+    }
+
+    // Comma separated items up to `end`, each read by `item`
+    fn items(&mut self, end: u8, item: &mut dyn FnMut(&mut Self) -> Result<(), JsonError>) -> Result<(), JsonError> {
+        self.pos += 1;
+        self.skip_whitespace();
+        if self.text.get(self.pos) == Some(&end) {
+            self.pos += 1;
+            return Ok(());
+        }
+        loop {
+            item(self)?;
+            self.skip_whitespace();
+            match self.text.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(&c) if c == end => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                _ => return Err(self.error("expected a comma or the end")),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Json, JsonError> {
+        let mut items = Vec::new();
+        self.items(b']', &mut |parser| {
+            items.push(parser.value(depth + 1)?);
+            Ok(())
+        })?;
+        return Ok(Json::Array(items));
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Json, JsonError> {
+        let mut fields = Vec::new();
+        self.items(b'}', &mut |parser| {
+            parser.skip_whitespace();
+            if parser.text.get(parser.pos) != Some(&b'"') {
+                return Err(parser.error("expected a key"));
+            }
+            let key = parser.string()?;
+            parser.skip_whitespace();
+            if parser.text.get(parser.pos) != Some(&b':') {
+                return Err(parser.error("expected a colon"));
+            }
+            parser.pos += 1;
+            fields.push((key, parser.value(depth + 1)?));
+            Ok(())
+        })?;
+        return Ok(Json::Object(fields));
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        let digits = |parser: &mut Self| {
+            let from = parser.pos;
+            while parser.text.get(parser.pos).is_some_and(u8::is_ascii_digit) {
+                parser.pos += 1;
+            }
+            return parser.pos > from;
+        };
+        if self.text[self.pos] == b'-' {
+            self.pos += 1;
+        }
+        // no leading zeros
+        if self.text.get(self.pos) == Some(&b'0') {
+            self.pos += 1;
+        } else if !digits(self) {
+            return Err(self.error("malformed number"));
+        }
+        if self.text.get(self.pos) == Some(&b'.') {
+            self.pos += 1;
+            if !digits(self) {
+                return Err(self.error("malformed number"));
+            }
+        }
+        if matches!(self.text.get(self.pos), Some(b'e' | b'E')) {
+            self.pos += 1;
+            if matches!(self.text.get(self.pos), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            if !digits(self) {
+                return Err(self.error("malformed number"));
+            }
+        }
+        // only ascii was consumed
+        let number = std::str::from_utf8(&self.text[start..self.pos]).unwrap();
+        return number
+            .parse()
+            .map(Json::Number)
+            .map_err(|_| self.error("malformed number"));
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let hex = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or(self.error("malformed unicode escape"))?;
+        self.pos += 4;
+        return Ok(hex);
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.pos += 1;
+        let mut s = Vec::new();
+        loop {
+            let Some(&c) = self.text.get(self.pos) else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let Some(&escape) = self.text.get(self.pos) else {
+                        return Err(self.error("unterminated string"));
+                    };
+                    self.pos += 1;
+                    let unescaped = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // characters outside the BMP come as a surrogate pair
+                            if (0xD800..0xDC00).contains(&code) && self.text[self.pos..].starts_with(b"\\u") {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return Err(self.error("unpaired surrogate"));
+                                }
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                            }
+                            char::from_u32(code).ok_or(self.error("unpaired surrogate"))?
+                        }
+                        _ => return Err(self.error("unknown escape")),
+                    };
+                    s.extend_from_slice(unescaped.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                c if c < 0x20 => return Err(self.error("control character in string")),
+                c => s.push(c),
+            }
+        }
+        // the text was a str and escapes are whole characters
+        return Ok(String::from_utf8(s).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json::Json;
+
+    #[test]
+    fn test_parse() {
+        let json =
+            Json::parse(r#" {"set": [[1, 2], [3, 4]], "paused": true, "rle": "bo$\n2bo!", "x": -1.5e1} "#).unwrap();
+        let set = json.get("set").and_then(Json::as_array).unwrap();
+        assert_eq!(set[1].as_array().unwrap()[0].as_i64(), Some(3));
+        assert_eq!(json.get("paused").and_then(Json::as_bool), Some(true));
+        assert_eq!(json.get("rle").and_then(Json::as_str), Some("bo$\n2bo!"));
+        assert_eq!(json.get("x").and_then(Json::as_i64), Some(-15));
+        assert_eq!(json.get("missing"), None);
+        assert_eq!(Json::parse("0.5").unwrap().as_i64(), None);
+        assert_eq!(Json::parse(r#""\u00e9\ud83d\ude00\/""#).unwrap(), Json::from("é😀/"));
+        assert_eq!(Json::parse("[]").unwrap(), Json::Array(vec![]));
+        assert_eq!(Json::parse("{ }").unwrap(), Json::Object(vec![]));
+        assert_eq!(Json::parse("null").unwrap(), Json::Null);
+
+        for text in [
+            "",
+            "{",
+            "[1,]",
+            "[1 2]",
+            "{\"a\" 1}",
+            "{1: 2}",
+            "01",
+            "1.",
+            "-",
+            "1e",
+            "tru",
+            "\"\\x\"",
+            "\"\\ud800\\u0041\"",
+            "\"a\nb\"",
+            "\"open",
+            "1 2",
+        ] {
+            assert!(Json::parse(text).is_err(), "{text:?}");
+        }
+        assert_eq!(Json::parse("[1,]").unwrap_err().offset, 3);
+        assert!(Json::parse(&"[".repeat(1000)).is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        let json = Json::object([
+            ("width", Json::from(48usize)),
+            ("rule", Json::from("B3/S23")),
+            ("paused", Json::from(false)),
+            ("cells", Json::from(vec![vec![0isize, 1], vec![2, 3]])),
+            ("note", Json::from("\"quoted\"\n\u{1}")),
+            ("half", Json::Number(0.5)),
+            ("none", Json::Null),
+        ]);
+        let text = json.to_string();
+        assert_eq!(
+            text,
+            r#"{"width":48,"rule":"B3/S23","paused":false,"cells":[[0,1],[2,3]],"note":"\"quoted\"\n\u0001","half":0.5,"none":null}"#
+        );
+        assert_eq!(Json::parse(&text).unwrap(), json);
+        assert_eq!(Json::Number(f64::NAN).to_string(), "null");
+    }
+}
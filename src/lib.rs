@@ -1,3 +1,16 @@
+pub mod api;
+pub mod checkpoint;
+pub mod crc32;
+pub mod deflate;
+pub mod entropy;
 pub mod game;
+pub mod http;
+pub mod json;
+pub mod metrics;
 pub mod term;
 pub mod net;
+pub mod pattern;
+pub mod protocol;
+pub mod replay;
+pub mod soup;
+pub mod sse;
@@ -152,7 +152,8 @@ fn send_msg(stream: &mut TcpStream, data: &[u8]) {
 fn send_dimensions(stream: &mut TcpStream) {}
 
 fn send_ping(stream: &mut TcpStream) {
-    let header: u8 = 0b10000000 & 9;
+    // fin bit and the ping opcode
+    let header: u8 = 0b10000000 | 0x9;
     // Server must send unmasked (mask=0) messages
     let masked_and_content_length: u8 = 0;
 
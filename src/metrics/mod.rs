@@ -0,0 +1,293 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::api::Status;
+use crate::game::State;
+use crate::http::Response;
+
+// Server statistics in the Prometheus text format (version 0.0.4), served at /metrics on the WebSocket port so they
+// can be scraped and graphed:
+//
+//   # HELP gol_frames_total Frames sent to the clients.
+//   # TYPE gol_frames_total counter
+//   gol_frames_total 1234
+//
+// Counters come from game::State and so carry on from a checkpoint, histograms start empty with every run.
+
+pub const METRICS_PATH: &str = "/metrics";
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+// Encoding label of the delta frames in gol_grid_bytes
+pub const DELTA_LABEL: &str = "DELTA";
+
+// A NONE grid of the default board is 1488 B, the rest are smaller
+pub const BYTE_BUCKETS: [f64; 10] = [8.0, 16.0, 32.0, 64.0, 128.0, 256.0, 512.0, 1024.0, 2048.0, 4096.0];
+// From a write that only fills a socket buffer to one that blocks for a frame or more
+pub const SECONDS_BUCKETS: [f64; 12] = [
+    0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.25,
+];
+
+/// Observations counted in buckets by their upper bounds, plus the ones above every bound.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    // not cumulative, the last one is for everything above the bounds
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Histogram {
+        return Histogram {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+            count: 0,
+        };
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        return self.count;
+    }
+
+    pub fn sum(&self) -> f64 {
+        return self.sum;
+    }
+}
+
+/// Observes `value` in the histogram of `label`, made with `bounds` the first time the label comes up.
+pub fn observe(histograms: &mut BTreeMap<String, Histogram>, label: &str, bounds: &'static [f64], value: f64) {
+    histograms
+        .entry(label.to_string())
+        .or_insert_with(|| Histogram::new(bounds))
+        .observe(value);
+}
+
+// Label values can be anything, quotes, backslashes and newlines are escaped
+fn labels(pairs: &[(&str, &str)]) -> String {
+    if pairs.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect();
+    return format!("{{{}}}", pairs.join(","));
+}
+
+/// Builds the text of a scrape, one metric family after the other.
+#[derive(Debug, Default)]
+pub struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    pub fn new() -> Exposition {
+        return Exposition::default();
+    }
+
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {name} {help}");
+        let _ = writeln!(self.text, "# TYPE {name} {kind}");
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: f64) {
+        self.family(name, "counter", help);
+        let _ = writeln!(self.text, "{name} {value}");
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.family(name, "gauge", help);
+        let _ = writeln!(self.text, "{name} {value}");
+    }
+
+    /// One histogram family with a series per label set, buckets counted up to each bound like Prometheus wants.
+    pub fn histogram(&mut self, name: &str, help: &str, series: &[(&[(&str, &str)], &Histogram)]) {
+        self.family(name, "histogram", help);
+        for (series_labels, histogram) in series {
+            let mut cumulative = 0;
+            for (i, count) in histogram.counts.iter().enumerate() {
+                cumulative += count;
+                let le = histogram
+                    .bounds
+                    .get(i)
+                    .map_or(String::from("+Inf"), |bound| bound.to_string());
+                let bucket_labels = labels(&[series_labels, &[("le", le.as_str())][..]].concat());
+                let _ = writeln!(self.text, "{name}_bucket{bucket_labels} {cumulative}");
+            }
+            let series_labels = labels(series_labels);
+            let _ = writeln!(self.text, "{name}_sum{series_labels} {}", histogram.sum);
+            let _ = writeln!(self.text, "{name}_count{series_labels} {}", histogram.count);
+        }
+    }
+
+    pub fn finish(self) -> String {
+        return self.text;
+    }
+}
+
+// A family with one series per `label` value
+fn labelled_histogram(
+    exposition: &mut Exposition,
+    name: &str,
+    help: &str,
+    label: &str,
+    histograms: &BTreeMap<String, Histogram>,
+) {
+    let series_labels: Vec<[(&str, &str); 1]> = histograms.keys().map(|value| [(label, value.as_str())]).collect();
+    let series: Vec<(&[(&str, &str)], &Histogram)> = series_labels
+        .iter()
+        .zip(histograms.values())
+        .map(|(labels, histogram)| (&labels[..], histogram))
+        .collect();
+    exposition.histogram(name, help, &series);
+}
+
+/// Every statistic of the server, as of the last frame. `peers` are the send times of each connected client by
+/// transport and address, which go away with the client.
+pub fn metrics_response(state: &State, status: &Status, peers: &[(&str, String, Histogram)]) -> Response {
+    let mut exposition = Exposition::new();
+    exposition.gauge(
+        "gol_active_connections",
+        "Clients connected over raw TCP, WebSocket and Server-Sent Events.",
+        status.connections as f64,
+    );
+    exposition.gauge("gol_generation", "Generation of the board.", status.generation as f64);
+    exposition.gauge("gol_population", "Live cells on the board.", status.population as f64);
+    exposition.gauge(
+        "gol_paused",
+        "1 while the game is paused.",
+        if status.paused { 1.0 } else { 0.0 },
+    );
+    exposition.counter("gol_frames_total", "Frames sent to the clients.", state.frames as f64);
+    exposition.counter(
+        "gol_sent_bytes_total",
+        "Bytes written to the clients, framing included.",
+        state.total_bytes_sent as f64,
+    );
+    exposition.counter(
+        "gol_sent_messages_total",
+        "Messages written to the clients, one per client.",
+        state.total_messages_sent as f64,
+    );
+    exposition.counter(
+        "gol_grid_bytes_total",
+        "Grid content in the adaptive format, as sent.",
+        state.sent_grid_bytes as f64,
+    );
+    exposition.counter(
+        "gol_full_grid_bytes_total",
+        "What gol_grid_bytes_total would be without delta frames.",
+        state.full_grid_bytes as f64,
+    );
+    labelled_histogram(
+        &mut exposition,
+        "gol_grid_bytes",
+        "Grid content in the adaptive format per frame, by the encoding picked (DELTA for delta frames).",
+        "encoding",
+        &state.grid_bytes,
+    );
+    labelled_histogram(
+        &mut exposition,
+        "gol_client_send_seconds",
+        "Time taken to write a message to a client, once per client and message.",
+        "transport",
+        &state.send_seconds,
+    );
+    let peer_labels: Vec<[(&str, &str); 2]> = peers
+        .iter()
+        .map(|(transport, peer, _)| [("transport", *transport), ("peer", peer.as_str())])
+        .collect();
+    let peer_series: Vec<(&[(&str, &str)], &Histogram)> = peer_labels
+        .iter()
+        .zip(peers)
+        .map(|(labels, (_, _, histogram))| (&labels[..], histogram))
+        .collect();
+    exposition.histogram(
+        "gol_peer_send_seconds",
+        "Time taken to write a message to each connected client, by its address.",
+        &peer_series,
+    );
+    exposition.histogram(
+        "gol_step_seconds",
+        "Time taken to compute a generation.",
+        &[(&[], &state.step_seconds)],
+    );
+    return Response::new(200, CONTENT_TYPE, exposition.finish().into_bytes()).header("Cache-Control", "no-store");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::metrics::{observe, Exposition, Histogram};
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new(&[1.0, 10.0]);
+        for value in [0.5, 1.0, 3.0, 50.0] {
+            histogram.observe(value);
+        }
+        assert_eq!((histogram.count(), histogram.sum()), (4, 54.5));
+
+        let mut by_transport = BTreeMap::new();
+        observe(&mut by_transport, "ws", &[1.0, 10.0], 0.5);
+        observe(&mut by_transport, "tcp", &[1.0], 2.0);
+        observe(&mut by_transport, "ws", &[], 3.0);
+        assert_eq!(by_transport["ws"].count(), 2);
+        assert_eq!(by_transport["tcp"], {
+            let mut tcp = Histogram::new(&[1.0]);
+            tcp.observe(2.0);
+            tcp
+        });
+
+        let mut exposition = Exposition::new();
+        exposition.counter("gol_frames_total", "Frames sent.", 12.0);
+        exposition.histogram(
+            "gol_grid_bytes",
+            "Grid bytes.",
+            &[
+                (&[("encoding", "VRLE")], &histogram),
+                (&[("encoding", "a\"b")], &Histogram::new(&[1.0])),
+            ],
+        );
+        exposition.histogram("gol_step_seconds", "Step time.", &[(&[], &Histogram::new(&[0.5]))]);
+        assert_eq!(
+            exposition.finish(),
+            "# HELP gol_frames_total Frames sent.\n\
+            # TYPE gol_frames_total counter\n\
+            gol_frames_total 12\n\
+            # HELP gol_grid_bytes Grid bytes.\n\
+            # TYPE gol_grid_bytes histogram\n\
+            gol_grid_bytes_bucket{encoding=\"VRLE\",le=\"1\"} 2\n\
+            gol_grid_bytes_bucket{encoding=\"VRLE\",le=\"10\"} 3\n\
+            gol_grid_bytes_bucket{encoding=\"VRLE\",le=\"+Inf\"} 4\n\
+            gol_grid_bytes_sum{encoding=\"VRLE\"} 54.5\n\
+            gol_grid_bytes_count{encoding=\"VRLE\"} 4\n\
+            gol_grid_bytes_bucket{encoding=\"a\\\"b\",le=\"1\"} 0\n\
+            gol_grid_bytes_bucket{encoding=\"a\\\"b\",le=\"+Inf\"} 0\n\
+            gol_grid_bytes_sum{encoding=\"a\\\"b\"} 0\n\
+            gol_grid_bytes_count{encoding=\"a\\\"b\"} 0\n\
+            # HELP gol_step_seconds Step time.\n\
+            # TYPE gol_step_seconds histogram\n\
+            gol_step_seconds_bucket{le=\"0.5\"} 0\n\
+            gol_step_seconds_bucket{le=\"+Inf\"} 0\n\
+            gol_step_seconds_sum 0\n\
+            gol_step_seconds_count 0\n"
+        );
+    }
+}
@@ -1,24 +1,78 @@
+#![allow(static_mut_refs)]
+
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+use crate::entropy::{compress_runs_range, uncompress_runs_range};
 use crate::game::{GRID, GRID_HEIGHT, GRID_WIDTH};
+use crate::protocol::Message;
 
 // PACKET
 // [8bit]  [16bit ]                [       ]
-// [header][content_size max=65535][content]
+// [header][content_size max=65534][content]
+//
+// Bigger contents (e.g. uncompressed boards over ~256x256) set content_size to 0xFFFF and the actual size follows
+// [8bit]  [16bit ]            [32bit       ][       ]
+// [header][content_size=65535][content_size][content]
 //
 // 8bit header allows 4 commands
 //   - 0000: New grid
 //   - 0001: Log msg
 //   - 0010: Dimensions
-//   - 0011: Unused
+//   - 0011: Delta grid
+//   - 0100: Hello (client -> server)
+//   - 0101: Encoding
+//   - 0110: Version
+//   - 0111: Error (server -> client)
+//   - 1000: Keyframe request (client -> server)
+//   - 1001: Edit cells (client -> server)
+//   - 1010: Stamp (client -> server)
+//   - 1011: Unused
 //   - ...
 //   - 1111: Unused
 pub const CMD_NEW_GRID: u8 = 0;
 pub const CMD_LOG_MSG: u8 = 1;
 pub const CMD_GRID_DIMENSIONS: u8 = 2;
+// Content is the generation and timestamp (like new grids) followed by the index (u16 BE) of every cell that changed
+// since the previous generation
+pub const CMD_DELTA_GRID: u8 = 3;
+// First message of a client: ids of the encodings it can decode, preferred first
+pub const CMD_HELLO: u8 = 4;
+// Answer to a hello: id of the encoding the server will use for that client's grids
+pub const CMD_ENCODING: u8 = 5;
+// Sent by a client before its hello and answered by the server: [version][capability flags], see the protocol
+// module
+pub const CMD_VERSION: u8 = 6;
+// Why the server is closing the connection: [error code][utf8 reason]
+pub const CMD_ERROR: u8 = 7;
+// Empty, asks for a full grid in the next frame
+pub const CMD_KEYFRAME_REQUEST: u8 = 8;
+// Cells a client changes: [action] followed by the x and y (u16 BE each) of every cell, see the protocol module
+pub const CMD_EDIT_CELLS: u8 = 9;
+// Pattern a client places with its top-left corner at a cell: [x u16 BE][y u16 BE][utf8 RLE]
+pub const CMD_STAMP: u8 = 10;
 
 // sizes are represented in Bytes
-pub const MAX_CONTENT_SIZE: usize = 65536;
+// Largest content a client or server is willing to read, so a bad size can't make it allocate gigabytes
+pub const MAX_CONTENT_SIZE: usize = 64 * 1024 * 1024;
 pub const CMD_HEADER_SIZE: usize = 1;
 pub const SIZE_HEADER_SIZE: usize = 2;
+pub const EXTENDED_SIZE: u16 = 0xFFFF;
+pub const EXTENDED_SIZE_HEADER_SIZE: usize = 4;
+
+/// [cmd][size][content], with the extended size if the content doesn't fit in 16 bits.
+pub fn message(cmd: u8, content: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(CMD_HEADER_SIZE + SIZE_HEADER_SIZE + EXTENDED_SIZE_HEADER_SIZE + content.len());
+    msg.push(cmd);
+    if content.len() < EXTENDED_SIZE as usize {
+        msg.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    } else {
+        msg.extend_from_slice(&EXTENDED_SIZE.to_be_bytes());
+        msg.extend_from_slice(&(content.len() as u32).to_be_bytes());
+    }
+    msg.extend_from_slice(content);
+    return msg;
+}
 
 // CMD       | SIZE                | CONTENT
 // 0000 0000 | 0000 0000 0000 0000 | 0000 ... 0000
@@ -26,6 +80,126 @@ pub const SIZE_HEADER_SIZE: usize = 2;
 // New grid of 10x10 cells
 // CMD(0)    | SIZE = 100          | CONTENT = grid serialized (?)
 // 0000 0000 | 0000 0000 0110 0100 | 0000 ... 0000
+//
+// New grid content starts with the generation, the server time and the encoding the grid was serialized with, the
+// server picks the smallest one for every frame
+// [varint    ][varint        ][8bit       ][        ]
+// [generation][timestamp (ms)][encoding id][cgrid   ]
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    NONE,
+    BINARY,
+    RLE,
+    // RLE with varint run lengths, see compress_rle_varint
+    VRLE,
+    // range coded runs, see the entropy module
+    RANGE,
+}
+
+pub const DEFAULT_ENCODING: Encoding = Encoding::RLE;
+pub const ENCODINGS: [Encoding; 5] = [
+    Encoding::NONE,
+    Encoding::BINARY,
+    Encoding::RLE,
+    Encoding::VRLE,
+    Encoding::RANGE,
+];
+
+impl FromStr for Encoding {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Encoding, Self::Err> {
+        match s {
+            "NONE" => Ok(Encoding::NONE),
+            "BINARY" => Ok(Encoding::BINARY),
+            "RLE" => Ok(Encoding::RLE),
+            "VRLE" => Ok(Encoding::VRLE),
+            "RANGE" => Ok(Encoding::RANGE),
+            _ => {
+                eprintln!("No ENCODING found named {s}. Defaulting to {DEFAULT_ENCODING:?}");
+                return Ok(DEFAULT_ENCODING);
+            }
+        }
+    }
+}
+
+impl Encoding {
+    pub fn id(&self) -> u8 {
+        return match self {
+            Encoding::NONE => 0,
+            Encoding::BINARY => 1,
+            Encoding::RLE => 2,
+            Encoding::VRLE => 3,
+            Encoding::RANGE => 4,
+        };
+    }
+
+    pub fn from_id(id: u8) -> Option<Encoding> {
+        return ENCODINGS.into_iter().find(|e| e.id() == id);
+    }
+
+    pub unsafe fn compress(&self) -> Vec<u8> {
+        return match self {
+            Encoding::BINARY => compress_grid().to_vec(),
+            Encoding::RLE => compress_grid_rle(),
+            _ => self.compress_cells(&GRID),
+        };
+    }
+
+    /// Same as compress for any board, not only GRID.
+    pub fn compress_cells(&self, cells: &[u8]) -> Vec<u8> {
+        return match self {
+            Encoding::NONE => cells.to_vec(),
+            Encoding::BINARY => compress_binary(cells),
+            Encoding::RLE => compress_grid_rle_arg(cells),
+            Encoding::VRLE => compress_rle_varint(cells),
+            Encoding::RANGE => compress_runs_range(cells),
+        };
+    }
+
+    /// Decodes a grid into GRID. A grid that doesn't decode to exactly GRID.len() cells leaves GRID as it was.
+    pub unsafe fn uncompress(&self, cgrid: &[u8]) -> std::io::Result<()> {
+        let cells = self.uncompress_cells(cgrid, GRID.len())?;
+        GRID.copy_from_slice(&cells);
+        return Ok(());
+    }
+
+    /// Decodes a board of `len` cells.
+    pub fn uncompress_cells(&self, cgrid: &[u8], len: usize) -> std::io::Result<Vec<u8>> {
+        return match self {
+            Encoding::NONE if cgrid.len() != len => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Grid has {} cells but should have {len}", cgrid.len()),
+            )),
+            Encoding::NONE => Ok(cgrid.to_vec()),
+            Encoding::BINARY => uncompress_binary(cgrid, len),
+            Encoding::RLE => uncompress_rle(cgrid, len),
+            Encoding::VRLE => uncompress_rle_varint(cgrid, len),
+            Encoding::RANGE => uncompress_runs_range(cgrid, len),
+        };
+    }
+}
+
+/// Encoding for a client that sent a hello with `supported` ids: the first one the server `allowed`, or else the
+/// first one the server knows, since sending something the client can decode beats the server preference.
+pub fn choose_encoding(supported: &[u8], allowed: &[Encoding]) -> Option<Encoding> {
+    let known = || supported.iter().filter_map(|id| Encoding::from_id(*id));
+    return known().find(|e| allowed.contains(e)).or_else(|| known().next());
+}
+
+/// Shortest of the encoded grids along with its encoding.
+pub fn smallest(encoded: Vec<(Encoding, Vec<u8>)>) -> (Encoding, Vec<u8>) {
+    return encoded
+        .into_iter()
+        .min_by_key(|(_, cgrid)| cgrid.len())
+        .expect("At least one encoding");
+}
+
+/// Encodes GRID with each of `encodings` and keeps the smallest.
+pub unsafe fn compress_grid_smallest(encodings: &[Encoding]) -> (Encoding, Vec<u8>) {
+    return smallest(encodings.iter().map(|e| (*e, e.compress())).collect());
+}
 
 pub unsafe fn compress_grid() -> [u8; (GRID_WIDTH * GRID_HEIGHT) / 8] {
     //  GRID = ["0", "0", "1", "0", "0", "0", "1", "0", ...] -> 80 elems (10x8)
@@ -46,6 +220,14 @@ pub unsafe fn compress_grid() -> [u8; (GRID_WIDTH * GRID_HEIGHT) / 8] {
     return compressed_grid;
 }
 
+/// compress_grid for a board of any size, the last byte is padded with dead cells.
+pub fn compress_binary(cells: &[u8]) -> Vec<u8> {
+    return cells
+        .chunks(8)
+        .map(|chunk| chunk.iter().enumerate().fold(0, |byte, (i, cell)| byte | (cell << i)))
+        .collect();
+}
+
 pub unsafe fn compress_grid_rle() -> Vec<u8> {
     //  GRID = ["0", "0", "1", "0", "0", "0", "1", "0", ...] -> 80 elems (10x8)
     //
@@ -150,9 +332,713 @@ pub fn compress_grid_rle_arg(data: &[u8]) -> Vec<u8> {
     return rle;
 }
 
+/// LEB128: 7 bits per byte, least significant group first, high bit set when more bytes follow.
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+/// Reads a varint starting at `*pos`, moving `*pos` past it.
+pub fn read_varint(data: &[u8], pos: &mut usize) -> std::io::Result<u64> {
+    let mut value: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *data
+            .get(*pos)
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "Data ended in the middle of a varint"))?;
+        *pos += 1;
+        if shift > 63 {
+            return Err(Error::new(ErrorKind::InvalidData, "Varint does not fit in 64 bits"));
+        }
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+pub fn compress_rle_varint(data: &[u8]) -> Vec<u8> {
+    //  Cells are either dead or alive, so there's no need to send the value of each run: runs alternate between
+    //  dead and alive, starting with dead (a 0 run if the first cell is alive). Run lengths are varints, so runs
+    //  shorter than 128 cells take a single byte and there's no limit on their length.
+    //
+    //  [0, 0, 0, 1, 1, 0, 0] -> [3, 2, 2]
+    //  [1, 1, 0]             -> [0, 2, 1]
+
+    let mut rle = Vec::new();
+    let mut alive = false;
+    let mut count: u64 = 0;
+    for cell in data {
+        if (*cell != 0) != alive {
+            write_varint(&mut rle, count);
+            alive = !alive;
+            count = 0;
+        }
+        count += 1;
+    }
+    if count > 0 {
+        write_varint(&mut rle, count);
+    }
+    return rle;
+}
+
+/// Decodes `compress_rle_varint` data that should have exactly `len` cells.
+pub fn uncompress_rle_varint(crle: &[u8], len: usize) -> std::io::Result<Vec<u8>> {
+    let mut cells = Vec::with_capacity(len);
+    let mut pos = 0;
+    let mut value = 0;
+    while pos < crle.len() {
+        let count = read_varint(crle, &mut pos)?;
+        if count > (len - cells.len()) as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("RLE run of {count} cells goes past the {len} cells of the grid"),
+            ));
+        }
+        cells.resize(cells.len() + count as usize, value);
+        value ^= 1;
+    }
+    if cells.len() != len {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            format!("RLE has {} cells but the grid has {len}", cells.len()),
+        ));
+    }
+    return Ok(cells);
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::net::compress_grid_rle_arg;
+    use proptest::prelude::*;
+
+    use std::io::{Cursor, ErrorKind, Read};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use crate::deflate::{Deflater, Inflater};
+    use crate::http::{parse_request, Request};
+    use crate::net::{
+        choose_encoding, close_payload, compress_binary, compress_grid_rle_arg, compress_rle_varint, connect_ws,
+        grid_delta, handle_ws_connection, http_error_response, message, parse_upgrade, parse_upgrade_response,
+        read_http_body, read_http_head, read_request, read_varint, send_ws_close, send_ws_frame_masked, send_ws_msg,
+        send_ws_msg_deflated, smallest, uncompress_binary, uncompress_rle, uncompress_rle_varint, upgrade_request,
+        write_data_to_stream, write_varint, ws_client_key, Encoding, WsConnection, WsDeflate, WsError, WsMessage,
+        WsReader, CMD_NEW_GRID, ENCODINGS, MAX_UPGRADE_SIZE, WS_CLOSE_GOING_AWAY, WS_CLOSE_INVALID_DATA,
+        WS_CLOSE_NORMAL, WS_CLOSE_POLICY_VIOLATION, WS_CLOSE_PROTOCOL_ERROR, WS_CLOSE_TOO_BIG, WS_OPCODE_BINARY,
+        WS_OPCODE_CLOSE, WS_SUBPROTOCOLS,
+    };
+    use crate::protocol::Message;
+
+    // 4096x1024 uncompressed board, 4 MiB
+    fn big_board() -> Vec<u8> {
+        return (0..4096 * 1024_u64).map(|i| ((i * 7919) % 13 == 0) as u8).collect();
+    }
+
+    // Connected pair of sockets on a random port
+    fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        return (listener.accept().unwrap().0, client);
+    }
+
+    #[test]
+    fn test_message_size() {
+        assert_eq!(message(1, b"hi"), [1, 0, 2, b'h', b'i']);
+        assert_eq!(message(0, &vec![0; 65534])[..3], [0, 0xFF, 0xFE]);
+        // 0xFFFF means the size is in the next 4 bytes
+        assert_eq!(message(0, &vec![0; 65535])[..7], [0, 0xFF, 0xFF, 0, 0, 0xFF, 0xFF]);
+        assert_eq!(message(0, &vec![0; 70000])[..7], [0, 0xFF, 0xFF, 0, 1, 0x11, 0x70]);
+    }
+
+    #[test]
+    fn test_multi_megabyte_board() {
+        let board = big_board();
+        let (mut server, mut client) = socket_pair();
+        let msg = Message::NewGrid {
+            generation: 0,
+            timestamp: 0,
+            encoding: Encoding::NONE,
+            cgrid: board.clone(),
+        };
+        let sent = msg.encode();
+        let writer = thread::spawn(move || write_data_to_stream(&mut server, &sent).unwrap());
+
+        assert_eq!(Message::read(&mut client).unwrap(), msg);
+        // header, extended size, generation, timestamp and encoding id
+        assert_eq!(writer.join().unwrap(), board.len() + 10);
+
+        let msg = Message::NewGrid {
+            generation: 0,
+            timestamp: 0,
+            encoding: Encoding::VRLE,
+            cgrid: compress_rle_varint(&board),
+        };
+        match Message::decode(&msg.encode()).unwrap() {
+            Message::NewGrid { cgrid, .. } => assert_eq!(uncompress_rle_varint(&cgrid, board.len()).unwrap(), board),
+            other => panic!("Expected a new grid, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_multi_megabyte_ws_frame() {
+        let board = big_board();
+        let (mut server, mut client) = socket_pair();
+        let msg = message(CMD_NEW_GRID, &board);
+        let sent = msg.clone();
+        let writer = thread::spawn(move || send_ws_msg(&mut server, &sent).unwrap());
+
+        // FIN + binary opcode, unmasked with a 64 bit length
+        let mut header = [0; 10];
+        client.read_exact(&mut header).unwrap();
+        assert_eq!(header[..2], [0b10000010, 127]);
+        assert_eq!(u64::from_be_bytes(header[2..].try_into().unwrap()), msg.len() as u64);
+        let mut payload = vec![0; msg.len()];
+        client.read_exact(&mut payload).unwrap();
+        assert_eq!(payload, msg);
+        assert_eq!(writer.join().unwrap(), msg.len() + 10);
+    }
+
+    // [fin + opcode][length][mask key][payload], masked with `mask` if any
+    fn ws_frame(first_byte: u8, mask: Option<[u8; 4]>, payload: &[u8]) -> Vec<u8> {
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        let mut frame = vec![first_byte];
+        match payload.len() {
+            len if len < 126 => frame.push(mask_bit | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        match mask {
+            Some(mask) => {
+                frame.extend_from_slice(&mask);
+                frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+            }
+            None => frame.extend_from_slice(payload),
+        }
+        return frame;
+    }
+
+    fn read_ws(reader: &mut WsReader<Cursor<Vec<u8>>>) -> Result<WsMessage, WsError> {
+        return reader.read_message();
+    }
+
+    #[test]
+    fn test_ws_rfc_examples() {
+        // RFC 6455 5.7
+        let hello_unmasked = vec![0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        let hello_masked = vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let hello_fragmented = vec![0x01, 0x03, 0x48, 0x65, 0x6c, 0x80, 0x02, 0x6c, 0x6f];
+        let ping = vec![0x89, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        let pong_masked = vec![0x8a, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+
+        let hello = WsMessage::Text(String::from("Hello"));
+        assert_eq!(
+            read_ws(&mut WsReader::client(Cursor::new(hello_unmasked))).unwrap(),
+            hello
+        );
+        assert_eq!(
+            read_ws(&mut WsReader::server(Cursor::new(hello_masked))).unwrap(),
+            hello
+        );
+        assert_eq!(
+            read_ws(&mut WsReader::client(Cursor::new(hello_fragmented))).unwrap(),
+            hello
+        );
+        assert_eq!(
+            read_ws(&mut WsReader::client(Cursor::new(ping))).unwrap(),
+            WsMessage::Ping(b"Hello".to_vec())
+        );
+        assert_eq!(
+            read_ws(&mut WsReader::server(Cursor::new(pong_masked))).unwrap(),
+            WsMessage::Pong(b"Hello".to_vec())
+        );
+
+        // 256 B and 64 KiB binary messages, with a 16 and a 64 bit length
+        for len in [256, 65536] {
+            let mut frame = vec![0x82];
+            match len {
+                256 => frame.extend_from_slice(&[0x7E, 0x01, 0x00]),
+                _ => frame.extend_from_slice(&[0x7F, 0, 0, 0, 0, 0, 0x01, 0, 0]),
+            }
+            frame.extend((0..len).map(|i| i as u8));
+            assert_eq!(
+                read_ws(&mut WsReader::client(Cursor::new(frame))).unwrap(),
+                WsMessage::Binary((0..len).map(|i| i as u8).collect())
+            );
+        }
+    }
+
+    #[test]
+    fn test_ws_browser_frames() {
+        // version and hello of the web page, as a browser masks it
+        let handshake = vec![
+            0x82, 0x8c, 0x9c, 0x3f, 0x51, 0xe2, 0x9a, 0x3f, 0x53, 0xe6, 0x9d, 0x3b, 0x51, 0xe6, 0x9f, 0x3d, 0x50, 0xe2,
+        ];
+        // close with 1001 (going away), sent when the tab is closed
+        let close = vec![0x88, 0x82, 0x1b, 0x7e, 0x40, 0x05, 0x18, 0x97];
+        let mut reader = WsReader::server(Cursor::new([handshake, close].concat()));
+        assert_eq!(
+            read_ws(&mut reader).unwrap(),
+            WsMessage::Binary(vec![6, 0, 2, 4, 1, 4, 0, 4, 3, 2, 1, 0])
+        );
+        assert_eq!(
+            read_ws(&mut reader).unwrap(),
+            WsMessage::Close(Some((WS_CLOSE_GOING_AWAY, String::new())))
+        );
+
+        // a big message in fragments, with a ping in between
+        let mask = Some([1, 2, 3, 4]);
+        let msg: Vec<u8> = (0..70_000_u32).map(|i| (i % 251) as u8).collect();
+        let frames = [
+            ws_frame(0x02, mask, &msg[..100]),
+            ws_frame(0x00, mask, &msg[100..60_000]),
+            ws_frame(0x89, mask, b"ping"),
+            ws_frame(0x80, mask, &msg[60_000..]),
+        ];
+        let mut reader = WsReader::server(Cursor::new(frames.concat()));
+        assert_eq!(read_ws(&mut reader).unwrap(), WsMessage::Ping(b"ping".to_vec()));
+        assert_eq!(read_ws(&mut reader).unwrap(), WsMessage::Binary(msg));
+    }
+
+    #[test]
+    fn test_ws_errors() {
+        let mask = Some([0xAA, 0xBB, 0xCC, 0xDD]);
+        let server_error = |frames: &[Vec<u8>]| read_ws(&mut WsReader::server(Cursor::new(frames.concat())));
+
+        assert!(matches!(
+            server_error(&[ws_frame(0x82, None, b"hi")]),
+            Err(WsError::BadMask { masked: false })
+        ));
+        assert!(matches!(
+            read_ws(&mut WsReader::client(Cursor::new(ws_frame(0x82, mask, b"hi")))),
+            Err(WsError::BadMask { masked: true })
+        ));
+        assert!(matches!(
+            server_error(&[ws_frame(0xC2, mask, b"hi")]),
+            Err(WsError::ReservedBits(0b100))
+        ));
+        assert!(matches!(
+            server_error(&[ws_frame(0x83, mask, b"hi")]),
+            Err(WsError::UnknownOpcode(3))
+        ));
+        assert!(matches!(
+            server_error(&[ws_frame(0x09, mask, b"hi")]),
+            Err(WsError::FragmentedControl(9))
+        ));
+        assert!(matches!(
+            server_error(&[ws_frame(0x89, mask, &[0; 126])]),
+            Err(WsError::ControlTooLarge(126))
+        ));
+        assert!(matches!(
+            server_error(&[ws_frame(0x80, mask, b"hi")]),
+            Err(WsError::UnexpectedContinuation)
+        ));
+        assert!(matches!(
+            server_error(&[ws_frame(0x01, mask, b"h"), ws_frame(0x81, mask, b"i")]),
+            Err(WsError::ExpectedContinuation(1))
+        ));
+        assert!(matches!(
+            server_error(&[ws_frame(0x81, mask, &[0xFF, 0xFE])]),
+            Err(WsError::InvalidUtf8)
+        ));
+        // 5 B in the 16 bit length
+        assert!(matches!(
+            server_error(&[vec![0x82, 0xFE, 0, 5]]),
+            Err(WsError::BadLength)
+        ));
+        // the whole message counts, not only the frame
+        let mut reader = WsReader::server(Cursor::new(
+            [ws_frame(0x02, mask, &[0; 60]), ws_frame(0x80, mask, &[0; 60])].concat(),
+        ))
+        .max_message_size(100);
+        assert!(matches!(read_ws(&mut reader), Err(WsError::TooLarge(120))));
+        // a huge length is refused before reading (or allocating) the payload
+        assert!(matches!(
+            server_error(&[vec![0x82, 0xFF, 0, 0, 0, 1, 0, 0, 0, 0]]),
+            Err(WsError::TooLarge(_))
+        ));
+        assert!(matches!(
+            server_error(&[ws_frame(0x82, mask, b"hello")[..8].to_vec()]),
+            Err(WsError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_ws_close() {
+        let mut sent = Vec::new();
+        send_ws_close(&mut sent, Some((WS_CLOSE_GOING_AWAY, "Server is shutting down"))).unwrap();
+        send_ws_close(&mut sent, None).unwrap();
+        // the reason is cut to fit in a control frame, without splitting a character
+        send_ws_close(&mut sent, Some((WS_CLOSE_PROTOCOL_ERROR, &"✓".repeat(100)))).unwrap();
+        assert_eq!(sent[..4], [0x88, 25, 0x03, 0xe9]);
+
+        let mut reader = WsReader::client(Cursor::new(sent));
+        assert_eq!(
+            read_ws(&mut reader).unwrap(),
+            WsMessage::Close(Some((WS_CLOSE_GOING_AWAY, String::from("Server is shutting down"))))
+        );
+        assert_eq!(read_ws(&mut reader).unwrap(), WsMessage::Close(None));
+        assert_eq!(
+            read_ws(&mut reader).unwrap(),
+            WsMessage::Close(Some((WS_CLOSE_PROTOCOL_ERROR, "✓".repeat(41))))
+        );
+
+        let mask = Some([9, 8, 7, 6]);
+        let close_error = |payload: &[u8]| read_ws(&mut WsReader::server(Cursor::new(ws_frame(0x88, mask, payload))));
+        assert!(matches!(close_error(&[0x03]), Err(WsError::InvalidClose(_))));
+        // 1005 (no status) is only for reporting locally
+        assert!(matches!(close_error(&[0x03, 0xed]), Err(WsError::InvalidClose(_))));
+        assert!(matches!(close_error(&[0x03, 0xe8, 0xFF]), Err(WsError::InvalidUtf8)));
+
+        assert_eq!(WsError::TooLarge(1 << 40).close_code(), Some(WS_CLOSE_TOO_BIG));
+        assert_eq!(WsError::InvalidUtf8.close_code(), Some(WS_CLOSE_INVALID_DATA));
+        assert_eq!(
+            WsError::UnexpectedContinuation.close_code(),
+            Some(WS_CLOSE_PROTOCOL_ERROR)
+        );
+    }
+
+    // The upgrade request of the web page, as a browser sends it
+    const BROWSER_UPGRADE: &str = "GET / HTTP/1.1\r\nHost: localhost:42069\r\nConnection: Upgrade\r\nPragma: no-cache\r\n\
+        Upgrade: websocket\r\nOrigin: http://localhost:42069\r\nSec-WebSocket-Version: 13\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\
+        Sec-WebSocket-Protocol: chat, gol-multi\r\n\r\n";
+
+    #[test]
+    fn test_upgrade() {
+        // key and accept from RFC 6455 1.3
+        let upgrade = parse_upgrade(BROWSER_UPGRADE, &WS_SUBPROTOCOLS).unwrap();
+        assert_eq!(upgrade.accept, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(upgrade.protocol.as_deref(), Some("gol-multi"));
+        assert!(upgrade
+            .response()
+            .starts_with("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n"));
+        assert!(upgrade
+            .response()
+            .ends_with("Sec-WebSocket-Protocol: gol-multi\r\nSec-WebSocket-Extensions: permessage-deflate\r\n\r\n"));
+
+        // header names in any case, lists in Connection (as Firefox sends it) and no subprotocol
+        let firefox = "GET /?v=4 HTTP/1.1\r\nhost: x\r\nconnection: keep-alive, Upgrade\r\nupgrade: WebSocket\r\n\
+            sec-websocket-version: 13\r\nsec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        let upgrade = parse_upgrade(firefox, &WS_SUBPROTOCOLS).unwrap();
+        assert_eq!(upgrade.protocol, None);
+        assert_eq!(upgrade.deflate, None);
+        assert!(!upgrade.response().contains("Sec-WebSocket-Protocol"));
+        assert!(!upgrade.response().contains("Sec-WebSocket-Extensions"));
+        assert_eq!(parse_upgrade(BROWSER_UPGRADE, &[]).unwrap().protocol, None);
+
+        let status = |request: &str| match parse_upgrade(request, &WS_SUBPROTOCOLS) {
+            Err(WsError::Upgrade { status, .. }) => status,
+            other => panic!("Expected the upgrade to be refused, got {other:?}"),
+        };
+        let without = |header: &str| {
+            return BROWSER_UPGRADE
+                .split("\r\n")
+                .filter(|line| !line.starts_with(header))
+                .collect::<Vec<_>>()
+                .join("\r\n");
+        };
+        assert_eq!(status(&BROWSER_UPGRADE.replace("GET", "POST")), 400);
+        assert_eq!(status(&BROWSER_UPGRADE.replace("HTTP/1.1", "HTTP/1.0")), 400);
+        assert_eq!(status(&BROWSER_UPGRADE.replace("GET / ", "GET  / ")), 400);
+        assert_eq!(status(&BROWSER_UPGRADE.replace("Pragma: no-cache", "Pragma")), 400);
+        assert_eq!(status(&without("Host")), 400);
+        assert_eq!(status(&without("Connection")), 400);
+        assert_eq!(status(&without("Sec-WebSocket-Key")), 400);
+        assert_eq!(
+            status(&BROWSER_UPGRADE.replace("dGhlIHNhbXBsZSBub25jZQ==", "dGhlIHNhbXBsZQ==")),
+            400
+        );
+        assert_eq!(status(&without("Upgrade")), 426);
+        assert_eq!(status(&BROWSER_UPGRADE.replace("Version: 13", "Version: 8")), 426);
+        assert_eq!(status(&BROWSER_UPGRADE.replace("GET /", "GET /chat")), 404);
+        // a plain page load is told to upgrade, wherever it goes
+        assert_eq!(status("GET /index.html HTTP/1.1\r\nHost: x\r\n\r\n"), 426);
+
+        let response = http_error_response(426, "only upgrades to websocket are served");
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(response.contains("\r\nSec-WebSocket-Version: 13\r\n"));
+        assert!(response.ends_with("Content-Length: 37\r\n\r\nonly upgrades to websocket are served"));
+    }
+
+    #[test]
+    fn test_deflate_negotiation() {
+        let offer = |extensions: &str| {
+            let request = BROWSER_UPGRADE.replace("permessage-deflate; client_max_window_bits", extensions);
+            return parse_upgrade(&request, &WS_SUBPROTOCOLS).unwrap().deflate;
+        };
+        let default = WsDeflate {
+            context_takeover: true,
+            max_window_bits: None,
+        };
+        assert_eq!(offer("permessage-deflate"), Some(default));
+        assert_eq!(
+            offer("x-webkit-deflate-frame, permessage-deflate; client_max_window_bits=10"),
+            Some(default)
+        );
+        assert_eq!(
+            offer("permessage-deflate; server_no_context_takeover; server_max_window_bits=\"10\""),
+            Some(WsDeflate {
+                context_takeover: false,
+                max_window_bits: Some(10),
+            })
+        );
+        // offers the server can't honour are skipped for the next one
+        assert_eq!(
+            offer("permessage-deflate; server_max_window_bits=16, permessage-deflate; server_max_window_bits=9"),
+            Some(WsDeflate {
+                context_takeover: true,
+                max_window_bits: Some(9),
+            })
+        );
+        assert_eq!(offer("permessage-deflate; client_no_context_takeover; mystery"), None);
+        assert_eq!(
+            offer("permessage-deflate; server_no_context_takeover; server_no_context_takeover"),
+            None
+        );
+        assert_eq!(offer("permessage-deflate; server_no_context_takeover=1"), None);
+        assert_eq!(offer("permessage-deflate; server_max_window_bits"), None);
+        assert_eq!(offer("x-webkit-deflate-frame"), None);
+
+        let upgrade = parse_upgrade(
+            &BROWSER_UPGRADE.replace(
+                "client_max_window_bits",
+                "server_no_context_takeover; server_max_window_bits=12",
+            ),
+            &WS_SUBPROTOCOLS,
+        )
+        .unwrap();
+        assert!(upgrade.response().contains(
+            "\r\nSec-WebSocket-Extensions: permessage-deflate; server_no_context_takeover; server_max_window_bits=12\r\n"
+        ));
+    }
+
+    #[test]
+    fn test_ws_compressed_frames() {
+        // RFC 7692 7.2.3: "Hello" compressed, in a single frame and in fragments, then with no compression
+        let hello = WsMessage::Text(String::from("Hello"));
+        let compressed = [0xc1, 0x07, 0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00].to_vec();
+        let fragmented = [0x41, 0x03, 0xf2, 0x48, 0xcd, 0x80, 0x04, 0xc9, 0xc9, 0x07, 0x00].to_vec();
+        let stored = [
+            0xc1, 0x0b, 0x00, 0x05, 0x00, 0xfa, 0xff, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x00,
+        ]
+        .to_vec();
+        for frames in [compressed.clone(), fragmented, stored] {
+            let mut reader = WsReader::client(Cursor::new(frames)).inflater(Inflater::new(true));
+            assert_eq!(read_ws(&mut reader).unwrap(), hello);
+        }
+        // the second message refers back to the first (context takeover)
+        let mut reader = WsReader::client(Cursor::new(
+            [compressed.clone(), vec![0xc1, 0x05, 0xf2, 0x00, 0x11, 0x00, 0x00]].concat(),
+        ))
+        .inflater(Inflater::new(true));
+        assert_eq!(read_ws(&mut reader).unwrap(), hello);
+        assert_eq!(read_ws(&mut reader).unwrap(), hello);
+
+        // rsv1 without permessage-deflate, on a continuation or on a control frame
+        assert!(matches!(
+            read_ws(&mut WsReader::client(Cursor::new(compressed))),
+            Err(WsError::ReservedBits(0b100))
+        ));
+        let server_error = |frames: &[Vec<u8>]| {
+            let mut reader = WsReader::server(Cursor::new(frames.concat())).inflater(Inflater::new(true));
+            return read_ws(&mut reader);
+        };
+        let mask = Some([1, 2, 3, 4]);
+        assert!(matches!(
+            server_error(&[ws_frame(0x42, mask, &[0xf2]), ws_frame(0xc0, mask, &[0x00])]),
+            Err(WsError::ReservedBits(0b100))
+        ));
+        assert!(matches!(
+            server_error(&[ws_frame(0xc9, mask, b"")]),
+            Err(WsError::ReservedBits(0b100))
+        ));
+        assert!(matches!(
+            server_error(&[ws_frame(0xe2, mask, &[0x00])]),
+            Err(WsError::ReservedBits(0b110))
+        ));
+        let error = server_error(&[ws_frame(0xc2, mask, &[0xff, 0xff])]).unwrap_err();
+        assert_eq!(error.close_code(), Some(WS_CLOSE_INVALID_DATA));
+
+        // the size limit applies to the decompressed message
+        let zeros = Deflater::new(false, 15).compress(&[0; 10_000]);
+        let mut reader = WsReader::server(Cursor::new(ws_frame(0xc2, mask, &zeros[..zeros.len() - 4])))
+            .inflater(Inflater::new(true))
+            .max_message_size(1000);
+        assert!(matches!(read_ws(&mut reader), Err(WsError::Inflate(_))));
+
+        // what the server sends inflates back, message after message
+        let board: Vec<u8> = (0..48 * 31).map(|i| (i % 17 == 0) as u8).collect();
+        let msg = message(CMD_NEW_GRID, &board);
+        let mut deflater = WsDeflate {
+            context_takeover: true,
+            max_window_bits: None,
+        }
+        .deflater();
+        let mut sent = Vec::new();
+        let first = send_ws_msg_deflated(&mut sent, Some(&mut deflater), &msg).unwrap();
+        let second = send_ws_msg_deflated(&mut sent, Some(&mut deflater), &msg).unwrap();
+        send_ws_msg_deflated(&mut sent, None, &msg).unwrap();
+        assert_eq!(sent[0], 0xc2);
+        assert!(first < msg.len() / 4 && second < first, "{first} B then {second} B");
+        let mut reader = WsReader::client(Cursor::new(sent)).inflater(Inflater::new(true));
+        for _ in 0..3 {
+            assert_eq!(read_ws(&mut reader).unwrap(), WsMessage::Binary(msg.clone()));
+        }
+    }
+
+    #[test]
+    fn test_client_upgrade() {
+        let key = ws_client_key();
+        assert_ne!(key, ws_client_key());
+        let request = upgrade_request("localhost:42069", &key, &WS_SUBPROTOCOLS);
+        let upgrade = parse_upgrade(&request, &WS_SUBPROTOCOLS).unwrap();
+        assert_eq!(
+            parse_upgrade_response(&upgrade.response(), &key, &WS_SUBPROTOCOLS).unwrap(),
+            upgrade
+        );
+
+        let refused = |response: &str| parse_upgrade_response(response, &key, &WS_SUBPROTOCOLS);
+        assert!(matches!(
+            refused(&http_error_response(404, "no websocket on this path")),
+            Err(WsError::Upgrade { status: 404, .. })
+        ));
+        assert!(matches!(refused("SSH-2.0-OpenSSH\r\n\r\n"), Err(WsError::Handshake(_))));
+        let response = upgrade.response();
+        for wrong in [
+            response.replace(&upgrade.accept, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="),
+            response.replace("gol-multi", "chat"),
+            response.replace("permessage-deflate", "x-webkit-deflate-frame"),
+            response.replace("Upgrade: websocket", "Upgrade: h2c"),
+        ] {
+            assert!(matches!(refused(&wrong), Err(WsError::Handshake(_))), "{wrong}");
+        }
+    }
+
+    #[test]
+    fn test_ws_masked_frames() {
+        let mut sent = Vec::new();
+        send_ws_frame_masked(&mut sent, WS_OPCODE_BINARY, b"hello").unwrap();
+        send_ws_frame_masked(&mut sent, WS_OPCODE_BINARY, &[7; 300]).unwrap();
+        send_ws_frame_masked(
+            &mut sent,
+            WS_OPCODE_CLOSE,
+            &close_payload(Some((WS_CLOSE_NORMAL, "bye"))),
+        )
+        .unwrap();
+        assert_eq!(sent[..2], [0x82, 0x85]);
+        assert_ne!(sent[6..11], *b"hello");
+
+        let mut reader = WsReader::server(Cursor::new(sent));
+        assert_eq!(read_ws(&mut reader).unwrap(), WsMessage::Binary(b"hello".to_vec()));
+        assert_eq!(read_ws(&mut reader).unwrap(), WsMessage::Binary(vec![7; 300]));
+        assert_eq!(
+            read_ws(&mut reader).unwrap(),
+            WsMessage::Close(Some((WS_CLOSE_NORMAL, String::from("bye"))))
+        );
+    }
+
+    fn read_binary(connection: &mut WsConnection) -> Vec<u8> {
+        return match connection.reader.read_message().unwrap() {
+            WsMessage::Binary(msg) => msg,
+            other => panic!("Expected a binary message, got {other:?}"),
+        };
+    }
+
+    #[test]
+    fn test_connect_ws() {
+        let (mut server, client) = socket_pair();
+        let accepting = thread::spawn(move || {
+            let request = read_request(&mut server).unwrap();
+            let (mut connection, first_msg) = handle_ws_connection(server, &request).unwrap();
+            send_ws_msg_deflated(&mut connection.stream, connection.deflater.as_mut(), &first_msg).unwrap();
+            return connection.reader.read_message().unwrap();
+        });
+
+        let mut connection = connect_ws(client, "localhost:42069").unwrap();
+        let hello = Message::Hello(vec![3, 2, 1, 0]).encode();
+        send_ws_frame_masked(&mut connection.stream, WS_OPCODE_BINARY, &hello).unwrap();
+        // dimensions come first, then the echo, both compressed
+        assert!(matches!(
+            Message::decode(&read_binary(&mut connection)).unwrap(),
+            Message::Dimensions { .. }
+        ));
+        assert_eq!(read_binary(&mut connection), hello);
+        send_ws_frame_masked(&mut connection.stream, WS_OPCODE_CLOSE, &close_payload(None)).unwrap();
+        assert_eq!(accepting.join().unwrap(), WsMessage::Close(None));
+    }
+
+    #[test]
+    fn test_ws_first_message_timeout() {
+        let (mut server, client) = socket_pair();
+        let accepting = thread::spawn(move || {
+            let request = read_request(&mut server).unwrap();
+            return handle_ws_connection(server, &request).map(|_| ());
+        });
+
+        // upgrades and then says nothing
+        let mut connection = connect_ws(client, "localhost:42069").unwrap();
+        assert!(matches!(accepting.join().unwrap(), Err(WsError::Io(_))));
+        assert_eq!(
+            connection.reader.read_message().unwrap(),
+            WsMessage::Close(Some((WS_CLOSE_POLICY_VIOLATION, String::from("No message in time"))))
+        );
+    }
+
+    #[test]
+    fn test_read_http_head() {
+        // the first frame right after the request is left for the frame reader
+        let mut stream = Cursor::new([BROWSER_UPGRADE.as_bytes(), &[0x82, 0x80]].concat());
+        assert_eq!(read_http_head(&mut stream, MAX_UPGRADE_SIZE).unwrap(), BROWSER_UPGRADE);
+        assert_eq!(stream.position() as usize, BROWSER_UPGRADE.len());
+
+        let endless = format!("GET / HTTP/1.1\r\nCookie: {}", "a".repeat(MAX_UPGRADE_SIZE));
+        assert!(matches!(
+            read_http_head(&mut Cursor::new(endless), MAX_UPGRADE_SIZE),
+            Err(WsError::Upgrade { status: 431, .. })
+        ));
+        assert!(matches!(
+            read_http_head(&mut Cursor::new("GET / HTTP/1.1\r\n"), MAX_UPGRADE_SIZE),
+            Err(WsError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_http_body() {
+        let post = |headers: &str| parse_request(&format!("POST /api/step HTTP/1.1\r\n{headers}\r\n")).unwrap();
+        let body = |request: &Request, data: &str| read_http_body(&mut Cursor::new(data.to_string()), request, 16);
+        let status = |result: Result<Vec<u8>, WsError>| match result {
+            Err(WsError::Upgrade { status, .. }) => status,
+            result => panic!("{result:?}"),
+        };
+
+        let request = post("Content-Length: 17\r\n");
+        assert_eq!(body(&post("Content-Length: 4\r\n"), "{}\r\nGET").unwrap(), b"{}\r\n");
+        assert_eq!(body(&post(""), "{}").unwrap(), b"");
+        assert_eq!(status(body(&request, "{}")), 413);
+        assert_eq!(status(body(&post("Content-Length: -1\r\n"), "")), 400);
+        assert_eq!(
+            status(body(&post("Transfer-Encoding: chunked\r\n"), "2\r\n{}\r\n0\r\n\r\n")),
+            501
+        );
+        assert!(matches!(
+            body(&post("Content-Length: 8\r\n"), "{}"),
+            Err(WsError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof
+        ));
+    }
 
     #[test]
     fn test_rle() {
@@ -162,6 +1048,150 @@ mod tests {
         let result = compress_grid_rle_arg(&data);
 
         assert_eq!(result, rle_data);
+        assert_eq!(uncompress_rle(&rle_data, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn test_uncompress_corrupted() {
+        let cells: Vec<u8> = (0..300).map(|i| (i % 7 == 0) as u8).collect();
+        for encoding in ENCODINGS {
+            let cgrid = encoding.compress_cells(&cells);
+            assert_eq!(encoding.uncompress_cells(&cgrid, cells.len()).unwrap(), cells);
+            assert!(encoding
+                .uncompress_cells(&cgrid[..cgrid.len() - 1], cells.len())
+                .is_err());
+        }
+        // run past the end of the grid, unknown count length, cell value other than 0 or 1
+        assert!(uncompress_rle(&[1, 4, 0], 3).is_err());
+        assert!(uncompress_rle(&[3, 0, 0, 3, 0], 3).is_err());
+        assert!(uncompress_rle(&[1, 3, 7], 3).is_err());
+        assert!(uncompress_binary(&[0xFF; 2], 8).is_err());
+    }
+
+    #[test]
+    fn test_smallest() {
+        let encoded = smallest(vec![
+            (Encoding::NONE, vec![0; 8]),
+            (Encoding::BINARY, vec![0]),
+            (Encoding::RLE, vec![1, 8, 0]),
+        ]);
+        assert_eq!(encoded, (Encoding::BINARY, vec![0]));
+
+        for encoding in ENCODINGS {
+            assert_eq!(Encoding::from_id(encoding.id()), Some(encoding));
+        }
+        assert_eq!(Encoding::from_id(5), None);
+    }
+
+    #[test]
+    fn test_choose_encoding() {
+        let all = [Encoding::NONE, Encoding::BINARY, Encoding::RLE];
+        assert_eq!(choose_encoding(&[2, 1, 0], &all), Some(Encoding::RLE));
+        assert_eq!(choose_encoding(&[9, 1], &all), Some(Encoding::BINARY));
+        assert_eq!(choose_encoding(&[2, 1], &[Encoding::BINARY]), Some(Encoding::BINARY));
+        assert_eq!(choose_encoding(&[0], &[Encoding::RLE]), Some(Encoding::NONE));
+        assert_eq!(choose_encoding(&[9], &all), None);
+        assert_eq!(choose_encoding(&[], &all), None);
+    }
+
+    #[test]
+    fn test_grid_delta() {
+        let prev = [0, 1, 1, 0, 0, 0];
+        let next = [0, 1, 0, 1, 0, 0];
+
+        assert_eq!(grid_delta(&prev, &next), [2, 3]);
+        assert_eq!(grid_delta(&prev, &prev), []);
+
+        let mut next = vec![0; 300];
+        next[299] = 1;
+        assert_eq!(grid_delta(&[0; 300], &next), [299]);
+
+        // past the 65536 cells u16 indexes could reach
+        let prev = big_board();
+        let mut next = prev.clone();
+        for idx in [5, 70_000, prev.len() - 1] {
+            next[idx] ^= 1;
+        }
+        let cells = grid_delta(&prev, &next);
+        assert_eq!(cells, [5, 70_000, 4_194_303]);
+        let delta = Message::DeltaGrid {
+            generation: 1,
+            timestamp: 2,
+            cells,
+        };
+        assert_eq!(Message::decode(&delta.encode()).unwrap(), delta);
+    }
+
+    #[test]
+    fn test_varint() {
+        for value in [0, 1, 127, 128, 300, 65535, 1 << 40, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+            let mut pos = 0;
+            assert_eq!(read_varint(&buf, &mut pos).unwrap(), value);
+            assert_eq!(pos, buf.len());
+        }
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, [0b1010_1100, 0b0000_0010]);
+        assert!(read_varint(&buf[..1], &mut 0).is_err());
+        assert!(read_varint(&[0xFF; 11], &mut 0).is_err());
+    }
+
+    #[test]
+    fn test_rle_varint() {
+        assert_eq!(compress_rle_varint(&[0, 0, 0, 1, 1, 0, 0]), [3, 2, 2]);
+        assert_eq!(compress_rle_varint(&[1, 1, 0]), [0, 2, 1]);
+        assert_eq!(compress_rle_varint(&[]), []);
+        // a run the 2 byte counts of the RLE encoding can't hold
+        assert_eq!(compress_rle_varint(&vec![0; 70_000]), [0xF0, 0xA2, 0x04]);
+
+        assert_eq!(uncompress_rle_varint(&[0, 2, 1], 3).unwrap(), [1, 1, 0]);
+        // too many cells, too few, and a varint cut in half
+        assert!(uncompress_rle_varint(&[3, 2], 4).is_err());
+        assert!(uncompress_rle_varint(&[3], 4).is_err());
+        assert!(uncompress_rle_varint(&[0x80], 4).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_rle_varint_round_trip(cells in prop::collection::vec(0u8..2, 0..5000)) {
+            let crle = compress_rle_varint(&cells);
+            prop_assert_eq!(uncompress_rle_varint(&crle, cells.len()).unwrap(), cells);
+        }
+
+        #[test]
+        fn prop_rle_varint_long_runs(runs in prop::collection::vec(1usize..200_000, 0..20)) {
+            let mut cells = Vec::new();
+            for (i, run) in runs.iter().enumerate() {
+                cells.resize(cells.len() + run, (i % 2) as u8);
+            }
+            let crle = compress_rle_varint(&cells);
+            prop_assert_eq!(uncompress_rle_varint(&crle, cells.len()).unwrap(), cells);
+        }
+
+        #[test]
+        fn prop_rle_varint_never_panics(crle in prop::collection::vec(any::<u8>(), 0..64), len in 0usize..2000) {
+            if let Ok(cells) = uncompress_rle_varint(&crle, len) {
+                prop_assert_eq!(cells.len(), len);
+            }
+        }
+
+        #[test]
+        fn prop_rle_never_panics(crle in prop::collection::vec(any::<u8>(), 0..64), len in 0usize..2000) {
+            if let Ok(cells) = uncompress_rle(&crle, len) {
+                prop_assert_eq!(cells.len(), len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compress_binary() {
+        assert_eq!(compress_binary(&[0, 1, 0, 0, 0, 1, 0, 0]), [0b00100010]);
+        assert_eq!(
+            compress_binary(&[1, 1, 0, 0, 0, 0, 0, 0, 1, 0, 1]),
+            [0b00000011, 0b00000101]
+        );
     }
 
     #[test]
@@ -176,296 +1206,1013 @@ mod tests {
     }
 }
 
-pub unsafe fn uncompress_grid_binary(cgrid: &[u8]) {
-    for (i, byte) in cgrid.iter().enumerate() {
+/// Decodes `compress_binary` data of a board with `len` cells.
+pub fn uncompress_binary(cgrid: &[u8], len: usize) -> std::io::Result<Vec<u8>> {
+    if cgrid.len() != len.div_ceil(8) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Binary grid of {} B can't hold {len} cells", cgrid.len()),
+        ));
+    }
+    let mut cells = Vec::with_capacity(len);
+    for byte in cgrid {
         for bit in 0..8 {
-            GRID[(i * 8) + bit] = (byte >> bit) & 0x01;
+            cells.push((byte >> bit) & 0x01);
         }
     }
-    for i in 0..GRID_HEIGHT {
-        eprintln!("{:?}", &GRID[(i * GRID_WIDTH)..((i * GRID_WIDTH) + GRID_WIDTH)]);
-    }
+    cells.truncate(len);
+    return Ok(cells);
 }
 
-pub unsafe fn uncompress_grid_rle(cgrid: &[u8]) {
+/// Decodes `compress_grid_rle_arg` data that should have exactly `len` cells.
+pub fn uncompress_rle(cgrid: &[u8], len: usize) -> std::io::Result<Vec<u8>> {
+    let invalid = |reason: String| Error::new(ErrorKind::InvalidData, reason);
+    let truncated = || Error::new(ErrorKind::UnexpectedEof, "RLE run is cut short");
+    let mut cells = Vec::with_capacity(len);
     let mut i = 0;
-    let mut grid_idx: usize = 0;
-    let mut len_bytes;
-    let mut value_count: u16;
-    let mut value: u8;
-
-    eprintln!("uncompress_grid_rle: {cgrid:?}");
 
     while i < cgrid.len() {
-        len_bytes = cgrid[i];
-        i += 1;
-        value_count = cgrid[i] as u16;
+        let len_bytes = cgrid[i];
+        let value_count = match len_bytes {
+            1 => *cgrid.get(i + 1).ok_or_else(truncated)? as usize,
+            2 => u16::from_be_bytes(cgrid.get((i + 1)..(i + 3)).ok_or_else(truncated)?.try_into().unwrap()) as usize,
+            _ => return Err(invalid(format!("Unsupported length for count: {len_bytes}"))),
+        };
+        i += 1 + len_bytes as usize;
+        let value = *cgrid.get(i).ok_or_else(truncated)?;
         i += 1;
 
-        if len_bytes == 1 {
-        } else if len_bytes == 2 {
-            value_count = (value_count << 8) | (cgrid[i] as u16);
-            i += 1;
-        } else {
-            panic!("Unsupported length for count: {len_bytes}");
+        if value > 1 {
+            return Err(invalid(format!("Cell value {value} is neither dead nor alive")));
         }
+        if value_count > len - cells.len() {
+            return Err(invalid(format!(
+                "RLE run of {value_count} cells goes past the {len} cells of the grid"
+            )));
+        }
+        cells.resize(cells.len() + value_count, value);
+    }
+    if cells.len() != len {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            format!("RLE has {} cells but the grid has {len}", cells.len()),
+        ));
+    }
+    return Ok(cells);
+}
 
-        value = cgrid[i];
-        i += 1;
-
-        eprintln!(
-            "Received {value_count} {value}s from [{grid_idx}, {}]",
-            grid_idx + (value_count as usize)
-        );
+/// Indexes of the cells that differ between the two grids. A glider changes ~10 cells per generation, so this is
+/// usually a fraction of the RLE grid.
+pub fn grid_delta(prev: &[u8], next: &[u8]) -> Vec<u32> {
+    return prev
+        .iter()
+        .zip(next.iter())
+        .enumerate()
+        .filter(|(_, (p, n))| p != n)
+        .map(|(i, _)| i as u32)
+        .collect();
+}
 
-        for _ in 0..value_count {
-            GRID[grid_idx] = value;
-            grid_idx += 1;
-        }
+/// Flips the cells of a delta grid on top of the current grid. A delta with a cell outside of the grid leaves GRID as
+/// it was.
+pub unsafe fn apply_grid_delta(delta: &[u32]) -> std::io::Result<()> {
+    if let Some(idx) = delta.iter().find(|idx| **idx as usize >= GRID.len()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Delta cell {idx} is outside of the {} cells of the grid", GRID.len()),
+        ));
     }
+    for idx in delta {
+        GRID[*idx as usize] ^= 1;
+    }
+    return Ok(());
 }
 
 /***** WS STUFF ******/
 
-use core::panic;
 use std::{
-    io::{BufRead, BufReader, Read, Write},
-    net::TcpStream,
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    time::{Duration, Instant},
 };
 
 use base64::{engine::general_purpose, Engine};
 use sha1::{Digest, Sha1};
 
+use crate::deflate::{Deflater, Inflater, MAX_WINDOW_BITS, MIN_WINDOW_BITS, SYNC_FLUSH_TAIL};
+use crate::http::{parse_request, status_text, HttpError, Request, MAX_BODY_SIZE};
+
 const MAGIC: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-const RESP: &str = "HTTP/1.1 101 Switching Protocols
-Upgrade: websocket
-Connection: Upgrade
-Sec-WebSocket-Accept: ";
-
-pub fn handle_ws_connection(mut stream: TcpStream) -> TcpStream {
-    let buf_reader = BufReader::new(&mut stream);
-    let mut key = String::new();
-    let http_request: Vec<_> = buf_reader
-        .lines()
-        .map(|result| {
-            let l = result.unwrap();
-            if l.starts_with("Sec-WebSocket-Key") {
-                key = l.split(": ").last().unwrap().to_string();
+
+// FRAME (RFC 6455 5.2)
+// [1bit][3bit][4bit  ][1bit][7bit       ][16/64bit        ][32bit   ][       ]
+// [fin ][rsv ][opcode][mask][payload_len][extended length][mask key][payload]
+//
+// payload_len 126 means the length is in the next 16 bits, 127 in the next 64. Clients mask every frame they send,
+// servers never do. A message is either a single frame with fin set or a text/binary frame followed by
+// continuation frames, the last of them with fin set. Control frames (close, ping, pong) can come between those
+// fragments but are never fragmented themselves.
+pub const WS_OPCODE_CONTINUATION: u8 = 0x0;
+pub const WS_OPCODE_TEXT: u8 = 0x1;
+pub const WS_OPCODE_BINARY: u8 = 0x2;
+pub const WS_OPCODE_CLOSE: u8 = 0x8;
+pub const WS_OPCODE_PING: u8 = 0x9;
+pub const WS_OPCODE_PONG: u8 = 0xA;
+// Set on the first frame of a message compressed with permessage-deflate
+pub const WS_RSV1: u8 = 0b01000000;
+
+// Largest message (all of its fragments) a WebSocket peer is willing to read: a whole protocol message
+pub const MAX_WS_MESSAGE_SIZE: usize =
+    CMD_HEADER_SIZE + SIZE_HEADER_SIZE + EXTENDED_SIZE_HEADER_SIZE + MAX_CONTENT_SIZE;
+pub const MAX_WS_CONTROL_SIZE: usize = 125;
+
+// Close status codes (RFC 6455 7.4.1), the first 2 bytes of a close frame, followed by an utf8 reason
+pub const WS_CLOSE_NORMAL: u16 = 1000;
+pub const WS_CLOSE_GOING_AWAY: u16 = 1001;
+pub const WS_CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const WS_CLOSE_INVALID_DATA: u16 = 1007;
+pub const WS_CLOSE_POLICY_VIOLATION: u16 = 1008;
+pub const WS_CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug)]
+pub enum WsError {
+    Io(io::Error),
+    // rsv bits are only for extensions, and none that uses them was negotiated (or they're on the wrong frame)
+    ReservedBits(u8),
+    UnknownOpcode(u8),
+    // clients have to mask their frames and servers must not
+    BadMask { masked: bool },
+    // length that doesn't use the shortest form, or a 64 bit one with the top bit set
+    BadLength,
+    TooLarge(u64),
+    ControlTooLarge(usize),
+    FragmentedControl(u8),
+    // continuation frame without a message to continue
+    UnexpectedContinuation,
+    // text or binary frame while the fragments of another message were still coming
+    ExpectedContinuation(u8),
+    InvalidUtf8,
+    // compressed message that doesn't inflate, or inflates past the size limit
+    Inflate(io::Error),
+    // close frame with a single byte, or a status code nobody may send
+    InvalidClose(&'static str),
+    // HTTP status the upgrade request was refused with
+    Upgrade { status: u16, reason: &'static str },
+    // the server accepted the upgrade with an answer the client can't go on with
+    Handshake(&'static str),
+}
+
+impl fmt::Display for WsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            WsError::Io(e) => write!(f, "{e}"),
+            WsError::ReservedBits(bits) => write!(f, "Reserved bits {bits:#05b} set without an extension using them"),
+            WsError::UnknownOpcode(opcode) => write!(f, "Unknown opcode {opcode:#x}"),
+            WsError::BadMask { masked: true } => write!(f, "Frame from the server is masked"),
+            WsError::BadMask { masked: false } => write!(f, "Frame from the client isn't masked"),
+            WsError::BadLength => write!(f, "Payload length isn't in its shortest form"),
+            WsError::TooLarge(size) => write!(
+                f,
+                "Message of {size} B is bigger than the {MAX_WS_MESSAGE_SIZE} B limit"
+            ),
+            WsError::ControlTooLarge(size) => {
+                write!(f, "Control frame of {size} B is bigger than {MAX_WS_CONTROL_SIZE} B")
             }
-            return l;
-        })
-        .take_while(|line| !line.is_empty())
-        .collect();
+            WsError::FragmentedControl(opcode) => write!(f, "Control frame {opcode:#x} is fragmented"),
+            WsError::UnexpectedContinuation => write!(f, "Continuation frame without a message to continue"),
+            WsError::ExpectedContinuation(opcode) => {
+                write!(f, "Frame {opcode:#x} in the middle of a fragmented message")
+            }
+            WsError::InvalidUtf8 => write!(f, "Text message is not valid utf8"),
+            WsError::Inflate(e) => write!(f, "Unable to decompress message: {e}"),
+            WsError::InvalidClose(reason) => write!(f, "Invalid close frame: {reason}"),
+            WsError::Upgrade { status, reason } => write!(f, "Upgrade refused with {status}: {reason}"),
+            WsError::Handshake(reason) => write!(f, "Invalid upgrade response: {reason}"),
+        };
+    }
+}
 
-    eprintln!("Request: {http_request:#?}");
-    eprintln!("Key: {key}");
-    let mut hasher = Sha1::new();
-    hasher.update(format!("{key}{MAGIC}"));
-    let result = hasher.finalize();
-    eprintln!("sha1(key+MAGIC): {:?}", result);
-    let bkey = general_purpose::STANDARD.encode(result);
+impl std::error::Error for WsError {}
+
+impl WsError {
+    /// Status to close the connection with, None if it can't be closed properly (the connection itself failed).
+    pub fn close_code(&self) -> Option<u16> {
+        return match self {
+            WsError::Io(_) | WsError::Upgrade { .. } | WsError::Handshake(_) => None,
+            WsError::TooLarge(_) | WsError::ControlTooLarge(_) => Some(WS_CLOSE_TOO_BIG),
+            WsError::InvalidUtf8 | WsError::Inflate(_) => Some(WS_CLOSE_INVALID_DATA),
+            _ => Some(WS_CLOSE_PROTOCOL_ERROR),
+        };
+    }
+}
 
-    let response = format!("{}{}\n\n", RESP, bkey).replace("\n", "\r\n");
-    eprintln!("Sending:\n{response}");
+impl From<io::Error> for WsError {
+    fn from(e: io::Error) -> Self {
+        return WsError::Io(e);
+    }
+}
 
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
-    eprintln!("Sent handshake response");
+impl From<HttpError> for WsError {
+    fn from(e: HttpError) -> Self {
+        return WsError::Upgrade {
+            status: e.status,
+            reason: e.reason,
+        };
+    }
+}
 
-    let mut one_byte: [u8; 1] = [0; 1];
-    let mut two_bytes: [u8; 2] = [0; 2];
-    let _four_bytes: [u8; 4] = [0; 4];
-
-    let fin: u8;
-    let rsv1: u8;
-    let rsv2: u8;
-    let rsv3: u8;
-    let opcode: u8;
-    let masked: u8;
-    let mut content_length: u64;
-    let mut mask: [u8; 4] = [0; 4];
-
-    let mut data: [u8; 128] = [0; 128];
-
-    stream.read_exact(&mut one_byte).unwrap();
-
-    fin = (one_byte[0] & 0b10000000) >> 7;
-    rsv1 = one_byte[0] & 0b01000000;
-    rsv2 = one_byte[0] & 0b00100000;
-    rsv3 = one_byte[0] & 0b00010000;
-    opcode = one_byte[0] & 0b00001111;
-
-    stream.read_exact(&mut one_byte).unwrap();
-
-    masked = (one_byte[0] & 0b10000000) >> 7;
-    content_length = (one_byte[0] & 0b01111111) as u64;
-
-    content_length = if content_length < 126 {
-        eprintln!("Content length is full");
-        content_length
-    } else if content_length == 126 {
-        stream.read_exact(&mut two_bytes).unwrap();
-        todo!("Parse more than 7bit length properly");
-    } else if content_length == 127 {
-        todo!("Parse more than 7bit length properly");
-    } else {
-        panic!("This is impossible so idk");
+impl From<WsError> for io::Error {
+    fn from(e: WsError) -> Self {
+        return match e {
+            WsError::Io(e) => e,
+            _ => io::Error::new(ErrorKind::InvalidData, e.to_string()),
+        };
+    }
+}
+
+/// A single frame, already unmasked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WsFrame {
+    pub fin: bool,
+    // rsv1, the message is compressed
+    pub compressed: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// A whole message, with its fragments put together.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WsMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    // status code and reason, if the peer gave any
+    Close(Option<(u16, String)>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+}
+
+/// Reads frames and messages from one side of a WebSocket connection.
+pub struct WsReader<R: Read> {
+    stream: R,
+    // frames from clients must be masked, frames from servers must not
+    masked: bool,
+    max_message_size: usize,
+    // set when permessage-deflate was negotiated
+    inflater: Option<Inflater>,
+    // opcode, whether it's compressed and payload so far of a fragmented message
+    partial: Option<(u8, bool, Vec<u8>)>,
+}
+
+impl<R: Read> WsReader<R> {
+    /// Reader for the frames a client sends to the server.
+    pub fn server(stream: R) -> WsReader<R> {
+        return WsReader {
+            stream,
+            masked: true,
+            max_message_size: MAX_WS_MESSAGE_SIZE,
+            inflater: None,
+            partial: None,
+        };
+    }
+
+    /// Reader for the frames a server sends to a client.
+    pub fn client(stream: R) -> WsReader<R> {
+        return WsReader {
+            masked: false,
+            ..WsReader::server(stream)
+        };
+    }
+
+    pub fn max_message_size(mut self, size: usize) -> WsReader<R> {
+        self.max_message_size = size;
+        return self;
+    }
+
+    /// Accepts messages compressed with permessage-deflate, decompressing them with `inflater`.
+    pub fn inflater(mut self, inflater: Inflater) -> WsReader<R> {
+        self.inflater = Some(inflater);
+        return self;
+    }
+
+    /// Reads the next frame. Nothing past it is read, so the stream can be handed over between frames.
+    pub fn read_frame(&mut self) -> Result<WsFrame, WsError> {
+        let mut header = [0; 2];
+        self.stream.read_exact(&mut header)?;
+        let fin = header[0] & 0b10000000 != 0;
+        let compressed = header[0] & WS_RSV1 != 0;
+        let rsv = (header[0] & 0b01110000) >> 4;
+        let opcode = header[0] & 0b00001111;
+        let masked = header[1] & 0b10000000 != 0;
+
+        let control = match opcode {
+            WS_OPCODE_CONTINUATION | WS_OPCODE_TEXT | WS_OPCODE_BINARY => false,
+            WS_OPCODE_CLOSE | WS_OPCODE_PING | WS_OPCODE_PONG => true,
+            _ => return Err(WsError::UnknownOpcode(opcode)),
+        };
+        // only the first frame of a data message says it's compressed
+        let may_compress = self.inflater.is_some() && matches!(opcode, WS_OPCODE_TEXT | WS_OPCODE_BINARY);
+        if rsv != 0 && !(compressed && may_compress && rsv == WS_RSV1 >> 4) {
+            return Err(WsError::ReservedBits(rsv));
+        }
+        if masked != self.masked {
+            return Err(WsError::BadMask { masked });
+        }
+
+        let length = match header[1] & 0b01111111 {
+            126 => {
+                let mut length = [0; 2];
+                self.stream.read_exact(&mut length)?;
+                let length = u16::from_be_bytes(length) as u64;
+                if length < 126 {
+                    return Err(WsError::BadLength);
+                }
+                length
+            }
+            127 => {
+                let mut length = [0; 8];
+                self.stream.read_exact(&mut length)?;
+                let length = u64::from_be_bytes(length);
+                if length <= u16::MAX as u64 || length >> 63 != 0 {
+                    return Err(WsError::BadLength);
+                }
+                length
+            }
+            length => length as u64,
+        };
+        if control && !fin {
+            return Err(WsError::FragmentedControl(opcode));
+        }
+        if control && length > MAX_WS_CONTROL_SIZE as u64 {
+            return Err(WsError::ControlTooLarge(length as usize));
+        }
+        // fragments so far count towards the limit, so a message can't get past it a frame at a time
+        let so_far = match (&self.partial, control) {
+            (Some((_, _, payload)), false) => payload.len() as u64,
+            _ => 0,
+        };
+        if length + so_far > self.max_message_size as u64 {
+            return Err(WsError::TooLarge(length + so_far));
+        }
+
+        let mut mask = [0; 4];
+        if masked {
+            self.stream.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0; length as usize];
+        self.stream.read_exact(&mut payload)?;
+        if masked {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+        return Ok(WsFrame {
+            fin,
+            compressed,
+            opcode,
+            payload,
+        });
+    }
+
+    /// Reads frames until a whole message is in, decompressing it if it was compressed. Control frames between the
+    /// fragments of a message are returned right away, the fragments are kept for the next call.
+    pub fn read_message(&mut self) -> Result<WsMessage, WsError> {
+        loop {
+            let frame = self.read_frame()?;
+            match frame.opcode {
+                WS_OPCODE_CLOSE => return parse_close(&frame.payload).map(WsMessage::Close),
+                WS_OPCODE_PING => return Ok(WsMessage::Ping(frame.payload)),
+                WS_OPCODE_PONG => return Ok(WsMessage::Pong(frame.payload)),
+                _ => {}
+            }
+            let (opcode, compressed, mut payload) = match (frame.opcode, self.partial.take()) {
+                (WS_OPCODE_CONTINUATION, None) => return Err(WsError::UnexpectedContinuation),
+                (WS_OPCODE_CONTINUATION, Some((opcode, compressed, mut payload))) => {
+                    payload.extend_from_slice(&frame.payload);
+                    (opcode, compressed, payload)
+                }
+                (opcode, None) => (opcode, frame.compressed, frame.payload),
+                (opcode, Some(_)) => return Err(WsError::ExpectedContinuation(opcode)),
+            };
+            if !frame.fin {
+                self.partial = Some((opcode, compressed, payload));
+                continue;
+            }
+            if let (true, Some(inflater)) = (compressed, &mut self.inflater) {
+                payload.extend_from_slice(&SYNC_FLUSH_TAIL);
+                payload = inflater
+                    .decompress(&payload, self.max_message_size)
+                    .map_err(WsError::Inflate)?;
+            }
+            return match opcode {
+                WS_OPCODE_TEXT => String::from_utf8(payload)
+                    .map(WsMessage::Text)
+                    .map_err(|_| WsError::InvalidUtf8),
+                _ => Ok(WsMessage::Binary(payload)),
+            };
+        }
+    }
+}
+
+fn parse_close(payload: &[u8]) -> Result<Option<(u16, String)>, WsError> {
+    let (code, reason) = match payload {
+        [] => return Ok(None),
+        [_] => return Err(WsError::InvalidClose("status code is a single byte")),
+        [c0, c1, reason @ ..] => (u16::from_be_bytes([*c0, *c1]), reason),
     };
+    // 1004-1006 and 1015 are reserved or only for reporting locally, below 1000 is unused
+    if !(1000..5000).contains(&code) || matches!(code, 1004..=1006 | 1015) {
+        return Err(WsError::InvalidClose("status code can't be sent"));
+    }
+    let reason = String::from_utf8(reason.to_vec()).map_err(|_| WsError::InvalidUtf8)?;
+    return Ok(Some((code, reason)));
+}
 
-    eprintln!("fin: {}", fin);
-    eprintln!("rsv1: {rsv1}");
-    eprintln!("rsv2: {rsv2}");
-    eprintln!("rsv3: {rsv3}");
-    eprintln!("opcode: {opcode}");
-    eprintln!("masked: {masked}");
-    eprintln!("content_length: {content_length}");
+// UPGRADE (RFC 6455 4.2)
+// The request has to be a GET of WS_PATH over HTTP/1.1 or later with a Host, `Upgrade: websocket`,
+// `Connection: Upgrade`, `Sec-WebSocket-Version: 13` and a Sec-WebSocket-Key of 16 base64 bytes. Header names
+// are case insensitive and Upgrade/Connection/Sec-WebSocket-Protocol are comma separated lists. Anything else is
+// answered with an HTTP error and the connection is closed:
+//   - 400: malformed request or missing headers
+//   - 404: some other path
+//   - 408: the request took longer than UPGRADE_TIMEOUT
+//   - 426: not an upgrade to websocket, or another websocket version
+//   - 431: the request is bigger than MAX_UPGRADE_SIZE
+//
+// After the 101 the client has FIRST_MESSAGE_TIMEOUT to send its first message (at most that long between two reads
+// of it), or the connection is closed with 1008.
+//
+// Clients offer extensions in Sec-WebSocket-Extensions, each with its `;` separated parameters. The first
+// permessage-deflate offer (RFC 7692) the server can honour is accepted, browsers offer it with
+// `client_max_window_bits` only. The server keeps its compression context between messages unless the client asks
+// for `server_no_context_takeover`, and its back-references within `server_max_window_bits` if given. The client's
+// own parameters don't matter, the server always keeps the 32 KiB its messages can reach back.
+pub const WS_PATH: &str = "/";
+pub const WS_VERSION: &str = "13";
+// Subprotocols the server speaks, preferred first. Clients that ask for none are still accepted
+pub const WS_SUBPROTOCOLS: [&str; 1] = ["gol-multi"];
+pub const MAX_UPGRADE_SIZE: usize = 8 * 1024;
+pub const UPGRADE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const FIRST_MESSAGE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const WS_DEFLATE: &str = "permessage-deflate";
+
+/// permessage-deflate parameters of the messages the server sends.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WsDeflate {
+    pub context_takeover: bool,
+    // None if the client didn't limit the window
+    pub max_window_bits: Option<u8>,
+}
 
-    if masked > 0 {
-        stream.read_exact(&mut mask).unwrap();
-        eprintln!("mask: {:?}", mask);
+impl WsDeflate {
+    pub fn deflater(&self) -> Deflater {
+        return Deflater::new(self.context_takeover, self.max_window_bits.unwrap_or(MAX_WINDOW_BITS));
     }
+}
 
-    eprintln!("Reading data");
-    stream.read_exact(&mut data[0..(content_length as usize)]).unwrap();
-    eprintln!("raw_data: {:?}", data);
+/// A valid upgrade request, as the server answers it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WsUpgrade {
+    // Sec-WebSocket-Accept
+    pub accept: String,
+    // the first subprotocol of the client that the server speaks
+    pub protocol: Option<String>,
+    // set if the client offered permessage-deflate
+    pub deflate: Option<WsDeflate>,
+}
 
-    for i in 0..content_length {
-        data[i as usize] ^= mask[(i % 4) as usize];
+impl WsUpgrade {
+    pub fn response(&self) -> String {
+        let mut response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n",
+            self.accept
+        );
+        if let Some(protocol) = &self.protocol {
+            response.push_str(&format!("Sec-WebSocket-Protocol: {protocol}\r\n"));
+        }
+        if let Some(deflate) = &self.deflate {
+            response.push_str(&format!("Sec-WebSocket-Extensions: {WS_DEFLATE}"));
+            if !deflate.context_takeover {
+                response.push_str("; server_no_context_takeover");
+            }
+            if let Some(bits) = deflate.max_window_bits {
+                response.push_str(&format!("; server_max_window_bits={bits}"));
+            }
+            response.push_str("\r\n");
+        }
+        response.push_str("\r\n");
+        return response;
     }
+}
 
-    let string = String::from_utf8(data[0..(content_length as usize)].to_vec()).unwrap();
-    eprintln!("data: {string}");
+/// Sec-WebSocket-Accept for a Sec-WebSocket-Key.
+pub fn ws_accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(format!("{key}{MAGIC}"));
+    return general_purpose::STANDARD.encode(hasher.finalize());
+}
 
-    if string.len() > 128 - "echo: ".len() {
-        todo!("Handle echo of larger chunks of data");
+// Parameters of a permessage-deflate offer, None if it isn't one or the server can't honour it
+fn parse_deflate_offer(offer: &str) -> Option<WsDeflate> {
+    let mut params = offer.split(';').map(str::trim);
+    if !params.next()?.eq_ignore_ascii_case(WS_DEFLATE) {
+        return None;
     }
+    let mut deflate = WsDeflate {
+        context_takeover: true,
+        max_window_bits: None,
+    };
+    let mut seen = Vec::new();
+    for param in params {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (param, None),
+        };
+        // every parameter at most once
+        if seen.contains(&name) {
+            return None;
+        }
+        seen.push(name);
+        let window_bits = |value: &str| {
+            value
+                .parse()
+                .ok()
+                .filter(|b| (MIN_WINDOW_BITS..=MAX_WINDOW_BITS).contains(b))
+        };
+        match (name, value) {
+            ("server_no_context_takeover", None) => deflate.context_takeover = false,
+            ("server_max_window_bits", Some(value)) => deflate.max_window_bits = Some(window_bits(value)?),
+            ("client_no_context_takeover", None) | ("client_max_window_bits", None) => {}
+            ("client_max_window_bits", Some(value)) => {
+                window_bits(value)?;
+            }
+            _ => return None,
+        }
+    }
+    return Some(deflate);
+}
 
-    eprintln!("Sending dimensions");
-    send_dimensions(&mut stream);
-    eprintln!("Sent dimensions");
-    return stream;
+/// Checks an upgrade request (everything up to the empty line), picks one of the `protocols` the server speaks and
+/// accepts permessage-deflate if the client offered it.
+pub fn parse_upgrade(request: &str, protocols: &[&str]) -> Result<WsUpgrade, WsError> {
+    return check_upgrade(&parse_request(request)?, protocols);
 }
 
-pub fn send_ws_msg(stream: &mut TcpStream, cmd: &[u8], size: &[u8], data: &[u8]) -> Result<usize, std::io::Error> {
-    let mut response: Vec<u8> = Vec::new();
+/// Same, for a request that was already parsed.
+pub fn check_upgrade(request: &Request, protocols: &[&str]) -> Result<WsUpgrade, WsError> {
+    let refuse = |status, reason| WsError::Upgrade { status, reason };
+    if request.method != "GET" {
+        return Err(refuse(400, "upgrade requests are GETs"));
+    }
+    if request.version < (1, 1) {
+        return Err(refuse(400, "upgrades need HTTP/1.1 or later"));
+    }
+    let header = |name: &str| request.header(name);
+    let tokens = |name: &str| request.tokens(name);
 
-    let header: u8 = 0b10000010;
-    response.push(header);
+    if header("host").is_none() {
+        return Err(refuse(400, "missing Host"));
+    }
+    if !request.is_upgrade() {
+        return Err(refuse(426, "only upgrades to websocket are served"));
+    }
+    if !tokens("connection").iter().any(|t| t.eq_ignore_ascii_case("upgrade")) {
+        return Err(refuse(400, "Connection doesn't ask for an upgrade"));
+    }
+    if header("sec-websocket-version") != Some(WS_VERSION) {
+        return Err(refuse(426, "unsupported Sec-WebSocket-Version"));
+    }
+    let key = header("sec-websocket-key").ok_or(refuse(400, "missing Sec-WebSocket-Key"))?;
+    if general_purpose::STANDARD.decode(key).map(|k| k.len()) != Ok(16) {
+        return Err(refuse(400, "Sec-WebSocket-Key isn't 16 base64 bytes"));
+    }
+    // the path goes last, so a request that wasn't for a websocket at all gets 426 rather than 404
+    if request.path != WS_PATH {
+        return Err(refuse(404, "no websocket on this path"));
+    }
 
-    let content_length: u64 = (cmd.len() + size.len() + data.len()) as u64;
+    let protocol = tokens("sec-websocket-protocol")
+        .into_iter()
+        .find(|p| protocols.contains(p))
+        .map(String::from);
+    let deflate = tokens("sec-websocket-extensions")
+        .into_iter()
+        .find_map(parse_deflate_offer);
+    return Ok(WsUpgrade {
+        accept: ws_accept_key(key),
+        protocol,
+        deflate,
+    });
+}
 
-    // Server must send unmasked (mask=0) messages
-    if content_length < 126 {
-        response.push(content_length as u8);
-    } else if content_length < 1_048_576 {
-        response.push(126);
-        response.push((content_length >> 8) as u8);
-        response.push(content_length as u8);
-    } else if content_length < u64::MAX {
-        response.push(127);
-        todo!("I don't think that'll be needed")
-    } else {
-        todo!("Split message into several frames")
+/// HTTP error response with `reason` as the body, closing the connection.
+pub fn http_error_response(status: u16, reason: &str) -> String {
+    let status_text = status_text(status);
+    // 426 has to say what to upgrade to
+    let upgrade = match status {
+        426 => format!("Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: {WS_VERSION}\r\n"),
+        _ => String::from("Connection: close\r\n"),
+    };
+    return format!(
+        "HTTP/1.1 {status} {status_text}\r\n{upgrade}Content-Type: text/plain\r\nContent-Length: {}\r\n\r\n{reason}",
+        reason.len()
+    );
+}
+
+// CLIENT SIDE
+// The client sends a fresh random key and checks the server answered it with 101, the matching
+// Sec-WebSocket-Accept and only a subprotocol or extension it offered. It always offers permessage-deflate (the
+// reader inflates what the server compresses) but sends its own messages uncompressed, which RFC 7692 allows.
+
+/// Random Sec-WebSocket-Key.
+pub fn ws_client_key() -> String {
+    let mut key = random_u64().to_be_bytes().to_vec();
+    key.extend_from_slice(&random_u64().to_be_bytes());
+    return general_purpose::STANDARD.encode(key);
+}
+
+// Seeded by the OS for every RandomState, so keys and masks can't be guessed
+fn random_u64() -> u64 {
+    return RandomState::new().build_hasher().finish();
+}
+
+/// Upgrade request of a client for `host`, asking for `protocols`.
+pub fn upgrade_request(host: &str, key: &str, protocols: &[&str]) -> String {
+    let mut request = format!(
+        "GET {WS_PATH} HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+        Sec-WebSocket-Version: {WS_VERSION}\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Extensions: {WS_DEFLATE}\r\n"
+    );
+    if !protocols.is_empty() {
+        request.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocols.join(", ")));
     }
+    request.push_str("\r\n");
+    return request;
+}
 
-    cmd.iter().for_each(|u| {
-        response.push(*u);
+/// Checks the answer to an upgrade request sent with `key` and `protocols`, returning what the server picked.
+pub fn parse_upgrade_response(response: &str, key: &str, protocols: &[&str]) -> Result<WsUpgrade, WsError> {
+    let mut lines = response.split("\r\n");
+    let status_line: Vec<&str> = lines.next().unwrap_or_default().splitn(3, ' ').collect();
+    match status_line[..] {
+        [version, "101", ..] if version.starts_with("HTTP/") => {}
+        [version, status, ..] if version.starts_with("HTTP/") => {
+            return Err(WsError::Upgrade {
+                status: status
+                    .parse()
+                    .map_err(|_| WsError::Handshake("malformed status line"))?,
+                reason: "server refused the upgrade",
+            });
+        }
+        _ => return Err(WsError::Handshake("malformed status line")),
+    }
+
+    let mut headers = Vec::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':').ok_or(WsError::Handshake("malformed header"))?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim()));
+    }
+    let header = |name: &str| headers.iter().find(|(n, _)| n == name).map(|(_, v)| *v);
+    let has_token = |name: &str, token: &str| {
+        return header(name).is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)));
+    };
+
+    if !has_token("upgrade", "websocket") || !has_token("connection", "upgrade") {
+        return Err(WsError::Handshake("not an upgrade to websocket"));
+    }
+    let accept = ws_accept_key(key);
+    if header("sec-websocket-accept") != Some(accept.as_str()) {
+        return Err(WsError::Handshake("Sec-WebSocket-Accept doesn't match the key"));
+    }
+    let protocol = match header("sec-websocket-protocol") {
+        Some(protocol) if protocols.contains(&protocol) => Some(String::from(protocol)),
+        Some(_) => return Err(WsError::Handshake("subprotocol wasn't asked for")),
+        None => None,
+    };
+    let deflate = match header("sec-websocket-extensions") {
+        Some(extensions) => Some(parse_deflate_offer(extensions).ok_or(WsError::Handshake("unknown extension"))?),
+        None => None,
+    };
+    return Ok(WsUpgrade {
+        accept,
+        protocol,
+        deflate,
     });
-    size.iter().for_each(|u| {
-        response.push(*u);
+}
+
+/// Reads an HTTP request up to (and including) the empty line, and nothing past it.
+pub fn read_http_head(stream: &mut impl Read, max_size: usize) -> Result<String, WsError> {
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= max_size {
+            return Err(WsError::Upgrade {
+                status: 431,
+                reason: "request is too big",
+            });
+        }
+        match stream.read(&mut byte) {
+            Ok(0) => return Err(WsError::Io(io::Error::from(ErrorKind::UnexpectedEof))),
+            Ok(_) => head.push(byte[0]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(WsError::Upgrade {
+                    status: 408,
+                    reason: "request took too long",
+                });
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(WsError::Io(e)),
+        }
+    }
+    return String::from_utf8(head).map_err(|_| WsError::Upgrade {
+        status: 400,
+        reason: "request is not valid utf8",
     });
-    data.iter().for_each(|u| {
-        response.push(*u);
+}
+
+/// Reads the body of a request, as long as its Content-Length says. Requests without one have no body.
+pub fn read_http_body(stream: &mut impl Read, request: &Request, max_size: usize) -> Result<Vec<u8>, WsError> {
+    if request.header("transfer-encoding").is_some() {
+        return Err(WsError::Upgrade {
+            status: 501,
+            reason: "only bodies with a Content-Length are taken",
+        });
+    }
+    let Some(length) = request.header("content-length") else {
+        return Ok(Vec::new());
+    };
+    let length: usize = length.parse().map_err(|_| WsError::Upgrade {
+        status: 400,
+        reason: "malformed Content-Length",
+    })?;
+    if length > max_size {
+        return Err(WsError::Upgrade {
+            status: 413,
+            reason: "request body is too big",
+        });
+    }
+    let mut body = vec![0; length];
+    return match stream.read_exact(&mut body) {
+        Ok(()) => Ok(body),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Err(WsError::Upgrade {
+            status: 408,
+            reason: "request took too long",
+        }),
+        Err(e) => Err(WsError::Io(e)),
+    };
+}
+
+// Reads from a TCP stream until a deadline, however the reads are spread over time
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    until: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::from(ErrorKind::TimedOut));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        return self.stream.read(buf);
+    }
+}
+
+/// An upgraded connection, from either side.
+pub struct WsConnection {
+    pub stream: TcpStream,
+    // reads what the other side sends, and decompresses it with the state of the messages so far
+    pub reader: WsReader<TcpStream>,
+    // compresses what the server sends, if permessage-deflate was negotiated. Clients send uncompressed
+    pub deflater: Option<Deflater>,
+}
+
+/// Reads the request of a new connection to the WebSocket port, an upgrade or a plain page load. A request that
+/// can't be read (too big, too slow or malformed) is answered with an HTTP error.
+pub fn read_request(stream: &mut TcpStream) -> Result<Request, WsError> {
+    let mut deadline = Deadline {
+        stream,
+        until: Instant::now() + UPGRADE_TIMEOUT,
+    };
+    let head = read_http_head(&mut deadline, MAX_UPGRADE_SIZE);
+    eprintln!("Request: {head:#?}");
+    let request = head.and_then(|head| {
+        let mut request = parse_request(&head)?;
+        request.body = read_http_body(&mut deadline, &request, MAX_BODY_SIZE)?;
+        Ok(request)
     });
+    stream.set_read_timeout(None)?;
+    if let Err(e) = &request {
+        refuse_request(stream, e);
+    }
+    return request;
+}
 
-    stream.write_all(&response)?;
+// Answers with the HTTP error the request was refused with, if any, and closes the connection
+fn refuse_request(stream: &mut TcpStream, e: &WsError) {
+    if let WsError::Upgrade { status, reason } = e {
+        let _ = stream.write_all(http_error_response(*status, reason).as_bytes());
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+}
 
-    return Ok(response.len());
+/// Answers the upgrade request and reads the first message of the client, which is returned along the connection.
+pub fn handle_ws_connection(mut stream: TcpStream, request: &Request) -> Result<(WsConnection, Vec<u8>), WsError> {
+    let upgrade = match check_upgrade(request, &WS_SUBPROTOCOLS) {
+        Ok(upgrade) => upgrade,
+        Err(e) => {
+            refuse_request(&mut stream, &e);
+            return Err(e);
+        }
+    };
+    let response = upgrade.response();
+    eprintln!("Sending:\n{response}");
+
+    stream.write_all(response.as_bytes())?;
+    stream.flush()?;
+    eprintln!("Sent handshake response");
+
+    // Browsers send the first message right away, pings before it are answered
+    let mut reader = WsReader::server(stream.try_clone()?);
+    let mut deflater = None;
+    if let Some(deflate) = upgrade.deflate {
+        reader = reader.inflater(Inflater::new(true));
+        deflater = Some(deflate.deflater());
+    }
+    // a peer that upgrades and then says nothing would keep its thread forever
+    stream.set_read_timeout(Some(FIRST_MESSAGE_TIMEOUT))?;
+    let first_msg = loop {
+        let msg = match reader.read_message() {
+            Ok(msg) => msg,
+            Err(WsError::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                let _ = send_ws_close(&mut stream, Some((WS_CLOSE_POLICY_VIOLATION, "No message in time")));
+                return Err(WsError::Io(e));
+            }
+            Err(e) => {
+                if let Some(code) = e.close_code() {
+                    let _ = send_ws_close(&mut stream, Some((code, &e.to_string())));
+                }
+                return Err(e);
+            }
+        };
+        match msg {
+            WsMessage::Binary(data) => break data,
+            WsMessage::Text(text) => break text.into_bytes(),
+            WsMessage::Ping(payload) => {
+                send_ws_frame(&mut stream, WS_OPCODE_PONG, &payload)?;
+            }
+            WsMessage::Pong(_) => {}
+            WsMessage::Close(close) => {
+                send_ws_close(&mut stream, close.as_ref().map(|(code, _)| (*code, "")))?;
+                return Err(WsError::Io(io::Error::new(
+                    ErrorKind::ConnectionAborted,
+                    "Closed before the first message",
+                )));
+            }
+        }
+    };
+    stream.set_read_timeout(None)?;
+    eprintln!("data: {}", String::from_utf8_lossy(&first_msg));
+
+    eprintln!("Sending dimensions");
+    send_dimensions(&mut stream, deflater.as_mut());
+    eprintln!("Sent dimensions");
+    let connection = WsConnection {
+        stream,
+        reader,
+        deflater,
+    };
+    return Ok((connection, first_msg));
 }
 
-pub fn send_ws_msg_text(stream: &mut TcpStream, message: &str) -> Result<usize, std::io::Error> {
-    let mut response: Vec<u8> = Vec::new();
+/// Connects as a client: sends the upgrade request for `host` and checks the answer. The stream is left right
+/// before the first frame of the server.
+pub fn connect_ws(mut stream: TcpStream, host: &str) -> Result<WsConnection, WsError> {
+    let key = ws_client_key();
+    stream.write_all(upgrade_request(host, &key, &WS_SUBPROTOCOLS).as_bytes())?;
+    let response = read_http_head(
+        &mut Deadline {
+            stream: &stream,
+            until: Instant::now() + UPGRADE_TIMEOUT,
+        },
+        MAX_UPGRADE_SIZE,
+    )?;
+    stream.set_read_timeout(None)?;
+    let upgrade = parse_upgrade_response(&response, &key, &WS_SUBPROTOCOLS)?;
+    let mut reader = WsReader::client(stream.try_clone()?);
+    if upgrade.deflate.is_some() {
+        reader = reader.inflater(Inflater::new(true));
+    }
+    return Ok(WsConnection {
+        stream,
+        reader,
+        deflater: None,
+    });
+}
+
+/// Sends a single unmasked frame, as servers do.
+pub fn send_ws_frame(stream: &mut impl Write, opcode: u8, payload: &[u8]) -> Result<usize, std::io::Error> {
+    return write_ws_frame(stream, 0b10000000 | opcode, None, payload);
+}
+
+/// Sends a single frame masked with a random key, as clients do.
+pub fn send_ws_frame_masked(stream: &mut impl Write, opcode: u8, payload: &[u8]) -> Result<usize, std::io::Error> {
+    let mask = (random_u64() as u32).to_be_bytes();
+    return write_ws_frame(stream, 0b10000000 | opcode, Some(mask), payload);
+}
+
+// [fin + rsv + opcode][mask + length][mask key][payload]
+fn write_ws_frame(
+    stream: &mut impl Write,
+    header: u8,
+    mask: Option<[u8; 4]>,
+    payload: &[u8],
+) -> Result<usize, std::io::Error> {
+    let mut response: Vec<u8> = Vec::with_capacity(payload.len() + 14);
 
-    let header: u8 = 0b10000010;
     response.push(header);
 
-    let content_length: u64 = (CMD_HEADER_SIZE + SIZE_HEADER_SIZE + message.len()) as u64;
+    let content_length: u64 = payload.len() as u64;
+    let mask_bit = if mask.is_some() { 0b10000000 } else { 0 };
 
-    // Server must send unmasked (mask=0) messages
     if content_length < 126 {
-        response.push(content_length as u8);
-    } else if content_length < 1_048_576 {
-        response.push(126);
-        response.push((content_length >> 8) as u8);
-        response.push(content_length as u8);
-    } else if content_length < u64::MAX {
-        response.push(127);
-        todo!("I don't think that'll be needed")
+        response.push(mask_bit | content_length as u8);
+    } else if content_length < 65_536 {
+        response.push(mask_bit | 126);
+        response.extend_from_slice(&(content_length as u16).to_be_bytes());
     } else {
-        todo!("Split message into several frames")
+        response.push(mask_bit | 127);
+        response.extend_from_slice(&content_length.to_be_bytes());
+    }
+    match mask {
+        Some(mask) => {
+            response.extend_from_slice(&mask);
+            response.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        }
+        None => response.extend_from_slice(payload),
     }
 
-    response.push(CMD_LOG_MSG);
-    // TODO: probably it makes sense to error if len does not fit in 16 bits
-    [(message.len() >> 8) as u8, message.len() as u8].iter().for_each(|u| {
-        response.push(*u);
-    });
-
-    eprint!("\nSending: ");
-    message.chars().for_each(|c| {
-        eprint!("{c}");
-        response.push(c as u8);
-    });
-    eprint!("\n");
-
-    eprintln!("{:?}", response);
     stream.write_all(&response)?;
 
     return Ok(response.len());
 }
 
-const DIMENSIONS_MSG_LEN: u8 = 7;
-pub fn send_dimensions(stream: &mut TcpStream) {
-    let header: u8 = 0b10000010;
-
-    // Server must send unmasked (mask=0) messages, which leaves 7bits for
-    // size
-    let masked_and_content_length: u8 = DIMENSIONS_MSG_LEN;
-
-    // Sending two u16 (assuming 16bit dimensions are enough). That totals
-    // CMD + SIZE  + DATA
-    // u8  + 2*u8 + 2*u16 = [u8; 7]
-    let data: [u8; DIMENSIONS_MSG_LEN as usize] = [
-        CMD_GRID_DIMENSIONS,
-        0,
-        4,
-        (GRID_WIDTH >> 8) as u8,
-        (GRID_WIDTH & 0xFF) as u8,
-        (GRID_HEIGHT >> 8) as u8,
-        (GRID_HEIGHT & 0xFF) as u8,
-    ];
-
-    let msg: [u8; DIMENSIONS_MSG_LEN as usize + 2] = [
-        header,
-        masked_and_content_length,
-        data[0],
-        data[1],
-        data[2],
-        data[3],
-        data[4],
-        data[5],
-        data[6],
-    ];
-
-    stream.write_all(&msg).expect("Data to be sent");
+/// Sends a whole message ([cmd][size][content]) as a single binary frame.
+pub fn send_ws_msg(stream: &mut TcpStream, msg: &[u8]) -> Result<usize, std::io::Error> {
+    return send_ws_frame(stream, WS_OPCODE_BINARY, msg);
 }
 
-pub fn write_data_to_stream(stream: &mut TcpStream, data: &[u8]) -> Result<usize, std::io::Error> {
-    return match stream.write(&data) {
-        Ok(n) if n == 0 => panic!("write call returned 0"),
-        Ok(n) if n < data.len() => todo!("write call was unable to write all bytes"),
-        Ok(n) if n == data.len() => Ok(n),
-        Err(e) => Err(e),
-        _ => unreachable!("Neither an error nor an expected num of bytes were returned in the write call"),
+/// Same, compressed with `deflater` if permessage-deflate was negotiated.
+pub fn send_ws_msg_deflated(
+    stream: &mut impl Write,
+    deflater: Option<&mut Deflater>,
+    msg: &[u8],
+) -> Result<usize, std::io::Error> {
+    let Some(deflater) = deflater else {
+        return send_ws_frame(stream, WS_OPCODE_BINARY, msg);
+    };
+    let mut compressed = deflater.compress(msg);
+    compressed.truncate(compressed.len() - SYNC_FLUSH_TAIL.len());
+    return write_ws_frame(stream, 0b10000000 | WS_RSV1 | WS_OPCODE_BINARY, None, &compressed);
+}
+
+/// Sends a close frame with a status code and reason, or an empty one. The reason is cut to fit in a control frame.
+pub fn send_ws_close(stream: &mut impl Write, close: Option<(u16, &str)>) -> Result<usize, std::io::Error> {
+    return send_ws_frame(stream, WS_OPCODE_CLOSE, &close_payload(close));
+}
+
+/// Payload of a close frame, see `send_ws_close`.
+pub fn close_payload(close: Option<(u16, &str)>) -> Vec<u8> {
+    let mut payload = Vec::new();
+    if let Some((code, reason)) = close {
+        payload.extend_from_slice(&code.to_be_bytes());
+        let mut end = reason.len().min(MAX_WS_CONTROL_SIZE - payload.len());
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+    }
+    return payload;
+}
+
+pub fn send_ws_msg_text(stream: &mut TcpStream, message: &str) -> Result<usize, std::io::Error> {
+    eprintln!("\nSending: {message}");
+    return send_ws_msg(stream, &Message::Log(message.to_string()).encode());
+}
+
+pub fn send_dimensions(stream: &mut TcpStream, deflater: Option<&mut Deflater>) {
+    // assuming 16bit dimensions are enough
+    let dimensions = Message::Dimensions {
+        width: GRID_WIDTH as u16,
+        height: GRID_HEIGHT as u16,
     };
+    send_ws_msg_deflated(stream, deflater, &dimensions.encode()).expect("Data to be sent");
+}
+
+pub fn write_data_to_stream(stream: &mut TcpStream, data: &[u8]) -> Result<usize, std::io::Error> {
+    // Big messages don't fit in the socket buffer in a single write call
+    stream.write_all(data)?;
+    return Ok(data.len());
 }
@@ -0,0 +1,447 @@
+// A pattern is a set of live cells with coordinates relative to wherever it is going to be placed. Cells are kept
+// sorted and without duplicates so two patterns with the same cells compare equal.
+//
+// Boards are the same one-byte-per-cell slices used for GRID, and coordinates wrap around the board edges like the
+// game does.
+//
+// Patterns can be read from and written to the RLE format used by most Life software
+// (https://conwaylife.com/wiki/Run_Length_Encoded):
+//
+//   #C glider
+//   x = 3, y = 3, rule = B3/S23
+//   bo$2bo$3o!
+
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Pattern {
+    cells: Vec<(isize, isize)>,
+}
+
+impl Pattern {
+    pub fn new(cells: impl IntoIterator<Item = (isize, isize)>) -> Pattern {
+        let mut cells: Vec<(isize, isize)> = cells.into_iter().collect();
+        cells.sort_by_key(|&(x, y)| (y, x));
+        cells.dedup();
+        return Pattern { cells };
+    }
+
+    /// Builds a pattern from rows of text where `o` (or `O`, `*`, `1`) is a live cell and anything else is dead.
+    pub fn from_rows(rows: &[&str]) -> Pattern {
+        return Pattern::new(rows.iter().enumerate().flat_map(|(y, row)| {
+            row.chars()
+                .enumerate()
+                .filter(|(_, c)| matches!(c, 'o' | 'O' | '*' | '1'))
+                .map(move |(x, _)| (x as isize, y as isize))
+        }));
+    }
+
+    /// Every live cell of a `width * height` board.
+    pub fn from_board(board: &[u8], width: usize, height: usize) -> Pattern {
+        return Pattern::new(
+            (0..(width * height))
+                .filter(|&i| board[i] == 1)
+                .map(|i| ((i % width) as isize, (i / width) as isize)),
+        );
+    }
+
+    /// Live cells of the `w * h` rectangle starting at (x, y), relative to the rectangle's top-left corner.
+    pub fn from_region(board: &[u8], width: usize, height: usize, x: usize, y: usize, w: usize, h: usize) -> Pattern {
+        let mut cells = Vec::new();
+        for ry in y..(y + h).min(height) {
+            for rx in x..(x + w).min(width) {
+                if board[rx + width * ry] == 1 {
+                    cells.push(((rx - x) as isize, (ry - y) as isize));
+                }
+            }
+        }
+        return Pattern::new(cells);
+    }
+
+    pub fn glider() -> Pattern {
+        return Pattern::from_rows(&[".o.", "..o", "ooo"]);
+    }
+
+    pub fn stick() -> Pattern {
+        return Pattern::from_rows(&["ooo"]);
+    }
+
+    pub fn cells(&self) -> &[(isize, isize)] {
+        return &self.cells;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.cells.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.cells.is_empty();
+    }
+
+    /// (min_x, min_y, max_x, max_y) of the live cells, None for an empty pattern.
+    pub fn bounding_box(&self) -> Option<(isize, isize, isize, isize)> {
+        let first = self.cells.first()?;
+        return Some(self.cells.iter().fold(
+            (first.0, first.1, first.0, first.1),
+            |(min_x, min_y, max_x, max_y), &(x, y)| (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y)),
+        ));
+    }
+
+    pub fn width(&self) -> usize {
+        return self
+            .bounding_box()
+            .map_or(0, |(min_x, _, max_x, _)| (max_x - min_x + 1) as usize);
+    }
+
+    pub fn height(&self) -> usize {
+        return self
+            .bounding_box()
+            .map_or(0, |(_, min_y, _, max_y)| (max_y - min_y + 1) as usize);
+    }
+
+    pub fn translate(&self, dx: isize, dy: isize) -> Pattern {
+        return Pattern::new(self.cells.iter().map(|&(x, y)| (x + dx, y + dy)));
+    }
+
+    /// Moves the pattern so its bounding box starts at (0, 0).
+    pub fn crop(&self) -> Pattern {
+        return match self.bounding_box() {
+            Some((min_x, min_y, _, _)) => self.translate(-min_x, -min_y),
+            None => Pattern::default(),
+        };
+    }
+
+    // Transformations keep the top-left corner of the bounding box where it was, so a pattern can be rotated
+    // in place.
+    fn transform(&self, f: impl Fn(isize, isize, isize, isize) -> (isize, isize)) -> Pattern {
+        let Some((min_x, min_y, max_x, max_y)) = self.bounding_box() else {
+            return Pattern::default();
+        };
+        let (w, h) = (max_x - min_x + 1, max_y - min_y + 1);
+        return Pattern::new(self.cells.iter().map(|&(x, y)| {
+            let (nx, ny) = f(x - min_x, y - min_y, w, h);
+            (nx + min_x, ny + min_y)
+        }));
+    }
+
+    /// Rotates 90 degrees clockwise.
+    pub fn rotate_90(&self) -> Pattern {
+        return self.transform(|x, y, _, h| (h - 1 - y, x));
+    }
+
+    pub fn rotate_180(&self) -> Pattern {
+        return self.transform(|x, y, w, h| (w - 1 - x, h - 1 - y));
+    }
+
+    /// Rotates 270 degrees clockwise (90 counterclockwise).
+    pub fn rotate_270(&self) -> Pattern {
+        return self.transform(|x, y, w, _| (y, w - 1 - x));
+    }
+
+    /// Mirrors left to right.
+    pub fn flip_horizontal(&self) -> Pattern {
+        return self.transform(|x, y, w, _| (w - 1 - x, y));
+    }
+
+    /// Mirrors top to bottom.
+    pub fn flip_vertical(&self) -> Pattern {
+        return self.transform(|x, y, _, h| (x, h - 1 - y));
+    }
+
+    /// The 8 ways the pattern can be placed: 4 rotations of the pattern and 4 of its mirror image.
+    pub fn orientations(&self) -> [Pattern; 8] {
+        let flipped = self.flip_horizontal();
+        return [
+            self.clone(),
+            self.rotate_90(),
+            self.rotate_180(),
+            self.rotate_270(),
+            flipped.rotate_90(),
+            flipped.rotate_180(),
+            flipped.rotate_270(),
+            flipped,
+        ];
+    }
+
+    fn board_index(x: isize, y: isize, width: usize, height: usize) -> usize {
+        return x.rem_euclid(width as isize) as usize + width * y.rem_euclid(height as isize) as usize;
+    }
+
+    /// Sets every cell of the pattern alive on the board.
+    pub fn union(&self, board: &mut [u8], width: usize, height: usize) {
+        for &(x, y) in &self.cells {
+            board[Pattern::board_index(x, y, width, height)] = 1;
+        }
+    }
+
+    /// Keeps alive only the board cells that are also alive in the pattern.
+    pub fn intersection(&self, board: &mut [u8], width: usize, height: usize) {
+        let mut mask = vec![0; width * height];
+        self.union(&mut mask, width, height);
+        board.iter_mut().zip(mask).for_each(|(cell, m)| *cell &= m);
+    }
+
+    /// Toggles every cell of the pattern on the board.
+    pub fn xor(&self, board: &mut [u8], width: usize, height: usize) {
+        for &(x, y) in &self.cells {
+            board[Pattern::board_index(x, y, width, height)] ^= 1;
+        }
+    }
+}
+
+// RLE lines should not go over 70 characters
+const RLE_LINE_WIDTH: usize = 70;
+
+impl Pattern {
+    /// Parses an RLE pattern. The rule in the header is ignored, the game only knows B3/S23.
+    pub fn from_rle(rle: &str) -> Result<Pattern> {
+        return Pattern::parse_rle(rle, None);
+    }
+
+    /// Parses an RLE pattern that has to fit a `width * height` board. It stops at the first run that goes past the
+    /// board, so an input of a few bytes can't have it push cells by the billion before being refused.
+    pub fn from_rle_within(rle: &str, width: usize, height: usize) -> Result<Pattern> {
+        return Pattern::parse_rle(rle, Some((width as isize, height as isize)));
+    }
+
+    fn parse_rle(rle: &str, bounds: Option<(isize, isize)>) -> Result<Pattern> {
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+        let too_big = || invalid(String::from("Pattern is bigger than the board"));
+        let (width, height) = bounds.unwrap_or((isize::MAX, isize::MAX));
+        let mut cells = Vec::new();
+        let (mut x, mut y): (isize, isize) = (0, 0);
+        let mut count: Option<isize> = None;
+        let mut header_seen = false;
+
+        'lines: for line in rle.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if !header_seen && line.starts_with('x') {
+                header_seen = true;
+                continue;
+            }
+            for c in line.chars() {
+                match c {
+                    '0'..='9' => {
+                        let digit = c as isize - '0' as isize;
+                        count = count
+                            .unwrap_or(0)
+                            .checked_mul(10)
+                            .and_then(|count| count.checked_add(digit));
+                        if count.is_none() {
+                            return Err(invalid(String::from("Run count too big in RLE pattern")));
+                        }
+                        continue;
+                    }
+                    'b' | '.' => {
+                        x = x.saturating_add(count.unwrap_or(1));
+                        if x > width {
+                            return Err(too_big());
+                        }
+                    }
+                    'o' | 'A'..='Z' => {
+                        let run = count.unwrap_or(1);
+                        if y >= height || run > width - x {
+                            return Err(too_big());
+                        }
+                        for _ in 0..run {
+                            cells.push((x, y));
+                            x += 1;
+                        }
+                    }
+                    '$' => {
+                        y = y.saturating_add(count.unwrap_or(1));
+                        x = 0;
+                    }
+                    '!' => break 'lines,
+                    c if c.is_whitespace() => {}
+                    c => return Err(invalid(format!("Unexpected character '{c}' in RLE pattern"))),
+                }
+                count = None;
+            }
+        }
+        return Ok(Pattern::new(cells));
+    }
+
+    pub fn to_rle(&self) -> String {
+        let pattern = self.crop();
+        return pattern.write_rle(pattern.width(), pattern.height());
+    }
+
+    /// RLE of a whole `width * height` board, cells staying where they are instead of being cropped.
+    pub fn to_board_rle(&self, width: usize, height: usize) -> String {
+        let board = Pattern::new(
+            self.cells
+                .iter()
+                .map(|&(x, y)| (x.rem_euclid(width as isize), y.rem_euclid(height as isize))),
+        );
+        return board.write_rle(width, height);
+    }
+
+    // Cells are written from (0, 0), so they can't be negative
+    fn write_rle(&self, width: usize, height: usize) -> String {
+        let mut body = String::new();
+        let push_run = |body: &mut String, count: usize, tag: char| {
+            if count > 1 {
+                body.push_str(&count.to_string());
+            }
+            body.push(tag);
+        };
+
+        let (mut x, mut y) = (0, 0);
+        let mut alive_run = 0;
+        for &(cx, cy) in self.cells() {
+            if cy != y || cx != x {
+                if alive_run > 0 {
+                    push_run(&mut body, alive_run, 'o');
+                    alive_run = 0;
+                }
+                if cy != y {
+                    push_run(&mut body, (cy - y) as usize, '$');
+                    y = cy;
+                    x = 0;
+                }
+                if cx != x {
+                    push_run(&mut body, (cx - x) as usize, 'b');
+                }
+            }
+            alive_run += 1;
+            x = cx + 1;
+        }
+        if alive_run > 0 {
+            push_run(&mut body, alive_run, 'o');
+        }
+        body.push('!');
+
+        let mut rle = format!("x = {width}, y = {height}, rule = B3/S23\n");
+        let mut line_len = 0;
+        let mut token = String::new();
+        for c in body.chars() {
+            token.push(c);
+            if c.is_ascii_digit() {
+                continue;
+            }
+            if line_len + token.len() > RLE_LINE_WIDTH {
+                rle.push('\n');
+                line_len = 0;
+            }
+            line_len += token.len();
+            rle.push_str(&token);
+            token.clear();
+        }
+        rle.push('\n');
+        return rle;
+    }
+
+    pub fn load_rle(path: &str) -> Result<Pattern> {
+        return Pattern::from_rle(&fs::read_to_string(path)?);
+    }
+
+    pub fn save_rle(&self, path: &str) -> Result<()> {
+        return fs::write(path, self.to_rle());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pattern::Pattern;
+
+    #[test]
+    fn test_rotations() {
+        let glider = Pattern::glider();
+
+        assert_eq!(glider.rotate_90(), Pattern::from_rows(&["o.", "o.o", "oo."]));
+        assert_eq!(glider.rotate_90().rotate_90(), glider.rotate_180());
+        assert_eq!(glider.rotate_90().rotate_180(), glider.rotate_270());
+        assert_eq!(glider.rotate_270().rotate_90(), glider);
+        assert_eq!(glider.flip_horizontal(), Pattern::from_rows(&[".o.", "o..", "ooo"]));
+        assert_eq!(glider.flip_vertical(), Pattern::from_rows(&["ooo", "..o", ".o."]));
+        assert_eq!(glider.flip_horizontal().flip_vertical(), glider.rotate_180());
+    }
+
+    #[test]
+    fn test_transformations_keep_position() {
+        let stick = Pattern::stick().translate(5, 7);
+
+        assert_eq!(stick.rotate_90(), Pattern::new([(5, 7), (5, 8), (5, 9)]));
+        assert_eq!(stick.crop(), Pattern::stick());
+        assert_eq!(stick.bounding_box(), Some((5, 7, 7, 7)));
+        assert_eq!((stick.width(), stick.height()), (3, 1));
+    }
+
+    #[test]
+    fn test_board_operations() {
+        let mut board = vec![0; 4 * 3];
+        // wraps around the right edge
+        Pattern::stick().translate(2, 1).union(&mut board, 4, 3);
+        assert_eq!(board, [0, 0, 0, 0, 1, 0, 1, 1, 0, 0, 0, 0]);
+
+        Pattern::new([(0, 1), (1, 1)]).xor(&mut board, 4, 3);
+        assert_eq!(board, [0, 0, 0, 0, 0, 1, 1, 1, 0, 0, 0, 0]);
+
+        Pattern::new([(1, 1), (2, 2)]).intersection(&mut board, 4, 3);
+        assert_eq!(board, [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(Pattern::from_board(&board, 4, 3), Pattern::new([(1, 1)]));
+    }
+
+    #[test]
+    fn test_rle() {
+        let glider = Pattern::from_rle("#C glider\nx = 3, y = 3, rule = B3/S23\nbo$2bo$3o!\n").unwrap();
+        assert_eq!(glider, Pattern::glider());
+        assert_eq!(glider.to_rle(), "x = 3, y = 3, rule = B3/S23\nbo$2bo$3o!\n");
+
+        let gaps = Pattern::new([(0, 0), (4, 0), (1, 3)]);
+        assert_eq!(gaps.to_rle(), "x = 5, y = 4, rule = B3/S23\no3bo3$bo!\n");
+        assert_eq!(Pattern::from_rle(&gaps.to_rle()).unwrap(), gaps);
+
+        let long_line = Pattern::new((0..100).map(|x| (x * 2, 0)));
+        assert!(long_line.to_rle().lines().all(|line| line.len() <= 70));
+        assert_eq!(Pattern::from_rle(&long_line.to_rle()).unwrap(), long_line);
+
+        // the whole board, with the empty rows and columns before the cells
+        let moved = Pattern::glider().translate(2, 1);
+        assert_eq!(
+            moved.to_board_rle(6, 5),
+            "x = 6, y = 5, rule = B3/S23\n$3bo$4bo$2b3o!\n"
+        );
+        assert_eq!(Pattern::from_rle(&moved.to_board_rle(6, 5)).unwrap(), moved);
+        assert_eq!(
+            Pattern::glider().translate(-1, 0).to_board_rle(6, 5),
+            "x = 6, y = 5, rule = B3/S23\no$bo$2o3bo!\n"
+        );
+        assert_eq!(
+            Pattern::default().to_board_rle(6, 5),
+            "x = 6, y = 5, rule = B3/S23\n!\n"
+        );
+
+        assert!(Pattern::from_rle("x = 1, y = 1\nq!").is_err());
+        assert!(Pattern::from_rle("99999999999999999999o!").is_err());
+
+        // within a board, the parser stops at the first run past its edges
+        assert_eq!(Pattern::from_rle_within("$3bo$4bo$2b3o!", 6, 5).unwrap(), moved);
+        assert_eq!(Pattern::from_rle_within("6o$o!", 6, 5).unwrap().len(), 7);
+        for rle in ["7o!", "4b3o!", "7bo!", "5$o!", "o$5o2o!"] {
+            assert!(Pattern::from_rle_within(rle, 6, 5).is_err(), "{rle}");
+        }
+        let flood = "4o$".repeat(10_000) + "!";
+        assert!(Pattern::from_rle_within(&flood, 6, 5).is_err());
+    }
+
+    #[test]
+    fn test_from_region() {
+        let board = [0, 1, 0, 0, 1, 1, 0, 0, 1];
+
+        assert_eq!(
+            Pattern::from_region(&board, 3, 3, 1, 1, 2, 2),
+            Pattern::new([(0, 0), (1, 0), (1, 1)])
+        );
+        // clamped to the board
+        assert_eq!(
+            Pattern::from_region(&board, 3, 3, 2, 0, 5, 5),
+            Pattern::new([(0, 1), (0, 2)])
+        );
+    }
+}
@@ -0,0 +1,813 @@
+use std::fmt;
+use std::io::{self, Read};
+
+use crate::crc32::crc32;
+use crate::net::{
+    choose_encoding, message, read_varint, write_varint, Encoding, CMD_DELTA_GRID, CMD_EDIT_CELLS, CMD_ENCODING,
+    CMD_ERROR, CMD_GRID_DIMENSIONS, CMD_HEADER_SIZE, CMD_HELLO, CMD_KEYFRAME_REQUEST, CMD_LOG_MSG, CMD_NEW_GRID,
+    CMD_STAMP, CMD_VERSION, EXTENDED_SIZE, EXTENDED_SIZE_HEADER_SIZE, MAX_CONTENT_SIZE, SIZE_HEADER_SIZE,
+};
+
+// PROTOCOL VERSIONS
+//   - 1: grids in the server encoding without an encoding id, no hello. Clients that say nothing
+//   - 2: hello, tagged grids and delta grids. Clients that send a hello without a version
+//   - 3: version and capabilities before the hello, errors
+//   - 4: generation and server timestamp in grid messages, keyframe requests
+//   - 5: cell edits and stamps from clients
+//   - 6: delta grid cells as varints, so boards over 65536 cells can get them
+//
+// A client that announces a newer version gets PROTOCOL_VERSION back and has to speak that.
+pub const PROTOCOL_VERSION: u8 = 6;
+pub const LEGACY_PROTOCOL_VERSION: u8 = 1;
+pub const HELLO_PROTOCOL_VERSION: u8 = 2;
+pub const HANDSHAKE_PROTOCOL_VERSION: u8 = 3;
+pub const GENERATION_PROTOCOL_VERSION: u8 = 4;
+pub const EDIT_PROTOCOL_VERSION: u8 = 5;
+pub const VARINT_DELTA_PROTOCOL_VERSION: u8 = 6;
+
+// Capability flags, sent along the version. The server answers with the ones both sides have
+// client applies delta grids, otherwise it only gets full grids
+pub const CAP_DELTA_GRID: u8 = 1 << 0;
+// every message the server sends after the version reply ends with the CRC-32 (u32 BE) of the rest of its content,
+// see Message::encode_checked
+pub const CAP_CRC32: u8 = 1 << 1;
+pub const SERVER_CAPABILITIES: u8 = CAP_DELTA_GRID | CAP_CRC32;
+
+pub const CRC_SIZE: usize = 4;
+
+// Error codes
+pub const ERR_UNSUPPORTED_VERSION: u8 = 1;
+pub const ERR_NO_COMMON_ENCODING: u8 = 2;
+pub const ERR_BAD_HANDSHAKE: u8 = 3;
+
+// Actions of a cell edit
+pub const EDIT_SET: u8 = 0;
+pub const EDIT_CLEAR: u8 = 1;
+pub const EDIT_TOGGLE: u8 = 2;
+// Biggest RLE a stamp can carry, like the body of an HTTP API call. A pattern that fits the board takes a few KiB
+pub const MAX_STAMP_SIZE: usize = 64 * 1024;
+
+// Every message the server and the clients exchange, framed as [cmd][size][content] (see the net module). The
+// web client parses the same bytes in public/main.ts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    // Grids go first with their generation and the server time (ms since the epoch) as varints, see
+    // GENERATION_PROTOCOL_VERSION. Both are 0 when decoding older versions
+    //
+    // grid encoded with `encoding`, whose id goes after the generation and timestamp
+    NewGrid {
+        generation: u64,
+        timestamp: u64,
+        encoding: Encoding,
+        cgrid: Vec<u8>,
+    },
+    Log(String),
+    Dimensions {
+        width: u16,
+        height: u16,
+    },
+    // index of every cell that changed since the previous generation. Each is a varint of its distance from the one
+    // before (from 0 for the first, wrapping around u32), which is small as the server lists them in order. Before
+    // VARINT_DELTA_PROTOCOL_VERSION they are u16 (BE), which only boards up to 65536 cells can be sent with
+    DeltaGrid {
+        generation: u64,
+        timestamp: u64,
+        cells: Vec<u32>,
+    },
+    // ids of the encodings a client can decode, preferred first. Unknown ids are kept, the server skips them
+    Hello(Vec<u8>),
+    Encoding(Encoding),
+    Version {
+        version: u8,
+        capabilities: u8,
+    },
+    // sent right before the server closes the connection
+    Error {
+        code: u8,
+        reason: String,
+    },
+    // client missed a generation and needs a full grid before the next delta grid makes sense
+    KeyframeRequest,
+    // The server applies edits between two generations, like the ones of the HTTP API
+    //
+    // cells (x, y) to set alive, kill or toggle, see EDIT_*
+    EditCells {
+        action: u8,
+        cells: Vec<(u16, u16)>,
+    },
+    // RLE pattern to place with its top-left corner at (x, y)
+    Stamp {
+        x: u16,
+        y: u16,
+        rle: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(io::Error),
+    // the message is shorter than its header says
+    Truncated,
+    TooLarge(usize),
+    UnknownCommand(u8),
+    UnknownEncoding(u8),
+    InvalidContent { cmd: u8, reason: &'static str },
+    // the content doesn't match its CRC-32, so it got corrupted on the way
+    ChecksumMismatch { cmd: u8 },
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            ProtocolError::Io(e) => write!(f, "{e}"),
+            ProtocolError::Truncated => write!(f, "Message is shorter than its header says"),
+            ProtocolError::TooLarge(size) => {
+                write!(f, "Message of {size} B is bigger than the {MAX_CONTENT_SIZE} B limit")
+            }
+            ProtocolError::UnknownCommand(cmd) => write!(f, "Unknown command {cmd}"),
+            ProtocolError::UnknownEncoding(id) => write!(f, "Unknown grid encoding {id}"),
+            ProtocolError::InvalidContent { cmd, reason } => write!(f, "Invalid content for command {cmd}: {reason}"),
+            ProtocolError::ChecksumMismatch { cmd } => write!(f, "Content of command {cmd} doesn't match its CRC-32"),
+        };
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        return match e.kind() {
+            io::ErrorKind::UnexpectedEof => ProtocolError::Truncated,
+            _ => ProtocolError::Io(e),
+        };
+    }
+}
+
+impl From<ProtocolError> for io::Error {
+    fn from(e: ProtocolError) -> Self {
+        return match e {
+            ProtocolError::Io(e) => e,
+            ProtocolError::Truncated => io::Error::new(io::ErrorKind::UnexpectedEof, e.to_string()),
+            _ => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        };
+    }
+}
+
+/// Command and content of a whole message, without parsing the content.
+pub fn split_message(msg: &[u8]) -> Result<(u8, &[u8]), ProtocolError> {
+    let (&cmd, rest) = msg.split_first().ok_or(ProtocolError::Truncated)?;
+    let size = rest.get(..SIZE_HEADER_SIZE).ok_or(ProtocolError::Truncated)?;
+    let mut size = u16::from_be_bytes([size[0], size[1]]) as usize;
+    let mut start = CMD_HEADER_SIZE + SIZE_HEADER_SIZE;
+    if size == EXTENDED_SIZE as usize {
+        let extended = msg
+            .get(start..(start + EXTENDED_SIZE_HEADER_SIZE))
+            .ok_or(ProtocolError::Truncated)?;
+        size = u32::from_be_bytes(extended.try_into().unwrap()) as usize;
+        start += EXTENDED_SIZE_HEADER_SIZE;
+    }
+    let content = msg
+        .get(start..)
+        .and_then(|c| c.get(..size))
+        .ok_or(ProtocolError::Truncated)?;
+    return Ok((cmd, content));
+}
+
+// Command and content of the next message on the stream
+fn read_content(stream: &mut impl Read) -> Result<(u8, Vec<u8>), ProtocolError> {
+    let mut header = [0; CMD_HEADER_SIZE + SIZE_HEADER_SIZE];
+    stream.read_exact(&mut header)?;
+    let mut size = u16::from_be_bytes([header[1], header[2]]) as usize;
+    if size == EXTENDED_SIZE as usize {
+        let mut extended = [0; EXTENDED_SIZE_HEADER_SIZE];
+        stream.read_exact(&mut extended)?;
+        size = u32::from_be_bytes(extended) as usize;
+    }
+    if size > MAX_CONTENT_SIZE {
+        return Err(ProtocolError::TooLarge(size));
+    }
+    let mut content = vec![0; size];
+    stream.read_exact(&mut content)?;
+    return Ok((header[0], content));
+}
+
+// Content without its trailing CRC-32, if it matches
+fn verify_crc(cmd: u8, content: &[u8]) -> Result<&[u8], ProtocolError> {
+    if content.len() < CRC_SIZE {
+        return Err(ProtocolError::InvalidContent {
+            cmd,
+            reason: "missing CRC-32",
+        });
+    }
+    let (content, crc) = content.split_at(content.len() - CRC_SIZE);
+    if crc32(content).to_be_bytes() != crc {
+        return Err(ProtocolError::ChecksumMismatch { cmd });
+    }
+    return Ok(content);
+}
+
+impl Message {
+    pub fn cmd(&self) -> u8 {
+        return match self {
+            Message::NewGrid { .. } => CMD_NEW_GRID,
+            Message::Log(_) => CMD_LOG_MSG,
+            Message::Dimensions { .. } => CMD_GRID_DIMENSIONS,
+            Message::DeltaGrid { .. } => CMD_DELTA_GRID,
+            Message::Hello(_) => CMD_HELLO,
+            Message::Encoding(_) => CMD_ENCODING,
+            Message::Version { .. } => CMD_VERSION,
+            Message::Error { .. } => CMD_ERROR,
+            Message::KeyframeRequest => CMD_KEYFRAME_REQUEST,
+            Message::EditCells { .. } => CMD_EDIT_CELLS,
+            Message::Stamp { .. } => CMD_STAMP,
+        };
+    }
+
+    pub fn content(&self) -> Vec<u8> {
+        return self.content_for(PROTOCOL_VERSION);
+    }
+
+    /// Content as clients of an older protocol `version` expect it.
+    pub fn content_for(&self, version: u8) -> Vec<u8> {
+        let frame = |generation: u64, timestamp: u64| {
+            let mut content = Vec::new();
+            if version >= GENERATION_PROTOCOL_VERSION {
+                write_varint(&mut content, generation);
+                write_varint(&mut content, timestamp);
+            }
+            return content;
+        };
+        return match self {
+            Message::NewGrid {
+                generation,
+                timestamp,
+                encoding,
+                cgrid,
+            } => {
+                let mut content = frame(*generation, *timestamp);
+                content.push(encoding.id());
+                content.extend_from_slice(cgrid);
+                content
+            }
+            Message::Log(log) => log.as_bytes().to_vec(),
+            Message::Dimensions { width, height } => [width.to_be_bytes(), height.to_be_bytes()].concat(),
+            Message::DeltaGrid {
+                generation,
+                timestamp,
+                cells,
+            } => {
+                let mut content = frame(*generation, *timestamp);
+                let mut prev = 0_u32;
+                for cell in cells {
+                    if version >= VARINT_DELTA_PROTOCOL_VERSION {
+                        write_varint(&mut content, cell.wrapping_sub(prev) as u64);
+                        prev = *cell;
+                    } else {
+                        content.extend((*cell as u16).to_be_bytes());
+                    }
+                }
+                content
+            }
+            Message::Hello(ids) => ids.clone(),
+            Message::Encoding(encoding) => vec![encoding.id()],
+            Message::Version { version, capabilities } => vec![*version, *capabilities],
+            Message::Error { code, reason } => [&[*code], reason.as_bytes()].concat(),
+            Message::KeyframeRequest => Vec::new(),
+            Message::EditCells { action, cells } => {
+                let mut content = vec![*action];
+                content.extend(
+                    cells
+                        .iter()
+                        .flat_map(|(x, y)| [x.to_be_bytes(), y.to_be_bytes()].concat()),
+                );
+                content
+            }
+            Message::Stamp { x, y, rle } => [&x.to_be_bytes()[..], &y.to_be_bytes(), rle.as_bytes()].concat(),
+        };
+    }
+
+    /// Whole message, header included.
+    pub fn encode(&self) -> Vec<u8> {
+        return message(self.cmd(), &self.content());
+    }
+
+    pub fn encode_for(&self, version: u8) -> Vec<u8> {
+        return message(self.cmd(), &self.content_for(version));
+    }
+
+    /// Whole message for clients that negotiated CAP_CRC32: the content is followed by its CRC-32, which the size
+    /// header counts.
+    pub fn encode_checked(&self, version: u8) -> Vec<u8> {
+        let mut content = self.content_for(version);
+        content.extend_from_slice(&crc32(&content).to_be_bytes());
+        return message(self.cmd(), &content);
+    }
+
+    /// Parses a whole message, header included. Bytes after the content are ignored.
+    pub fn decode(msg: &[u8]) -> Result<Message, ProtocolError> {
+        return Message::decode_for(msg, PROTOCOL_VERSION);
+    }
+
+    /// Parses a message written for protocol `version`, e.g. in an older recording.
+    pub fn decode_for(msg: &[u8], version: u8) -> Result<Message, ProtocolError> {
+        let (cmd, content) = split_message(msg)?;
+        return Message::from_content_for(cmd, content, version);
+    }
+
+    /// Reads the next message from a client or server stream.
+    pub fn read(stream: &mut impl Read) -> Result<Message, ProtocolError> {
+        return Message::read_for(stream, PROTOCOL_VERSION);
+    }
+
+    pub fn read_for(stream: &mut impl Read, version: u8) -> Result<Message, ProtocolError> {
+        let (cmd, content) = read_content(stream)?;
+        return Message::from_content_for(cmd, &content, version);
+    }
+
+    /// Reads a message written with encode_checked. A ChecksumMismatch still consumes the whole message, so the
+    /// stream can be read on.
+    pub fn read_checked(stream: &mut impl Read, version: u8) -> Result<Message, ProtocolError> {
+        let (cmd, content) = read_content(stream)?;
+        return Message::from_content_for(cmd, verify_crc(cmd, &content)?, version);
+    }
+
+    pub fn decode_checked(msg: &[u8], version: u8) -> Result<Message, ProtocolError> {
+        let (cmd, content) = split_message(msg)?;
+        return Message::from_content_for(cmd, verify_crc(cmd, content)?, version);
+    }
+
+    pub fn from_content(cmd: u8, content: &[u8]) -> Result<Message, ProtocolError> {
+        return Message::from_content_for(cmd, content, PROTOCOL_VERSION);
+    }
+
+    pub fn from_content_for(cmd: u8, content: &[u8], version: u8) -> Result<Message, ProtocolError> {
+        let invalid = |reason| ProtocolError::InvalidContent { cmd, reason };
+        // generation and timestamp of grid messages, and where the grid itself starts
+        let frame = || -> Result<(u64, u64, usize), ProtocolError> {
+            if version < GENERATION_PROTOCOL_VERSION {
+                return Ok((0, 0, 0));
+            }
+            let mut pos = 0;
+            let generation = read_varint(content, &mut pos).map_err(|_| invalid("bad generation"))?;
+            let timestamp = read_varint(content, &mut pos).map_err(|_| invalid("bad timestamp"))?;
+            return Ok((generation, timestamp, pos));
+        };
+        return match cmd {
+            CMD_NEW_GRID => {
+                let (generation, timestamp, start) = frame()?;
+                let (&id, cgrid) = content[start..].split_first().ok_or(invalid("missing encoding id"))?;
+                let encoding = Encoding::from_id(id).ok_or(ProtocolError::UnknownEncoding(id))?;
+                Ok(Message::NewGrid {
+                    generation,
+                    timestamp,
+                    encoding,
+                    cgrid: cgrid.to_vec(),
+                })
+            }
+            CMD_LOG_MSG => String::from_utf8(content.to_vec())
+                .map(Message::Log)
+                .map_err(|_| invalid("log is not valid utf8")),
+            CMD_GRID_DIMENSIONS => match content {
+                [w0, w1, h0, h1] => Ok(Message::Dimensions {
+                    width: u16::from_be_bytes([*w0, *w1]),
+                    height: u16::from_be_bytes([*h0, *h1]),
+                }),
+                _ => Err(invalid("dimensions must be two u16")),
+            },
+            CMD_DELTA_GRID => {
+                let (generation, timestamp, mut pos) = frame()?;
+                let mut cells = Vec::new();
+                if version >= VARINT_DELTA_PROTOCOL_VERSION {
+                    let mut cell = 0_u32;
+                    while pos < content.len() {
+                        let gap = read_varint(content, &mut pos).map_err(|_| invalid("bad cell index"))?;
+                        let gap = u32::try_from(gap).map_err(|_| invalid("cell index must fit in a u32"))?;
+                        cell = cell.wrapping_add(gap);
+                        cells.push(cell);
+                    }
+                } else {
+                    let content = &content[pos..];
+                    if !content.len().is_multiple_of(2) {
+                        return Err(invalid("cell indexes must be u16"));
+                    }
+                    cells.extend(content.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]]) as u32));
+                }
+                Ok(Message::DeltaGrid {
+                    generation,
+                    timestamp,
+                    cells,
+                })
+            }
+            CMD_HELLO => Ok(Message::Hello(content.to_vec())),
+            CMD_ENCODING => match content {
+                [id] => Encoding::from_id(*id)
+                    .map(Message::Encoding)
+                    .ok_or(ProtocolError::UnknownEncoding(*id)),
+                _ => Err(invalid("encoding must be a single id")),
+            },
+            CMD_VERSION => match content {
+                [version, capabilities] => Ok(Message::Version {
+                    version: *version,
+                    capabilities: *capabilities,
+                }),
+                _ => Err(invalid("version must be a version and capability flags")),
+            },
+            CMD_ERROR => {
+                let (&code, reason) = content.split_first().ok_or(invalid("missing error code"))?;
+                let reason = String::from_utf8(reason.to_vec()).map_err(|_| invalid("reason is not valid utf8"))?;
+                Ok(Message::Error { code, reason })
+            }
+            CMD_KEYFRAME_REQUEST => match content {
+                [] => Ok(Message::KeyframeRequest),
+                _ => Err(invalid("keyframe request has no content")),
+            },
+            CMD_EDIT_CELLS => {
+                let (&action, cells) = content.split_first().ok_or(invalid("missing edit action"))?;
+                if ![EDIT_SET, EDIT_CLEAR, EDIT_TOGGLE].contains(&action) {
+                    return Err(invalid("unknown edit action"));
+                }
+                if !cells.len().is_multiple_of(4) {
+                    return Err(invalid("cells must be two u16"));
+                }
+                Ok(Message::EditCells {
+                    action,
+                    cells: cells
+                        .chunks_exact(4)
+                        .map(|c| (u16::from_be_bytes([c[0], c[1]]), u16::from_be_bytes([c[2], c[3]])))
+                        .collect(),
+                })
+            }
+            // x and y, then the pattern
+            CMD_STAMP if content.len() > 4 + MAX_STAMP_SIZE => Err(invalid("pattern is too big")),
+            CMD_STAMP => match content {
+                [x0, x1, y0, y1, rle @ ..] => Ok(Message::Stamp {
+                    x: u16::from_be_bytes([*x0, *x1]),
+                    y: u16::from_be_bytes([*y0, *y1]),
+                    rle: String::from_utf8(rle.to_vec()).map_err(|_| invalid("pattern is not valid utf8"))?,
+                }),
+                _ => Err(invalid("stamp must start with two u16")),
+            },
+            _ => Err(ProtocolError::UnknownCommand(cmd)),
+        };
+    }
+}
+
+// What the server agreed on with a client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Handshake {
+    pub version: u8,
+    pub capabilities: u8,
+    // None means the smallest one of every frame for version 2+ and the server default for version 1
+    pub encoding: Option<Encoding>,
+}
+
+/// Settles the protocol with a client from the version and hello it sent, if any. Err is the error to send the
+/// client before closing the connection.
+pub fn accept_handshake(
+    version: Option<(u8, u8)>,
+    hello: Option<&[u8]>,
+    allowed: &[Encoding],
+) -> Result<Handshake, Message> {
+    let refuse = |code, reason: String| Message::Error { code, reason };
+    let (version, capabilities) = match (version, hello) {
+        (None, None) => (LEGACY_PROTOCOL_VERSION, 0),
+        (None, Some(_)) => (HELLO_PROTOCOL_VERSION, CAP_DELTA_GRID),
+        (Some((0, _)), _) => {
+            return Err(refuse(
+                ERR_UNSUPPORTED_VERSION,
+                format!("Protocol version 0 does not exist, this server speaks 1 to {PROTOCOL_VERSION}"),
+            ));
+        }
+        (Some((version, capabilities)), _) => (version.min(PROTOCOL_VERSION), capabilities & SERVER_CAPABILITIES),
+    };
+    if version == LEGACY_PROTOCOL_VERSION {
+        return Ok(Handshake {
+            version,
+            capabilities: 0,
+            encoding: None,
+        });
+    }
+
+    let hello = match hello {
+        Some(hello) => hello,
+        None => {
+            return Err(refuse(
+                ERR_BAD_HANDSHAKE,
+                String::from("Expected a hello after the version"),
+            ))
+        }
+    };
+    let encoding = choose_encoding(hello, allowed);
+    // version 2 clients were sent the smallest encoding when there was no match, so that stays
+    if encoding.is_none() && version >= HANDSHAKE_PROTOCOL_VERSION {
+        return Err(refuse(
+            ERR_NO_COMMON_ENCODING,
+            format!("None of the encodings {hello:?} is known to this server"),
+        ));
+    }
+    return Ok(Handshake {
+        version,
+        capabilities,
+        encoding,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::net::{Encoding, ENCODINGS};
+    use crate::protocol::{
+        accept_handshake, Handshake, Message, ProtocolError, CAP_DELTA_GRID, EDIT_CLEAR, EDIT_TOGGLE,
+        ERR_BAD_HANDSHAKE, ERR_NO_COMMON_ENCODING, ERR_UNSUPPORTED_VERSION, MAX_STAMP_SIZE, PROTOCOL_VERSION,
+        SERVER_CAPABILITIES,
+    };
+
+    fn round_trip(msg: Message) {
+        let bytes = msg.encode();
+        assert_eq!(Message::decode(&bytes).unwrap(), msg);
+        assert_eq!(Message::read(&mut Cursor::new(&bytes)).unwrap(), msg);
+        assert!(matches!(
+            Message::decode(&bytes[..bytes.len() - 1]),
+            Err(ProtocolError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for encoding in ENCODINGS {
+            round_trip(Message::NewGrid {
+                generation: 1234,
+                timestamp: 1_700_000_000_000,
+                encoding,
+                cgrid: vec![1, 200, 0],
+            });
+            round_trip(Message::Encoding(encoding));
+        }
+        round_trip(Message::NewGrid {
+            generation: u64::MAX,
+            timestamp: 0,
+            encoding: Encoding::NONE,
+            cgrid: vec![0; 70_000],
+        });
+        round_trip(Message::Log(String::from("This is a test log message ✓")));
+        round_trip(Message::Dimensions { width: 48, height: 31 });
+        round_trip(Message::DeltaGrid {
+            generation: 7,
+            timestamp: 1_700_000_000_200,
+            cells: vec![0, 300, 65535, 4_194_303],
+        });
+        round_trip(Message::DeltaGrid {
+            generation: 7,
+            timestamp: 1_700_000_000_200,
+            cells: vec![300, 2, u32::MAX, 0],
+        });
+        round_trip(Message::DeltaGrid {
+            generation: 8,
+            timestamp: 1_700_000_000_400,
+            cells: Vec::new(),
+        });
+        round_trip(Message::Hello(vec![3, 2, 9]));
+        round_trip(Message::Version {
+            version: PROTOCOL_VERSION,
+            capabilities: CAP_DELTA_GRID,
+        });
+        round_trip(Message::Error {
+            code: ERR_NO_COMMON_ENCODING,
+            reason: String::from("No common encoding"),
+        });
+        round_trip(Message::KeyframeRequest);
+        round_trip(Message::EditCells {
+            action: EDIT_TOGGLE,
+            cells: vec![(0, 0), (47, 30), (65535, 1)],
+        });
+        round_trip(Message::Stamp {
+            x: 46,
+            y: 2,
+            rle: String::from("bo$2bo$3o!"),
+        });
+    }
+
+    #[test]
+    fn test_older_versions() {
+        let grid = Message::NewGrid {
+            generation: 300,
+            timestamp: 5,
+            encoding: Encoding::RLE,
+            cgrid: vec![1, 3, 0],
+        };
+        // no generation and timestamp before version 4, which decode as 0
+        let old = grid.encode_for(3);
+        assert_eq!(old, [0, 0, 4, 2, 1, 3, 0]);
+        assert_eq!(
+            Message::decode_for(&old, 3).unwrap(),
+            Message::NewGrid {
+                generation: 0,
+                timestamp: 0,
+                encoding: Encoding::RLE,
+                cgrid: vec![1, 3, 0],
+            }
+        );
+        assert_eq!(Message::read_for(&mut Cursor::new(grid.encode()), 4).unwrap(), grid);
+        assert_eq!(Message::Log(String::from("hi")).encode_for(1), [1, 0, 2, b'h', b'i']);
+    }
+
+    #[test]
+    fn test_wire_format() {
+        assert_eq!(
+            Message::Dimensions { width: 48, height: 31 }.encode(),
+            [2, 0, 4, 0, 48, 0, 31]
+        );
+        // generation 300 is 2 varint bytes
+        let delta = Message::DeltaGrid {
+            generation: 300,
+            timestamp: 1,
+            cells: vec![2, 300],
+        };
+        // and cells 2 and 300 are 2 then 298 more
+        assert_eq!(delta.encode(), [3, 0, 6, 0xAC, 0x02, 1, 2, 0xAA, 0x02]);
+        // u16 before varint cells
+        assert_eq!(delta.encode_for(5), [3, 0, 7, 0xAC, 0x02, 1, 0, 2, 1, 44]);
+        assert_eq!(Message::decode_for(&delta.encode_for(5), 5).unwrap(), delta);
+        assert_eq!(
+            Message::NewGrid {
+                generation: 1,
+                timestamp: 2,
+                encoding: Encoding::RLE,
+                cgrid: vec![1, 3, 0]
+            }
+            .encode(),
+            [0, 0, 6, 1, 2, 2, 1, 3, 0]
+        );
+        assert_eq!(Message::KeyframeRequest.encode(), [8, 0, 0]);
+        assert_eq!(
+            Message::EditCells {
+                action: EDIT_CLEAR,
+                cells: vec![(1, 2), (300, 0)],
+            }
+            .encode(),
+            [9, 0, 9, 1, 0, 1, 0, 2, 1, 44, 0, 0]
+        );
+        assert_eq!(
+            Message::Stamp {
+                x: 1,
+                y: 2,
+                rle: String::from("o!"),
+            }
+            .encode(),
+            [10, 0, 6, 0, 1, 0, 2, b'o', b'!']
+        );
+    }
+
+    #[test]
+    fn test_checksum() {
+        let grid = Message::NewGrid {
+            generation: 3,
+            timestamp: 4,
+            encoding: Encoding::VRLE,
+            cgrid: vec![5, 1, 9],
+        };
+        let checked = grid.encode_checked(PROTOCOL_VERSION);
+        assert_eq!(checked.len(), grid.encode().len() + 4);
+        assert_eq!(Message::decode_checked(&checked, PROTOCOL_VERSION).unwrap(), grid);
+        // the CRC-32 of no content is 0
+        assert_eq!(
+            Message::KeyframeRequest.encode_checked(PROTOCOL_VERSION),
+            [8, 0, 4, 0, 0, 0, 0]
+        );
+
+        // a flipped bit anywhere in the content or the CRC is caught, and the next message still reads fine
+        for i in 3..checked.len() {
+            let mut corrupted = checked.clone();
+            corrupted[i] ^= 0x10;
+            let log = Message::Log(String::from("next"));
+            let mut stream = Cursor::new([corrupted, log.encode_checked(PROTOCOL_VERSION)].concat());
+            assert!(matches!(
+                Message::read_checked(&mut stream, PROTOCOL_VERSION),
+                Err(ProtocolError::ChecksumMismatch { cmd: 0 })
+            ));
+            assert_eq!(Message::read_checked(&mut stream, PROTOCOL_VERSION).unwrap(), log);
+        }
+        assert!(matches!(
+            Message::decode_checked(&[1, 0, 2, 0, 0], PROTOCOL_VERSION),
+            Err(ProtocolError::InvalidContent { cmd: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(matches!(
+            Message::decode(&[11, 0, 0]),
+            Err(ProtocolError::UnknownCommand(11))
+        ));
+        assert!(matches!(
+            Message::decode(&[0, 0, 3, 0, 0, 42]),
+            Err(ProtocolError::UnknownEncoding(42))
+        ));
+        assert!(matches!(
+            Message::decode(&[5, 0, 1, 42]),
+            Err(ProtocolError::UnknownEncoding(42))
+        ));
+        assert!(matches!(
+            Message::decode(&[0, 0, 0]),
+            Err(ProtocolError::InvalidContent { cmd: 0, .. })
+        ));
+        assert!(matches!(
+            Message::decode(&[1, 0, 1, 0xFF]),
+            Err(ProtocolError::InvalidContent { cmd: 1, .. })
+        ));
+        assert!(matches!(
+            Message::decode(&[2, 0, 3, 0, 0, 0]),
+            Err(ProtocolError::InvalidContent { cmd: 2, .. })
+        ));
+        assert!(matches!(
+            Message::decode(&[3, 0, 1, 0]),
+            Err(ProtocolError::InvalidContent { cmd: 3, .. })
+        ));
+        assert!(matches!(
+            Message::decode(&[9, 0, 0]),
+            Err(ProtocolError::InvalidContent { cmd: 9, .. })
+        ));
+        assert!(matches!(
+            Message::decode(&[9, 0, 1, 3]),
+            Err(ProtocolError::InvalidContent { cmd: 9, .. })
+        ));
+        assert!(matches!(
+            Message::decode(&[9, 0, 3, 0, 0, 1]),
+            Err(ProtocolError::InvalidContent { cmd: 9, .. })
+        ));
+        assert!(matches!(
+            Message::decode(&[10, 0, 3, 0, 1, 0]),
+            Err(ProtocolError::InvalidContent { cmd: 10, .. })
+        ));
+        let huge = Message::Stamp {
+            x: 0,
+            y: 0,
+            rle: "o".repeat(MAX_STAMP_SIZE + 1),
+        };
+        assert!(matches!(
+            Message::decode(&huge.encode()),
+            Err(ProtocolError::InvalidContent { cmd: 10, .. })
+        ));
+        assert!(matches!(Message::decode(&[0, 0xFF]), Err(ProtocolError::Truncated)));
+        assert!(matches!(
+            Message::read(&mut Cursor::new([0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF])),
+            Err(ProtocolError::TooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_handshake() {
+        let all = ENCODINGS;
+        let accepted = |version, capabilities, encoding| {
+            Ok(Handshake {
+                version,
+                capabilities,
+                encoding,
+            })
+        };
+        let refused = |result: Result<Handshake, Message>| match result {
+            Err(Message::Error { code, .. }) => code,
+            other => panic!("Expected an error, got {other:?}"),
+        };
+
+        // clients from before the hello and before the version
+        assert_eq!(accept_handshake(None, None, &all), accepted(1, 0, None));
+        assert_eq!(
+            accept_handshake(None, Some(&[2]), &all),
+            accepted(2, CAP_DELTA_GRID, Some(Encoding::RLE))
+        );
+        assert_eq!(
+            accept_handshake(None, Some(&[9]), &all),
+            accepted(2, CAP_DELTA_GRID, None)
+        );
+
+        assert_eq!(
+            accept_handshake(Some((3, CAP_DELTA_GRID)), Some(&[3, 2]), &all),
+            accepted(3, CAP_DELTA_GRID, Some(Encoding::VRLE))
+        );
+        // newer clients are downgraded, unknown capabilities dropped
+        assert_eq!(
+            accept_handshake(Some((200, 0xFF)), Some(&[2]), &all),
+            accepted(PROTOCOL_VERSION, SERVER_CAPABILITIES, Some(Encoding::RLE))
+        );
+        assert_eq!(
+            accept_handshake(Some((3, 0)), Some(&[2]), &all),
+            accepted(3, 0, Some(Encoding::RLE))
+        );
+        assert_eq!(
+            accept_handshake(Some((1, CAP_DELTA_GRID)), None, &all),
+            accepted(1, 0, None)
+        );
+
+        assert_eq!(
+            refused(accept_handshake(Some((0, 0)), Some(&[2]), &all)),
+            ERR_UNSUPPORTED_VERSION
+        );
+        assert_eq!(refused(accept_handshake(Some((3, 0)), None, &all)), ERR_BAD_HANDSHAKE);
+        assert_eq!(
+            refused(accept_handshake(Some((3, 0)), Some(&[9]), &all)),
+            ERR_NO_COMMON_ENCODING
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::time::Instant;
+
+use crate::net::{read_varint, write_varint, CMD_NEW_GRID};
+
+// REPLAY FILE
+// [6 bytes ][8bit   ][16bit][16bit ][8bit        ][            ]
+// [GOLREC  ][version][width][height][encoding len][encoding name]
+//
+// followed by one record per broadcast message:
+// [varint              ][varint                  ][varint ][       ]
+// [ms since last record][generations since last ][msg len][message]
+//
+// message is exactly what was written to the raw TCP clients ([cmd][size][content]). Varints are LEB128 (see the
+// net module), so a record usually costs 3-4 bytes on top of the message itself.
+pub const REPLAY_MAGIC: &[u8; 6] = b"GOLREC";
+pub const REPLAY_VERSION: u8 = 1;
+
+pub struct Recorder {
+    file: BufWriter<File>,
+    start: Instant,
+    last_ms: u64,
+    last_generation: u64,
+}
+
+impl Recorder {
+    pub fn create(path: &str, width: usize, height: usize, encoding: &str) -> Result<Recorder> {
+        let mut header = Vec::new();
+        header.extend_from_slice(REPLAY_MAGIC);
+        header.push(REPLAY_VERSION);
+        header.extend_from_slice(&(width as u16).to_be_bytes());
+        header.extend_from_slice(&(height as u16).to_be_bytes());
+        header.push(encoding.len() as u8);
+        header.extend_from_slice(encoding.as_bytes());
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&header)?;
+        return Ok(Recorder {
+            file,
+            start: Instant::now(),
+            last_ms: 0,
+            last_generation: 0,
+        });
+    }
+
+    pub fn record(&mut self, generation: u64, message: &[u8]) -> Result<()> {
+        let ms = self.start.elapsed().as_millis() as u64;
+        let mut record = Vec::with_capacity(message.len() + 8);
+        write_varint(&mut record, ms - self.last_ms);
+        write_varint(&mut record, generation.saturating_sub(self.last_generation));
+        write_varint(&mut record, message.len() as u64);
+        record.extend_from_slice(message);
+        self.last_ms = ms;
+        self.last_generation = generation;
+        return self.file.write_all(&record);
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        return self.file.flush();
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    // milliseconds since the recording started
+    pub ms: u64,
+    pub generation: u64,
+    pub message: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Replay {
+    pub width: usize,
+    pub height: usize,
+    pub encoding: String,
+    pub frames: Vec<Frame>,
+}
+
+impl Replay {
+    pub fn load(path: &str) -> Result<Replay> {
+        return Replay::parse(&fs::read(path)?);
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Replay> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+        if data.len() < 12 || &data[0..6] != REPLAY_MAGIC {
+            return Err(invalid("Not a replay file"));
+        }
+        if data[6] != REPLAY_VERSION {
+            return Err(invalid("Unsupported replay version"));
+        }
+        let width = u16::from_be_bytes([data[7], data[8]]) as usize;
+        let height = u16::from_be_bytes([data[9], data[10]]) as usize;
+        let encoding_len = data[11] as usize;
+        let encoding = data
+            .get(12..(12 + encoding_len))
+            .and_then(|e| String::from_utf8(e.to_vec()).ok())
+            .ok_or_else(|| invalid("Invalid encoding name in replay header"))?;
+
+        let mut frames = Vec::new();
+        let mut pos = 12 + encoding_len;
+        let (mut ms, mut generation) = (0, 0);
+        while pos < data.len() {
+            ms += read_varint(data, &mut pos)?;
+            generation += read_varint(data, &mut pos)?;
+            let len = read_varint(data, &mut pos)? as usize;
+            let message = data
+                .get(pos..(pos + len))
+                .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "Replay ended in the middle of a message"))?;
+            pos += len;
+            frames.push(Frame {
+                ms,
+                generation,
+                message: message.to_vec(),
+            });
+        }
+
+        return Ok(Replay {
+            width,
+            height,
+            encoding,
+            frames,
+        });
+    }
+
+    /// Index of the first frame of `generation`, or of the last frame if the recording ends before it. Delta
+    /// grids only make sense on top of the previous grid, so it goes back to the closest full grid.
+    pub fn seek(&self, generation: u64) -> usize {
+        let idx = self
+            .frames
+            .iter()
+            .position(|f| f.generation >= generation)
+            .unwrap_or(self.frames.len().saturating_sub(1));
+        return self.frames[..(idx + 1).min(self.frames.len())]
+            .iter()
+            .rposition(|f| f.message.first() == Some(&CMD_NEW_GRID))
+            .unwrap_or(idx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::replay::{Recorder, Replay};
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("gol_replay_test_{}.rec", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut recorder = Recorder::create(path, 48, 31, "RLE").unwrap();
+        recorder.record(0, &[0, 0, 3, 1, 200, 0]).unwrap();
+        recorder.record(0, &[1, 0, 2, b'h', b'i']).unwrap();
+        recorder.record(1, &[0, 0, 3, 1, 201, 1]).unwrap();
+        recorder.record(5, &[0, 0, 3, 1, 202, 0]).unwrap();
+        recorder.record(6, &[3, 0, 3, 1, 202, 0]).unwrap();
+        recorder.flush().unwrap();
+
+        let replay = Replay::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!((replay.width, replay.height, replay.encoding.as_str()), (48, 31, "RLE"));
+        assert_eq!(replay.frames.len(), 5);
+        assert_eq!(replay.frames[1].message, [1, 0, 2, b'h', b'i']);
+        assert_eq!(
+            replay.frames.iter().map(|f| f.generation).collect::<Vec<u64>>(),
+            [0, 0, 1, 5, 6]
+        );
+        assert!(replay.frames.windows(2).all(|w| w[0].ms <= w[1].ms));
+
+        assert_eq!(replay.seek(0), 0);
+        assert_eq!(replay.seek(2), 3);
+        // the delta frame of generation 6 goes back to the full grid of generation 5
+        assert_eq!(replay.seek(6), 3);
+        assert_eq!(replay.seek(100), 3);
+        assert!(Replay::parse(b"GOLREC").is_err());
+    }
+}
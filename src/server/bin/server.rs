@@ -1,146 +1,983 @@
 #![allow(static_mut_refs)]
 
-use std::io::{Result, Write};
-use std::net::{TcpListener, TcpStream};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Result, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::exit;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{env, thread};
 
 use crossterm::event::{poll, read, Event, KeyCode};
-use gol_multi::game::{create_state, next_grid, State, GRID, GRID_WIDTH, MS_PER_FRAME, PREV_GRID};
+use gol_multi::api::{api_error, client_call, grid_response, is_api, route, state_response, Call, Status};
+use gol_multi::checkpoint::save_checkpoint;
+use gol_multi::deflate::Deflater;
+use gol_multi::game::{
+    create_state, next_grid, State, CHECKPOINT_INTERVAL_FRAMES, GRID, GRID_HEIGHT, GRID_WIDTH,
+    KEYFRAME_INTERVAL_FRAMES, MS_PER_FRAME, PREV_GRID,
+};
+use gol_multi::http::{serve_file, unbuilt_script, Request, Response, DEFAULT_PUBLIC_DIR, PAGE_SCRIPT};
+use gol_multi::metrics::{metrics_response, observe, Histogram, BYTE_BUCKETS, DELTA_LABEL, SECONDS_BUCKETS};
 use gol_multi::net::{
-    compress_grid, compress_grid_rle, handle_ws_connection, send_ws_msg, send_ws_msg_text, write_data_to_stream,
-    CMD_HEADER_SIZE, CMD_LOG_MSG, CMD_NEW_GRID, SIZE_HEADER_SIZE,
+    apply_grid_delta, compress_grid_smallest, grid_delta, handle_ws_connection, message, read_request, send_ws_close,
+    send_ws_frame, send_ws_msg_deflated, write_data_to_stream, Encoding, WsConnection, WsMessage, WsReader,
+    CMD_NEW_GRID, DEFAULT_ENCODING, ENCODINGS, WS_CLOSE_GOING_AWAY, WS_CLOSE_POLICY_VIOLATION, WS_OPCODE_PING,
+    WS_OPCODE_PONG,
+};
+use gol_multi::pattern::Pattern;
+use gol_multi::protocol::{
+    accept_handshake, split_message, Handshake, Message, ProtocolError, CAP_CRC32, CAP_DELTA_GRID,
+    HANDSHAKE_PROTOCOL_VERSION, LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION, VARINT_DELTA_PROTOCOL_VERSION,
 };
-use gol_multi::term::{end_terminal, render, render_debug_data, reset_terminal, start_terminal};
+use gol_multi::replay::{Recorder, Replay};
+use gol_multi::sse::{event as sse_event, last_event_id, response_head, sse_handshake, SSE_KEEP_ALIVE, SSE_PATH};
+use gol_multi::term::{
+    end_terminal, render, render_debug_data, render_selection, render_status, reset_terminal, start_terminal,
+};
+
+// Both are shared with the threads of the connections
+static ACTIVE_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+// Set when a client connects (once it's in its list) or asks for one, it needs a full grid before delta frames make
+// sense
+static KEYFRAME_NEEDED: AtomicBool = AtomicBool::new(true);
+
+// How long a raw TCP client has to say hello before it's treated as one that doesn't
+const HELLO_TIMEOUT: Duration = Duration::from_millis(500);
+
+// How the grids of a client are sent, so that the grid is encoded once per format in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum GridFormat {
+    // protocol version 1: full grids in the server encoding, without the encoding id
+    Legacy,
+    // grids tagged with their encoding (None being whichever is smallest), delta grids if the client takes them,
+    // written for the protocol version of the client. Every message ends with a CRC-32 if the client checks them
+    Tagged {
+        encoding: Option<Encoding>,
+        deltas: bool,
+        checksum: bool,
+        version: u8,
+    },
+}
+
+// What the stats and the recording are made of
+const ADAPTIVE_FORMAT: GridFormat = GridFormat::Tagged {
+    encoding: None,
+    deltas: true,
+    checksum: false,
+    version: PROTOCOL_VERSION,
+};
+
+impl GridFormat {
+    fn of(handshake: &Handshake) -> GridFormat {
+        if handshake.version == LEGACY_PROTOCOL_VERSION {
+            return GridFormat::Legacy;
+        }
+        return GridFormat::Tagged {
+            encoding: handshake.encoding,
+            deltas: handshake.capabilities & CAP_DELTA_GRID != 0,
+            checksum: handshake.capabilities & CAP_CRC32 != 0,
+            version: handshake.version,
+        };
+    }
+
+    // Whole message as clients of this format read it
+    fn encode(&self, msg: &Message) -> Vec<u8> {
+        return match *self {
+            GridFormat::Legacy => msg.encode_for(LEGACY_PROTOCOL_VERSION),
+            GridFormat::Tagged {
+                checksum: true,
+                version,
+                ..
+            } => msg.encode_checked(version),
+            GridFormat::Tagged { version, .. } => msg.encode_for(version),
+        };
+    }
+}
+
+// A connected client and the format it negotiated
+struct Client {
+    stream: TcpStream,
+    format: GridFormat,
+    // WebSocket clients only: when the last ping went out and whether its pong is still due
+    last_ping: Instant,
+    awaiting_pong: bool,
+    // the server sent a close frame and waits for the one of the client
+    closing: bool,
+    // the close handshake is over (or the connection broke), the client is dropped before the next message
+    closed: bool,
+    // WebSocket clients that negotiated permessage-deflate
+    deflater: Option<Deflater>,
+    // SSE clients that reconnected: the generation of the last grid they got. They get nothing until the game loop
+    // has settled whether they need a keyframe
+    resume: Option<u64>,
+    // for the client's own series in /metrics, the address is kept as it can't be asked once the connection broke
+    peer: String,
+    send_seconds: Histogram,
+}
+
+impl Client {
+    fn new(stream: TcpStream, handshake: &Handshake) -> Client {
+        let peer = stream
+            .peer_addr()
+            .map_or(String::from("unknown"), |addr| addr.to_string());
+        return Client {
+            stream,
+            format: GridFormat::of(handshake),
+            last_ping: Instant::now(),
+            awaiting_pong: false,
+            closing: false,
+            closed: false,
+            deflater: None,
+            resume: None,
+            peer,
+            send_seconds: Histogram::new(&SECONDS_BUCKETS),
+        };
+    }
+}
 
-static mut ACTIVE_CONNECTIONS: u64 = 0;
+// Shared with the thread reading the client, which answers pings and closes from there
+type Clients = Arc<Mutex<Vec<Arc<Mutex<Client>>>>>;
+
+// WebSocket clients are pinged after this long without one, and dropped if the pong takes longer than PONG_TIMEOUT
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+const PONG_TIMEOUT: Duration = Duration::from_secs(5);
+// How long the server waits for web clients to answer its close when quitting
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+// Sends happen from the game loop, so a client that stops reading fails its write after this long and is dropped
+// instead of freezing the game
+const WRITE_TIMEOUT: Duration = Duration::from_millis(MS_PER_FRAME as u64);
+
+// An API request for the game loop and where its response goes. The loop answers between two generations, so
+// calls see and change whole generations
+type ApiCall = (Call, Sender<Response>);
+
+// How long an API request waits for the game loop, which answers within a frame unless it's stuck
+const API_TIMEOUT: Duration = Duration::from_secs(5);
+// Part of a frame a step call can take. Longer steps stop early, the generation answered says how far they got
+const STEP_BUDGET: Duration = Duration::from_millis(MS_PER_FRAME as u64 / 2);
 
 fn main() -> Result<()> {
     println!("Hello, server!");
 
-    let streams: Arc<Mutex<Vec<Mutex<TcpStream>>>> = Arc::new(Mutex::new(Vec::with_capacity(10)));
-    let ws_streams: Arc<Mutex<Vec<Mutex<TcpStream>>>> = Arc::new(Mutex::new(Vec::with_capacity(10)));
+    let streams: Clients = Arc::new(Mutex::new(Vec::with_capacity(10)));
+    let ws_streams: Clients = Arc::new(Mutex::new(Vec::with_capacity(10)));
+    let sse_streams: Clients = Arc::new(Mutex::new(Vec::with_capacity(10)));
+
+    // Every frame is sent with whichever encoding makes it smallest, unless ENCODING forces one
+    let encodings = match env::var("ENCODING") {
+        Ok(encoding) => vec![Encoding::from_str(&encoding)?],
+        Err(_) => ENCODINGS.to_vec(),
+    };
+
+    let (api, api_calls) = channel::<ApiCall>();
 
     unsafe {
         // TODO: Abstract and pass in handle_connection fn
         let streams_clone = Arc::clone(&streams);
+        let allowed = encodings.clone();
+        let api_clone = api.clone();
         thread::spawn(move || {
             let listener = TcpListener::bind("0.0.0.0:42068").unwrap();
             for stream in listener.incoming() {
                 let stream = stream.unwrap();
                 eprintln!("Connection established from {}", stream.peer_addr().unwrap());
-                ACTIVE_CONNECTIONS += 1;
-                streams_clone.lock().unwrap().push(Mutex::new(stream));
+                let streams_clone = Arc::clone(&streams_clone);
+                let allowed = allowed.clone();
+                let api = api_clone.clone();
+                // Waiting for the hello shouldn't hold back the next connections
+                thread::spawn(move || {
+                    let mut writer = stream.try_clone().expect("TCP stream to be cloneable");
+                    let _ = stream.set_read_timeout(Some(HELLO_TIMEOUT));
+                    let handshake = accept_client(
+                        &mut &stream,
+                        &mut |msg| write_data_to_stream(&mut writer, msg),
+                        &allowed,
+                    );
+                    let _ = stream.set_read_timeout(None);
+                    eprintln!("Raw TCP client handshake: {handshake:?}");
+                    let Some(handshake) = handshake else {
+                        let _ = stream.shutdown(Shutdown::Both);
+                        return;
+                    };
+                    let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+                    let mut reader = stream.try_clone().expect("TCP stream to be cloneable");
+                    let client = Arc::new(Mutex::new(Client::new(stream, &handshake)));
+                    ACTIVE_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+                    streams_clone.lock().unwrap().push(Arc::clone(&client));
+                    KEYFRAME_NEEDED.store(true, Ordering::SeqCst);
+                    read_client_messages(&client, &mut reader, &api);
+                });
             }
         });
         let ws_streams_clone = Arc::clone(&ws_streams);
+        let sse_streams_clone = Arc::clone(&sse_streams);
+        let allowed = encodings.clone();
+        // The web page is served on the WebSocket port, so it can connect back to wherever it was loaded from
+        let public_dir = PathBuf::from(env::var("PUBLIC_DIR").unwrap_or(String::from(DEFAULT_PUBLIC_DIR)));
+        if let Some(reason) = unbuilt_script(&public_dir.join(PAGE_SCRIPT)) {
+            eprintln!("WARNING - The web page won't work: {reason}");
+        }
         thread::spawn(move || {
             let listener = TcpListener::bind("0.0.0.0:42069").unwrap();
             for stream in listener.incoming() {
                 let mut stream = stream.unwrap();
                 eprintln!("WS connection established from {}", stream.peer_addr().unwrap());
-                ACTIVE_CONNECTIONS += 1;
-                stream = handle_ws_connection(stream);
-                ws_streams_clone.lock().unwrap().push(Mutex::new(stream));
+                let ws_streams_clone = Arc::clone(&ws_streams_clone);
+                let sse_streams_clone = Arc::clone(&sse_streams_clone);
+                let allowed = allowed.clone();
+                let public_dir = public_dir.clone();
+                let api = api.clone();
+                // A slow upgrade request shouldn't hold back the next connections either
+                thread::spawn(move || {
+                    let request = match read_request(&mut stream) {
+                        Ok(request) => request,
+                        Err(e) => {
+                            eprintln!("Unable to read HTTP request: {e}");
+                            return;
+                        }
+                    };
+                    if request.path == SSE_PATH && !request.is_upgrade() {
+                        accept_sse_client(stream, &request, &sse_streams_clone, &allowed);
+                        return;
+                    }
+                    if !request.is_upgrade() {
+                        serve_http(&mut stream, &request, &public_dir, &api);
+                        return;
+                    }
+                    let (connection, first_msg) = match handle_ws_connection(stream, &request) {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            eprintln!("WS handshake failed: {e}");
+                            return;
+                        }
+                    };
+                    let WsConnection {
+                        mut stream,
+                        reader,
+                        mut deflater,
+                    } = connection;
+                    let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+                    // The version and hello come together in the first frame. Older pages send a hello only, or a
+                    // login key
+                    let handshake = accept_client(
+                        &mut first_msg.as_slice(),
+                        &mut |msg| send_ws_msg_deflated(&mut stream, deflater.as_mut(), msg),
+                        &allowed,
+                    );
+                    eprintln!("WS client handshake: {handshake:?}");
+                    let Some(handshake) = handshake else {
+                        let _ = send_ws_close(&mut stream, Some((WS_CLOSE_POLICY_VIOLATION, "Refused, see the error")));
+                        let _ = stream.shutdown(Shutdown::Both);
+                        return;
+                    };
+                    let client = Arc::new(Mutex::new(Client {
+                        deflater,
+                        ..Client::new(stream, &handshake)
+                    }));
+                    ACTIVE_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+                    ws_streams_clone.lock().unwrap().push(Arc::clone(&client));
+                    KEYFRAME_NEEDED.store(true, Ordering::SeqCst);
+                    read_ws_client_messages(client, reader, &api);
+                });
             }
         });
 
         let streams_clone2 = Arc::clone(&streams);
         let ws_streams_clone2 = Arc::clone(&ws_streams);
         let state: State = create_state();
-        run(state, streams_clone2, ws_streams_clone2)?;
+        match state.replay_path.clone() {
+            Some(path) => run_replay(
+                state,
+                &path,
+                &encodings,
+                streams_clone2,
+                ws_streams_clone2,
+                sse_streams,
+                api_calls,
+            )?,
+            None => run(
+                state,
+                &encodings,
+                streams_clone2,
+                ws_streams_clone2,
+                sse_streams,
+                api_calls,
+            )?,
+        }
     }
 
     Ok(())
 }
 
-#[derive(Debug)]
-enum Encoding {
-    NONE,
-    BINARY,
-    RLE,
+// Answers a plain HTTP request on the WebSocket port with a file of the web page or an API call, and closes the
+// connection
+fn serve_http(stream: &mut TcpStream, request: &Request, public_dir: &Path, api: &Sender<ApiCall>) {
+    let response = if is_api(&request.path) {
+        match route(request) {
+            Ok(call) => call_api(api, call),
+            Err(response) => response,
+        }
+    } else {
+        serve_file(public_dir, request)
+    };
+    eprintln!("HTTP {} {} -> {}", request.method, request.path, response.status);
+    let _ = stream.write_all(&response.encode(request.method == "HEAD"));
+    let _ = stream.shutdown(Shutdown::Write);
+}
+
+// Starts the event stream of a dashboard, and keeps the connection until the dashboard goes away
+unsafe fn accept_sse_client(mut stream: TcpStream, request: &Request, sse_streams: &Clients, allowed: &[Encoding]) {
+    let handshake = if request.method != "GET" {
+        Err(Response::error(405, "only GET is served").header("Allow", "GET"))
+    } else {
+        match sse_handshake(request) {
+            Ok(handshake) if handshake.encoding.is_some_and(|e| !allowed.contains(&e)) => {
+                Err(Response::error(400, "encoding is not allowed"))
+            }
+            Ok(handshake) => Ok(handshake),
+            Err(e) => Err(Response::error(e.status, e.reason)),
+        }
+    };
+    let handshake = match handshake {
+        Ok(handshake) => handshake,
+        Err(response) => {
+            eprintln!("Refusing SSE client: {}", response.status);
+            let _ = stream.write_all(&response.encode(false));
+            let _ = stream.shutdown(Shutdown::Write);
+            return;
+        }
+    };
+    let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+    if stream.write_all(&response_head()).is_err() {
+        return;
+    }
+    let mut reader = stream.try_clone().expect("TCP stream to be cloneable");
+    let resume = last_event_id(request);
+    eprintln!("SSE client handshake: {handshake:?}, resuming from {resume:?}");
+    let client = Arc::new(Mutex::new(Client {
+        resume,
+        ..Client::new(stream, &handshake)
+    }));
+    ACTIVE_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+    sse_streams.lock().unwrap().push(Arc::clone(&client));
+    if resume.is_none() {
+        KEYFRAME_NEEDED.store(true, Ordering::SeqCst);
+    }
+    // Dashboards don't send anything, the read only ends when the connection does
+    let mut buf = [0; 64];
+    while matches!(reader.read(&mut buf), Ok(n) if n > 0) {}
+    let mut client = client.lock().unwrap();
+    client.closed = true;
+    let _ = client.stream.shutdown(Shutdown::Both);
+}
+
+// Whether an SSE client that reconnected has another generation than `sent`, the one of the last grid sent (None
+// if it can't be told). Their generations are taken, from here on they get the grids
+fn resumed_behind(sse_streams: &Clients, sent: Option<u64>) -> bool {
+    let mut behind = false;
+    for client in sse_streams.lock().unwrap().iter() {
+        if let Some(generation) = client.lock().unwrap().resume.take() {
+            behind |= sent != Some(generation);
+        }
+    }
+    return behind;
+}
+
+// Hands an API call to the game loop and waits for its answer
+fn call_api(api: &Sender<ApiCall>, call: Call) -> Response {
+    let (reply, response) = channel();
+    if api.send((call, reply)).is_err() {
+        return api_error(503, "the game is not running");
+    }
+    return response
+        .recv_timeout(API_TIMEOUT)
+        .unwrap_or_else(|_| api_error(503, "the game did not answer in time"));
+}
+
+// Answers the API calls that came since the last frame
+fn answer_api(api_calls: &Receiver<ApiCall>, answer: &mut dyn FnMut(Call) -> Response) {
+    while let Ok((call, reply)) = api_calls.try_recv() {
+        // the caller may have given up waiting
+        let _ = reply.send(answer(call));
+    }
+}
+
+unsafe fn status(state: &State, paused: bool) -> Status {
+    return Status {
+        generation: state.generation,
+        population: GRID.iter().filter(|&&cell| cell == 1).count(),
+        connections: ACTIVE_CONNECTIONS.load(Ordering::SeqCst),
+        paused,
+    };
+}
+
+// Reads the version and hello of a new client and answers them with `send`. None if the client was refused, in
+// which case it has been sent the reason
+fn accept_client(
+    reader: &mut impl Read,
+    send: &mut dyn FnMut(&[u8]) -> Result<usize>,
+    allowed: &[Encoding],
+) -> Option<Handshake> {
+    // the version, if any, comes first. Anything else means a client from before the hello
+    let mut version = None;
+    let mut next = Message::read(reader);
+    if let Ok(Message::Version {
+        version: v,
+        capabilities,
+    }) = next
+    {
+        version = Some((v, capabilities));
+        next = Message::read(reader);
+    }
+    let hello = match next {
+        Ok(Message::Hello(ids)) => Some(ids),
+        _ => None,
+    };
+
+    return match accept_handshake(version, hello.as_deref(), allowed) {
+        Ok(handshake) => {
+            if version.is_some() {
+                let _ = send(
+                    &Message::Version {
+                        version: handshake.version,
+                        capabilities: handshake.capabilities,
+                    }
+                    .encode(),
+                );
+            }
+            // from here on the client checks the CRC-32s it asked for
+            if let Some(encoding) = handshake.encoding {
+                let _ = send(&GridFormat::of(&handshake).encode(&Message::Encoding(encoding)));
+            }
+            Some(handshake)
+        }
+        Err(error) => {
+            eprintln!("Refusing client: {error:?}");
+            let _ = send(&error.encode());
+            None
+        }
+    };
+}
+
+// Hands a cell edit or stamp of a client to the game loop, like the API call that does the same. Nothing is sent
+// back, the client sees the change in the next grid
+fn apply_client_edit(api: &Sender<ApiCall>, msg: &Message) {
+    let response = match client_call(msg) {
+        Ok(call) => call_api(api, call),
+        Err(reason) => {
+            eprintln!("Refusing client edit: {reason}");
+            return;
+        }
+    };
+    if response.status != 200 {
+        eprintln!("Client edit not applied: {}", String::from_utf8_lossy(&response.body));
+    }
+}
+
+// Handles what a raw TCP client sends after the handshake, until it disconnects. The client is marked closed then,
+// so the game loop drops it instead of writing to it
+unsafe fn read_client_messages(client: &Mutex<Client>, reader: &mut TcpStream, api: &Sender<ApiCall>) {
+    loop {
+        match Message::read(reader) {
+            Ok(Message::KeyframeRequest) => KEYFRAME_NEEDED.store(true, Ordering::SeqCst),
+            Ok(msg @ (Message::EditCells { .. } | Message::Stamp { .. })) => apply_client_edit(api, &msg),
+            Ok(msg) => eprintln!("Unexpected client message: {msg:?}"),
+            // the whole message was read, so the next one can still be parsed
+            Err(
+                e @ (ProtocolError::UnknownCommand(_)
+                | ProtocolError::UnknownEncoding(_)
+                | ProtocolError::InvalidContent { .. }),
+            ) => eprintln!("Skipping client message: {e}"),
+            Err(_) => {
+                client.lock().unwrap().closed = true;
+                return;
+            }
+        }
+    }
+}
+
+// Same for WebSocket clients, whose messages come one per WebSocket message. Pings are answered right away and a
+// close ends the connection, answering it if the client started it
+unsafe fn read_ws_client_messages(client: Arc<Mutex<Client>>, mut reader: WsReader<TcpStream>, api: &Sender<ApiCall>) {
+    loop {
+        let msg = reader.read_message();
+        let mut client = client.lock().unwrap();
+        match msg {
+            Ok(WsMessage::Binary(msg)) => match Message::decode(&msg) {
+                Ok(Message::KeyframeRequest) => KEYFRAME_NEEDED.store(true, Ordering::SeqCst),
+                Ok(msg @ (Message::EditCells { .. } | Message::Stamp { .. })) => {
+                    // the game loop needs the client to send it the frame before it gets to the edit
+                    drop(client);
+                    apply_client_edit(api, &msg);
+                }
+                Ok(msg) => eprintln!("Unexpected WS client message: {msg:?}"),
+                Err(e) => eprintln!("Skipping WS client message: {e}"),
+            },
+            Ok(WsMessage::Ping(payload)) => {
+                let _ = send_ws_frame(&mut client.stream, WS_OPCODE_PONG, &payload);
+            }
+            Ok(WsMessage::Pong(_)) => client.awaiting_pong = false,
+            Ok(WsMessage::Close(close)) => {
+                eprintln!("WS client closed the connection: {close:?}");
+                if !client.closing {
+                    let _ = send_ws_close(&mut client.stream, close.as_ref().map(|(code, _)| (*code, "")));
+                }
+                client.closed = true;
+                let _ = client.stream.shutdown(Shutdown::Both);
+                return;
+            }
+            Ok(msg) => eprintln!("Ignoring WS client message: {msg:?}"),
+            Err(e) => {
+                // nothing to tell a client that was dropped or whose connection broke
+                if !client.closed {
+                    eprintln!("Unable to read WS client message: {e}");
+                    if let Some(code) = e.close_code() {
+                        let _ = send_ws_close(&mut client.stream, Some((code, &e.to_string())));
+                    }
+                }
+                client.closed = true;
+                let _ = client.stream.shutdown(Shutdown::Both);
+                return;
+            }
+        }
+    }
+}
+
+// Pings the web clients that are due one and drops the ones that closed or never answered the last one
+unsafe fn keep_alive(ws_streams: &Clients) {
+    ws_streams.lock().unwrap().retain(|client| {
+        let mut client = client.lock().unwrap();
+        if client.awaiting_pong && client.last_ping.elapsed() > PONG_TIMEOUT {
+            eprintln!("No pong from WS client in {PONG_TIMEOUT:?}, dropping it");
+            client.closed = true;
+            let _ = client.stream.shutdown(Shutdown::Both);
+        }
+        if !client.closed && !client.awaiting_pong && client.last_ping.elapsed() >= PING_INTERVAL {
+            client.last_ping = Instant::now();
+            client.awaiting_pong = true;
+            client.closed = send_ws_frame(&mut client.stream, WS_OPCODE_PING, &[]).is_err();
+        }
+        if client.closed {
+            ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        return true;
+    });
+}
+
+// Sends a comment to the SSE clients that had no event in PING_INTERVAL, and drops the ones that are gone
+unsafe fn keep_sse_alive(sse_streams: &Clients) {
+    sse_streams.lock().unwrap().retain(|client| {
+        let mut client = client.lock().unwrap();
+        if !client.closed && client.last_ping.elapsed() >= PING_INTERVAL {
+            client.last_ping = Instant::now();
+            client.closed = client.stream.write_all(SSE_KEEP_ALIVE).is_err();
+        }
+        if client.closed {
+            ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        return true;
+    });
+}
+
+// Close handshake with every web client, waiting up to CLOSE_TIMEOUT for their answers
+fn close_ws_clients(ws_streams: &Clients, code: u16, reason: &str) {
+    let clients = ws_streams.lock().unwrap().clone();
+    for client in &clients {
+        let mut client = client.lock().unwrap();
+        client.closing = true;
+        client.closed = send_ws_close(&mut client.stream, Some((code, reason))).is_err();
+    }
+    let start = Instant::now();
+    while start.elapsed() < CLOSE_TIMEOUT && clients.iter().any(|c| !c.lock().unwrap().closed) {
+        thread::sleep(Duration::from_millis(20));
+    }
+    for client in &clients {
+        let _ = client.lock().unwrap().stream.shutdown(Shutdown::Both);
+    }
+}
+
+// Version 1 clients only know the encoding the server was started with
+fn legacy_encoding(encodings: &[Encoding]) -> Encoding {
+    return match encodings {
+        [encoding] => *encoding,
+        _ => DEFAULT_ENCODING,
+    };
 }
 
-const DEFAULT_ENCODING: Encoding = Encoding::RLE;
+fn now_ms() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+}
+
+// Formats negotiated by the connected clients, plus the adaptive one
+fn formats_in_use(streams: &Clients, ws_streams: &Clients, sse_streams: &Clients) -> HashSet<GridFormat> {
+    let mut in_use = HashSet::from([ADAPTIVE_FORMAT]);
+    for clients in [streams, ws_streams, sse_streams] {
+        in_use.extend(clients.lock().unwrap().iter().map(|c| c.lock().unwrap().format));
+    }
+    return in_use;
+}
 
-impl FromStr for Encoding {
-    type Err = std::io::Error;
+// Size of the grid content for the adaptive format, as a full grid and as sent, and the label of what was sent in
+// the metrics
+struct GridLengths {
+    full: usize,
+    sent: usize,
+    encoding: String,
+}
 
-    fn from_str(s: &str) -> Result<Encoding> {
-        match s {
-            "NONE" => Ok(Encoding::NONE),
-            "BINARY" => Ok(Encoding::BINARY),
-            "RLE" => Ok(Encoding::RLE),
-            _ => {
-                eprintln!("No ENCODING found named {s}. Defaulting to {DEFAULT_ENCODING:?}");
-                return Ok(DEFAULT_ENCODING);
+// The current grid as each of `formats` gets it. Only the cells that changed (`delta`) are sent to clients that
+// take deltas, unless the full grid happens to be smaller
+unsafe fn grid_msgs(
+    formats: HashSet<GridFormat>,
+    encodings: &[Encoding],
+    legacy_encoding: Encoding,
+    delta: Option<&[u32]>,
+    generation: u64,
+) -> (HashMap<GridFormat, Vec<u8>>, GridLengths) {
+    let timestamp = now_ms();
+    let mut msgs = HashMap::new();
+    let mut lengths = GridLengths {
+        full: 0,
+        sent: 0,
+        encoding: String::new(),
+    };
+    for format in formats {
+        let (encoding, deltas, version) = match format {
+            GridFormat::Legacy => {
+                msgs.insert(format, message(CMD_NEW_GRID, &legacy_encoding.compress()));
+                continue;
             }
+            // clients before VARINT_DELTA_PROTOCOL_VERSION can't be sent the cells of bigger boards
+            GridFormat::Tagged {
+                encoding,
+                deltas,
+                version,
+                ..
+            } => (
+                encoding,
+                deltas && (version >= VARINT_DELTA_PROTOCOL_VERSION || GRID_WIDTH * GRID_HEIGHT <= 1 << 16),
+                version,
+            ),
+        };
+        let (grid_encoding, cgrid) = match encoding {
+            Some(encoding) => compress_grid_smallest(&[encoding]),
+            None => compress_grid_smallest(encodings),
+        };
+        let grid_msg = Message::NewGrid {
+            generation,
+            timestamp,
+            encoding: grid_encoding,
+            cgrid,
+        };
+        let delta_msg = delta.filter(|_| deltas).map(|delta| Message::DeltaGrid {
+            generation,
+            timestamp,
+            cells: delta.to_vec(),
+        });
+        let msg = match delta_msg {
+            Some(delta_msg) if delta_msg.content_for(version).len() < grid_msg.content_for(version).len() => delta_msg,
+            _ => grid_msg.clone(),
+        };
+        if format == ADAPTIVE_FORMAT {
+            lengths.full = grid_msg.content().len();
+            lengths.sent = msg.content().len();
+            lengths.encoding = match msg {
+                Message::DeltaGrid { .. } => String::from(DELTA_LABEL),
+                _ => format!("{grid_encoding:?}"),
+            };
         }
+        msgs.insert(format, format.encode(&msg));
+    }
+    return (msgs, lengths);
+}
+
+// Places a pattern on both grids. next_grid reads the neighbours from PREV_GRID, so editing only GRID would be
+// undone by the next generation
+unsafe fn stamp(pattern: &Pattern) {
+    pattern.union(&mut GRID, GRID_WIDTH, GRID_HEIGHT);
+    pattern.union(&mut PREV_GRID, GRID_WIDTH, GRID_HEIGHT);
+}
+
+// Sends a message to every client (and to the recording, if any), dropping the clients that are gone
+unsafe fn broadcast(
+    state: &mut State,
+    streams: &Clients,
+    ws_streams: &Clients,
+    sse_streams: &Clients,
+    recorder: &mut Option<Recorder>,
+    msg: &Message,
+) {
+    record(state, recorder, &msg.encode());
+    let msgs: HashMap<GridFormat, Vec<u8>> = formats_in_use(streams, ws_streams, sse_streams)
+        .into_iter()
+        .map(|format| (format, format.encode(msg)))
+        .collect();
+    send_to_clients(state, streams, ws_streams, sse_streams, None, &|format| &msgs[&format]);
+}
+
+fn record(state: &State, recorder: &mut Option<Recorder>, msg: &[u8]) {
+    if let Some(recorder) = recorder {
+        if let Err(e) = recorder.record(state.generation, msg) {
+            eprintln!("Unable to write recording: {e}");
+        }
+    }
+}
+
+// Sends every client the message for the format it negotiated, dropping the clients that are gone. `generation` is
+// the one of a grid message, which event stream clients get as the event id
+unsafe fn send_to_clients<'a>(
+    state: &mut State,
+    streams: &Clients,
+    ws_streams: &Clients,
+    sse_streams: &Clients,
+    generation: Option<u64>,
+    msg_for: &dyn Fn(GridFormat) -> &'a [u8],
+) {
+    streams.lock().unwrap().retain(|client| {
+        let mut client = client.lock().unwrap();
+        let msg = msg_for(client.format);
+        eprintln!("Sending msg: {:?}", msg);
+        let peer_addr = client.stream.peer_addr();
+        if peer_addr.is_err() || client.closed {
+            ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        let peer_addr = peer_addr.unwrap();
+        let start = Instant::now();
+        match write_data_to_stream(&mut client.stream, msg) {
+            Ok(sent) => state.total_bytes_sent += sent,
+            Err(e) => {
+                eprintln!("Unable to send to {peer_addr}, dropping it: {e}");
+                let _ = client.stream.shutdown(Shutdown::Both);
+                ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+                return false;
+            }
+        }
+        state.total_messages_sent += 1;
+
+        let _ = client.stream.flush();
+        let seconds = start.elapsed().as_secs_f64();
+        observe(&mut state.send_seconds, "tcp", &SECONDS_BUCKETS, seconds);
+        client.send_seconds.observe(seconds);
+        eprintln!("Sent to {peer_addr}");
+        return true;
+    });
+    ws_streams.lock().unwrap().retain(|client| {
+        let client = &mut *client.lock().unwrap();
+        let msg = msg_for(client.format);
+        let peer_addr = client.stream.peer_addr();
+        if peer_addr.is_err() || client.closed {
+            ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        let peer_addr = peer_addr.unwrap();
+        eprintln!("Sending to {peer_addr}");
+        let start = Instant::now();
+        match send_ws_msg_deflated(&mut client.stream, client.deflater.as_mut(), msg) {
+            Ok(sent) => {
+                state.total_bytes_sent += sent;
+                let seconds = start.elapsed().as_secs_f64();
+                observe(&mut state.send_seconds, "ws", &SECONDS_BUCKETS, seconds);
+                client.send_seconds.observe(seconds);
+            }
+            Err(e) => {
+                eprintln!("Unable to send to {peer_addr}, dropping it: {e}");
+                let _ = client.stream.shutdown(Shutdown::Both);
+                ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+                return false;
+            }
+        }
+        state.total_messages_sent += 1;
+        eprintln!("Sent to {peer_addr}");
+        return true;
+    });
+    let mut events: HashMap<GridFormat, Vec<u8>> = HashMap::new();
+    sse_streams.lock().unwrap().retain(|client| {
+        let client = &mut *client.lock().unwrap();
+        if client.closed {
+            ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        // Resuming clients wait for the run loop to know whether they missed a generation
+        if client.resume.is_some() {
+            return true;
+        }
+        let event = events
+            .entry(client.format)
+            .or_insert_with(|| sse_event(msg_for(client.format), generation));
+        let start = Instant::now();
+        if let Err(e) = client.stream.write_all(event) {
+            eprintln!("Unable to send event, dropping the stream: {e}");
+            let _ = client.stream.shutdown(Shutdown::Both);
+            ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        state.total_bytes_sent += event.len();
+        state.total_messages_sent += 1;
+        client.last_ping = Instant::now();
+        let seconds = start.elapsed().as_secs_f64();
+        observe(&mut state.send_seconds, "sse", &SECONDS_BUCKETS, seconds);
+        client.send_seconds.observe(seconds);
+        return true;
+    });
+}
+
+// Send times of the clients still connected, by transport and address
+fn peer_send_seconds(
+    streams: &Clients,
+    ws_streams: &Clients,
+    sse_streams: &Clients,
+) -> Vec<(&'static str, String, Histogram)> {
+    let mut peers = Vec::new();
+    for (transport, clients) in [("tcp", streams), ("ws", ws_streams), ("sse", sse_streams)] {
+        for client in clients.lock().unwrap().iter() {
+            let client = client.lock().unwrap();
+            if !client.closed {
+                peers.push((transport, client.peer.clone(), client.send_seconds.clone()));
+            }
+        }
+    }
+    return peers;
+}
+
+unsafe fn next_generation(state: &mut State) {
+    let start = Instant::now();
+    next_grid();
+    state.step_seconds.observe(start.elapsed().as_secs_f64());
+    state.generation += 1;
+}
+
+unsafe fn write_checkpoint(state: &State) {
+    match save_checkpoint(&state.checkpoint_path, state) {
+        Ok(()) => eprintln!(
+            "Checkpoint written to {} at generation {}",
+            state.checkpoint_path, state.generation
+        ),
+        Err(e) => eprintln!("Unable to write checkpoint to {}: {e}", state.checkpoint_path),
+    }
+}
+
+const CLIPBOARD_FILE: &str = "clipboard.rle";
+
+// Operator cursor, the selection being made (from anchor to cursor) and the copied cells
+struct Clipboard {
+    cursor: (usize, usize),
+    anchor: Option<(usize, usize)>,
+    pattern: Option<Pattern>,
+}
+
+impl Clipboard {
+    fn move_cursor(&mut self, dx: isize, dy: isize) {
+        self.cursor.0 = (self.cursor.0 as isize + dx).rem_euclid(GRID_WIDTH as isize) as usize;
+        self.cursor.1 = (self.cursor.1 as isize + dy).rem_euclid(GRID_HEIGHT as isize) as usize;
+    }
+
+    // (x, y, w, h) of the selected rectangle
+    fn selection(&self) -> Option<(usize, usize, usize, usize)> {
+        let (ax, ay) = self.anchor?;
+        let (cx, cy) = self.cursor;
+        return Some((ax.min(cx), ay.min(cy), ax.abs_diff(cx) + 1, ay.abs_diff(cy) + 1));
+    }
+
+    unsafe fn copy(&mut self) -> Option<(usize, usize)> {
+        let (x, y, w, h) = self.selection()?;
+        self.pattern = Some(Pattern::from_region(&GRID, GRID_WIDTH, GRID_HEIGHT, x, y, w, h));
+        self.anchor = None;
+        return Some((x, y));
+    }
+
+    unsafe fn cut(&mut self) {
+        if let Some((x, y)) = self.copy() {
+            // Every copied cell is alive, so toggling them clears the region
+            let copied = self.pattern.as_ref().unwrap().translate(x as isize, y as isize);
+            copied.xor(&mut GRID, GRID_WIDTH, GRID_HEIGHT);
+            copied.xor(&mut PREV_GRID, GRID_WIDTH, GRID_HEIGHT);
+        }
+    }
+
+    unsafe fn paste(&self) {
+        if let Some(pattern) = &self.pattern {
+            stamp(&pattern.translate(self.cursor.0 as isize, self.cursor.1 as isize));
+        }
+    }
+
+    fn transform(&mut self, f: fn(&Pattern) -> Pattern) {
+        self.pattern = self.pattern.as_ref().map(f);
+    }
+
+    fn status(&self) -> String {
+        let clipboard = match &self.pattern {
+            Some(p) => format!("{}x{} ({} cells)", p.width(), p.height(), p.len()),
+            None => String::from("empty"),
+        };
+        return format!(
+            "cursor: {:?} clipboard: {clipboard} | arrows v:select c:copy x:cut p:paste r:rotate f/F:flip s/l:save/load",
+            self.cursor
+        );
     }
 }
 
 unsafe fn run(
     mut state: State,
-    streams: Arc<Mutex<Vec<Mutex<TcpStream>>>>,
-    ws_streams: Arc<Mutex<Vec<Mutex<TcpStream>>>>,
+    encodings: &[Encoding],
+    streams: Clients,
+    ws_streams: Clients,
+    sse_streams: Clients,
+    api_calls: Receiver<ApiCall>,
 ) -> Result<()> {
     start_terminal()?;
+    let legacy_encoding = legacy_encoding(encodings);
 
-    let encoding = match env::var("ENCODING") {
-        Ok(encoding) => Encoding::from_str(&encoding)?,
-        Err(_) => DEFAULT_ENCODING,
-    };
+    if !state.resumed {
+        /* GLIDER */
+        stamp(&Pattern::glider().translate(1, 1));
+        /**/
 
-    /* GLIDER */
-    GRID[1 + GRID_WIDTH * 3] = 1;
-    GRID[2 + GRID_WIDTH * 3] = 1;
-    GRID[3 + GRID_WIDTH * 3] = 1;
-    GRID[2 + GRID_WIDTH * 1] = 1;
-    GRID[3 + GRID_WIDTH * 2] = 1;
-
-    PREV_GRID[1 + GRID_WIDTH * 3] = 1;
-    PREV_GRID[2 + GRID_WIDTH * 3] = 1;
-    PREV_GRID[3 + GRID_WIDTH * 3] = 1;
-    PREV_GRID[2 + GRID_WIDTH * 1] = 1;
-    PREV_GRID[3 + GRID_WIDTH * 2] = 1;
-    /**/
-
-    /* GLIDER 2 */
-    GRID[6 + GRID_WIDTH * 4] = 1;
-    GRID[7 + GRID_WIDTH * 4] = 1;
-    GRID[8 + GRID_WIDTH * 4] = 1;
-    GRID[7 + GRID_WIDTH * 2] = 1;
-    GRID[8 + GRID_WIDTH * 3] = 1;
-
-    PREV_GRID[6 + GRID_WIDTH * 4] = 1;
-    PREV_GRID[7 + GRID_WIDTH * 4] = 1;
-    PREV_GRID[8 + GRID_WIDTH * 4] = 1;
-    PREV_GRID[7 + GRID_WIDTH * 2] = 1;
-    PREV_GRID[8 + GRID_WIDTH * 3] = 1;
-    /**/
-
-    /* STICK * /
-    GRID[0 + GRID_WIDTH * 0] = 1;
-    GRID[1 + GRID_WIDTH * 0] = 1;
-    GRID[2 + GRID_WIDTH * 0] = 1;
-
-    PREV_GRID[0 + GRID_WIDTH * 0] = 1;
-    PREV_GRID[1 + GRID_WIDTH * 0] = 1;
-    PREV_GRID[2 + GRID_WIDTH * 0] = 1;
-    / **/
+        /* GLIDER 2 */
+        stamp(&Pattern::glider().translate(6, 2));
+        /**/
+
+        /* STICK * /
+        stamp(&Pattern::stick());
+        / **/
+    }
 
     let mut clock;
     let mut exit = false;
 
-    let mut cmd_msg: [u8; CMD_HEADER_SIZE];
-    let mut size_msg: [u8; SIZE_HEADER_SIZE];
-    let mut grid_msg: Vec<u8>;
+    // generation and cells of the last grid sent
+    let mut last_sent: Option<(u64, Vec<u8>)> = None;
+    // whether that generation went out more than once, edited while paused
+    let mut resent = false;
+    // by the operator or through the API. Clients keep getting the grid when it's edited
+    let mut paused = false;
 
     let mut send_msg = false;
     let log_msg = "This is a test log message";
-    let log_msg_size: u16 = log_msg.len().try_into().expect("Size must fit in 16bits");
+
+    let mut recorder = match &state.record_path {
+        Some(path) => Some(Recorder::create(
+            path,
+            GRID_WIDTH,
+            GRID_HEIGHT,
+            &format!("{RECORDING_PROTOCOL}{PROTOCOL_VERSION}"),
+        )?),
+        None => None,
+    };
+
+    let mut clipboard = Clipboard {
+        cursor: (0, 0),
+        anchor: None,
+        pattern: None,
+    };
 
     loop {
         clock = Instant::now();
@@ -153,6 +990,37 @@ unsafe fn run(
                     KeyCode::Char('m') => {
                         send_msg = true;
                     }
+                    KeyCode::Char(' ') => paused = !paused,
+                    KeyCode::Left => clipboard.move_cursor(-1, 0),
+                    KeyCode::Right => clipboard.move_cursor(1, 0),
+                    KeyCode::Up => clipboard.move_cursor(0, -1),
+                    KeyCode::Down => clipboard.move_cursor(0, 1),
+                    KeyCode::Char('v') => {
+                        clipboard.anchor = match clipboard.anchor {
+                            Some(_) => None,
+                            None => Some(clipboard.cursor),
+                        };
+                    }
+                    KeyCode::Char('c') => {
+                        clipboard.copy();
+                    }
+                    KeyCode::Char('x') => clipboard.cut(),
+                    KeyCode::Char('p') => clipboard.paste(),
+                    KeyCode::Char('r') => clipboard.transform(Pattern::rotate_90),
+                    KeyCode::Char('f') => clipboard.transform(Pattern::flip_horizontal),
+                    KeyCode::Char('F') => clipboard.transform(Pattern::flip_vertical),
+                    KeyCode::Char('s') => {
+                        if let Some(pattern) = &clipboard.pattern {
+                            match pattern.save_rle(CLIPBOARD_FILE) {
+                                Ok(()) => eprintln!("Clipboard saved to {CLIPBOARD_FILE}"),
+                                Err(e) => eprintln!("Unable to save clipboard to {CLIPBOARD_FILE}: {e}"),
+                            }
+                        }
+                    }
+                    KeyCode::Char('l') => match Pattern::load_rle(CLIPBOARD_FILE) {
+                        Ok(pattern) => clipboard.pattern = Some(pattern),
+                        Err(e) => eprintln!("Unable to load clipboard from {CLIPBOARD_FILE}: {e}"),
+                    },
                     _ => {}
                 },
                 _ => {}
@@ -160,80 +1028,143 @@ unsafe fn run(
         }
         if exit {
             reset_terminal()?;
+            write_checkpoint(&state);
+            close_ws_clients(&ws_streams, WS_CLOSE_GOING_AWAY, "Server is shutting down");
             break;
         }
 
+        answer_api(&api_calls, &mut |call| match call {
+            Call::State => state_response(&status(&state, paused)),
+            Call::Grid(view) => grid_response(view, state.generation, &GRID),
+            Call::Metrics => metrics_response(
+                &state,
+                &status(&state, paused),
+                &peer_send_seconds(&streams, &ws_streams, &sse_streams),
+            ),
+            Call::Cells(edit) => {
+                // both grids, like stamp
+                edit.apply(&mut GRID);
+                edit.apply(&mut PREV_GRID);
+                state_response(&status(&state, paused))
+            }
+            Call::Pause(pause) => {
+                paused = pause;
+                state_response(&status(&state, paused))
+            }
+            Call::Step(_) if !paused => api_error(409, "the game is running, pause it first"),
+            Call::Step(generations) => {
+                let start = Instant::now();
+                for _ in 0..generations {
+                    if start.elapsed() >= STEP_BUDGET {
+                        break;
+                    }
+                    next_generation(&mut state);
+                }
+                state_response(&status(&state, paused))
+            }
+            Call::Pattern(pattern) => {
+                stamp(&pattern);
+                state_response(&status(&state, paused))
+            }
+        });
+
         render()?;
+        render_selection(clipboard.cursor, clipboard.selection())?;
+        if paused {
+            render_status(&format!("PAUSED (space: resume) | {}", clipboard.status()))?;
+        } else {
+            render_status(&clipboard.status())?;
+        }
         //render_txt()?;
-        render_debug_data(true, &state, &ACTIVE_CONNECTIONS)?;
+        render_debug_data(true, &state, &ACTIVE_CONNECTIONS.load(Ordering::SeqCst))?;
 
-        cmd_msg = CMD_NEW_GRID.to_be_bytes();
-        grid_msg = match encoding {
-            Encoding::NONE => GRID.to_vec(),
-            Encoding::BINARY => compress_grid().to_vec(),
-            Encoding::RLE => compress_grid_rle(),
-        };
-        size_msg = (grid_msg.len() as u16).to_be_bytes();
-        state.encoded_grid_lengths.push(grid_msg.len());
-
-        eprintln!("Sending cmd_msg: {:?}", cmd_msg);
-        eprintln!("Sending size_msg: {:?}", size_msg);
-        eprintln!("Sending content_msg: {:?}", grid_msg);
-
-        streams.lock().unwrap().retain(|stream| {
-            let mut stream_lock = stream.lock().unwrap();
-            // TODO: Handle connection errors/dcs
-            let peer_addr = stream_lock.peer_addr();
-            if peer_addr.is_err() {
-                ACTIVE_CONNECTIONS -= 1;
-                return false;
-            }
-            let peer_addr = peer_addr.unwrap();
-            state.total_bytes_sent += write_data_to_stream(&mut stream_lock, &cmd_msg).expect("write call to {peer_addr} to succeed");
-            state.total_bytes_sent += write_data_to_stream(&mut stream_lock, &size_msg).expect("write call to {peer_addr} to succeed");
-            state.total_bytes_sent += write_data_to_stream(&mut stream_lock, &grid_msg).expect("write call to {peer_addr} to succeed");
-            state.total_messages_sent += 1;
-
-            if send_msg {
-                size_msg = [(log_msg_size >> 8 & 0xFF) as u8, (log_msg_size & 0xFF) as u8];
-                state.total_bytes_sent += write_data_to_stream(&mut stream_lock, &CMD_LOG_MSG.to_be_bytes())
-                    .expect("write call to {peer_addr} to succeed");
-                state.total_bytes_sent +=
-                    write_data_to_stream(&mut stream_lock, &size_msg).expect("write call to {peer_addr} to succeed");
-                state.total_bytes_sent += write_data_to_stream(&mut stream_lock, &log_msg.as_bytes())
-                    .expect("write call to {peer_addr} to succeed");
-                state.total_messages_sent += 1;
-            }
-
-            let _ = stream_lock.flush();
-            eprintln!("Sent to {peer_addr}");
-            return true;
-        });
-        ws_streams.lock().unwrap().retain(|stream| {
-            let mut stream_lock = stream.lock().unwrap();
-            // TODO: Handle connection errors/dcs
-            let peer_addr = stream_lock.peer_addr();
-            if peer_addr.is_err() {
-                ACTIVE_CONNECTIONS -= 1;
-                return false;
+        keep_alive(&ws_streams);
+        keep_sse_alive(&sse_streams);
+        let sent = last_sent
+            .as_ref()
+            .filter(|_| !resent)
+            .map(|(generation, _)| *generation);
+        if resumed_behind(&sse_streams, sent) {
+            KEYFRAME_NEEDED.store(true, Ordering::SeqCst);
+        }
+        // While paused the grid only goes out again after an edit or a step, or for a client that needs a keyframe
+        let frame_due = !paused
+            || KEYFRAME_NEEDED.load(Ordering::SeqCst)
+            || last_sent
+                .as_ref()
+                .is_none_or(|(generation, grid)| *generation != state.generation || grid[..] != GRID[..]);
+        if frame_due {
+            // The grid is encoded once per format some client negotiated. The adaptive one is always needed for the
+            // stats and the recording. Deltas only go on top of the generation before, so an edit while paused goes
+            // out as a keyframe
+            // taken right away, so a client asking while this frame goes out gets the next one
+            let keyframe_due =
+                KEYFRAME_NEEDED.swap(false, Ordering::SeqCst) || state.frames % KEYFRAME_INTERVAL_FRAMES == 0;
+            let delta = match &last_sent {
+                Some((generation, last)) if !keyframe_due && generation + 1 == state.generation => {
+                    Some(grid_delta(last, &GRID))
+                }
+                _ => None,
+            };
+            resent = last_sent
+                .as_ref()
+                .is_some_and(|(generation, _)| *generation == state.generation);
+            last_sent = Some((state.generation, GRID.to_vec()));
+
+            let (msgs, lengths) = grid_msgs(
+                formats_in_use(&streams, &ws_streams, &sse_streams),
+                encodings,
+                legacy_encoding,
+                delta.as_deref(),
+                state.generation,
+            );
+            state.full_grid_bytes += lengths.full;
+            state.encoded_grid_lengths.push(lengths.sent);
+            state.sent_grid_bytes += lengths.sent;
+            observe(
+                &mut state.grid_bytes,
+                &lengths.encoding,
+                &BYTE_BUCKETS,
+                lengths.sent as f64,
+            );
+            record(&state, &mut recorder, &msgs[&ADAPTIVE_FORMAT]);
+            let generation = state.generation;
+            send_to_clients(
+                &mut state,
+                &streams,
+                &ws_streams,
+                &sse_streams,
+                Some(generation),
+                &|format| &msgs[&format],
+            );
+        }
+        if send_msg {
+            broadcast(
+                &mut state,
+                &streams,
+                &ws_streams,
+                &sse_streams,
+                &mut recorder,
+                &Message::Log(log_msg.to_string()),
+            );
+        }
+        if let Some(recorder) = &mut recorder {
+            if let Err(e) = recorder.flush() {
+                eprintln!("Unable to write recording: {e}");
             }
-            let peer_addr = peer_addr.unwrap();
-            eprintln!("Sending to {peer_addr}");
-            state.total_bytes_sent += send_ws_msg(&mut stream_lock, &cmd_msg, &size_msg, &grid_msg)
-                .expect("ws write to {peer_addr} to succeed");
-            state.total_messages_sent += 1;
-            if send_msg {
-                state.total_bytes_sent += send_ws_msg_text(&mut stream_lock, &log_msg).expect("ws write to {peer_addr} to succeed");
-                state.total_messages_sent += 1;
-            }
-            // let _ = stream_lock.flush();
-            eprintln!("Sent to {peer_addr}");
-            return true;
-        });
+        }
         send_msg = false;
-        next_grid();
-        state.frames += 1;
-        let diff = Duration::from_millis(MS_PER_FRAME as u64) - Instant::now().duration_since(clock);
+        if !paused {
+            next_generation(&mut state);
+        }
+        if frame_due {
+            state.frames += 1;
+            if state.frames % CHECKPOINT_INTERVAL_FRAMES == 0 {
+                write_checkpoint(&state);
+            }
+        }
+        // a frame that took longer than it should (a long step, a slow client) starts the next one right away
+        let diff = Duration::from_millis(MS_PER_FRAME as u64).saturating_sub(Instant::now().duration_since(clock));
         if diff.as_millis() > 0 {
             thread::sleep(diff);
         }
@@ -241,3 +1172,180 @@ unsafe fn run(
     end_terminal()?;
     return Ok(());
 }
+
+// Encoding name in the header of recordings whose grids carry their own encoding id. Messages of newer recordings
+// are written for the protocol version that follows the prefix, TAGGED ones for version 3
+const TAGGED_ENCODING: &str = "TAGGED";
+const RECORDING_PROTOCOL: &str = "PROTOCOL ";
+
+// Generations skipped with [ and ] while replaying
+const REPLAY_SEEK_STEP: u64 = 50;
+
+// Sends a recording to the clients instead of running the game, keeping the time between messages (scaled by the
+// replay speed)
+unsafe fn run_replay(
+    mut state: State,
+    path: &str,
+    encodings: &[Encoding],
+    streams: Clients,
+    ws_streams: Clients,
+    sse_streams: Clients,
+    api_calls: Receiver<ApiCall>,
+) -> Result<()> {
+    let replay = Replay::load(path)?;
+    if replay.width != GRID_WIDTH || replay.height != GRID_HEIGHT {
+        eprintln!(
+            "ERROR - Recording board is {}x{} but the server board is {GRID_WIDTH}x{GRID_HEIGHT}",
+            replay.width, replay.height
+        );
+        exit(1);
+    }
+    if replay.frames.is_empty() {
+        eprintln!("ERROR - Recording {path} is empty");
+        exit(1);
+    }
+    // Recordings made before grids were tagged name the one encoding they used
+    let (encoding, version) = match replay.encoding.as_str() {
+        TAGGED_ENCODING => (None, HANDSHAKE_PROTOCOL_VERSION),
+        header => match header.strip_prefix(RECORDING_PROTOCOL).map(|v| v.parse::<u8>()) {
+            Some(Ok(version)) => (None, version),
+            _ => (Some(Encoding::from_str(header)?), LEGACY_PROTOCOL_VERSION),
+        },
+    };
+
+    start_terminal()?;
+
+    let mut speed = state.replay_speed;
+    let mut idx = replay.seek(state.replay_seek);
+    let mut start = Instant::now();
+    let mut start_ms = replay.frames[idx].ms;
+
+    loop {
+        let mut restart = false;
+        if poll(Duration::from_millis((MS_PER_FRAME as f64 * 0.2) as u64))? {
+            if let Event::Key(event) = read()? {
+                match event.code {
+                    KeyCode::Char('q') => break,
+                    KeyCode::Char('+') => {
+                        speed *= 2.0;
+                        restart = true;
+                    }
+                    KeyCode::Char('-') => {
+                        speed /= 2.0;
+                        restart = true;
+                    }
+                    KeyCode::Char(']') => {
+                        idx = replay.seek(state.generation + REPLAY_SEEK_STEP);
+                        restart = true;
+                    }
+                    KeyCode::Char('[') => {
+                        idx = replay.seek(state.generation.saturating_sub(REPLAY_SEEK_STEP));
+                        restart = true;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if restart && idx < replay.frames.len() {
+            start = Instant::now();
+            start_ms = replay.frames[idx].ms;
+        }
+
+        keep_alive(&ws_streams);
+        keep_sse_alive(&sse_streams);
+        // the recording doesn't say which grids a client that reconnected missed
+        if resumed_behind(&sse_streams, None) {
+            KEYFRAME_NEEDED.store(true, Ordering::SeqCst);
+        }
+        let elapsed_ms = start.elapsed().as_millis() as f64 * speed;
+        while idx < replay.frames.len() && (replay.frames[idx].ms - start_ms) as f64 <= elapsed_ms {
+            let frame = &replay.frames[idx];
+            state.generation = frame.generation;
+            let split = split_message(&frame.message);
+            let content_len = split.as_ref().map_or(0, |(_, content)| content.len());
+            let decoded = match (encoding, split) {
+                // grids of older recordings aren't tagged with their encoding
+                (Some(encoding), Ok((CMD_NEW_GRID, cgrid))) => Ok(Message::NewGrid {
+                    generation: frame.generation,
+                    timestamp: 0,
+                    encoding,
+                    cgrid: cgrid.to_vec(),
+                }),
+                _ => Message::decode_for(&frame.message, version),
+            };
+            let label = match &decoded {
+                Ok(Message::NewGrid { encoding, .. }) => format!("{encoding:?}"),
+                _ => String::from(DELTA_LABEL),
+            };
+            // Grids are sent again in the format of each client, logs are the same in every version
+            let delta = match decoded {
+                Ok(Message::NewGrid { encoding, cgrid, .. }) => encoding.uncompress(&cgrid).map(|_| None),
+                Ok(Message::DeltaGrid { cells, .. }) => apply_grid_delta(&cells).map(|_| Some(cells)),
+                Ok(msg) => {
+                    broadcast(&mut state, &streams, &ws_streams, &sse_streams, &mut None, &msg);
+                    idx += 1;
+                    continue;
+                }
+                Err(e) => Err(e.into()),
+            };
+            let delta = match delta {
+                Ok(delta) => delta,
+                Err(e) => {
+                    eprintln!("Skipping replay frame: {e}");
+                    idx += 1;
+                    continue;
+                }
+            };
+            state.encoded_grid_lengths.push(content_len);
+            state.sent_grid_bytes += content_len;
+            observe(&mut state.grid_bytes, &label, &BYTE_BUCKETS, content_len as f64);
+            state.frames += 1;
+            let delta = if KEYFRAME_NEEDED.swap(false, Ordering::SeqCst) {
+                None
+            } else {
+                delta
+            };
+            let (msgs, _) = grid_msgs(
+                formats_in_use(&streams, &ws_streams, &sse_streams),
+                encodings,
+                legacy_encoding(encodings),
+                delta.as_deref(),
+                frame.generation,
+            );
+            send_to_clients(
+                &mut state,
+                &streams,
+                &ws_streams,
+                &sse_streams,
+                Some(frame.generation),
+                &|format| &msgs[&format],
+            );
+            idx += 1;
+        }
+
+        // the recording can be watched but not changed
+        answer_api(&api_calls, &mut |call| match call {
+            Call::State => state_response(&status(&state, false)),
+            Call::Grid(view) => grid_response(view, state.generation, &GRID),
+            Call::Metrics => metrics_response(
+                &state,
+                &status(&state, false),
+                &peer_send_seconds(&streams, &ws_streams, &sse_streams),
+            ),
+            _ => api_error(409, "the server is replaying a recording"),
+        });
+
+        render()?;
+        let position = if idx < replay.frames.len() { "" } else { " (end)" };
+        render_status(&format!(
+            "replay: generation {}{position} speed x{speed} | +/-: speed [/]: seek q: quit",
+            state.generation
+        ))?;
+        render_debug_data(true, &state, &ACTIVE_CONNECTIONS.load(Ordering::SeqCst))?;
+    }
+
+    reset_terminal()?;
+    close_ws_clients(&ws_streams, WS_CLOSE_GOING_AWAY, "Server is shutting down");
+    end_terminal()?;
+    return Ok(());
+}
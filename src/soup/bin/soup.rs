@@ -0,0 +1,156 @@
+use std::{
+    collections::HashMap,
+    env::args,
+    fs::File,
+    io::{Result, Write},
+    process::exit,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Instant,
+};
+
+use gol_multi::soup::{is_common, run_soup, SoupResult, SOUP_SIZE};
+
+fn print_usage() {
+    println!("Runs random {SOUP_SIZE}x{SOUP_SIZE} soups until they stabilise and reports the objects they leave");
+    println!();
+    println!("    --help  print this help");
+    println!("    -n  amount of soups to run (default 10000)");
+    println!("    -s  seed of the first soup, soup i uses seed + i (default 0)");
+    println!("    -t  amount of threads (default available parallelism)");
+    println!("    -o  report file (default soup_report.md)");
+}
+
+fn parse_arg<T: std::str::FromStr>(flag: &str, value: Option<String>) -> T {
+    match value.map(|v| v.parse::<T>()) {
+        Some(Ok(v)) => v,
+        _ => {
+            eprintln!("ERROR - Number expected after flag {flag}");
+            exit(1);
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let mut args = args().skip(1);
+    let mut count: u64 = 10_000;
+    let mut base_seed: u64 = 0;
+    let mut threads: usize = thread::available_parallelism().map_or(1, |n| n.get());
+    let mut report_path = String::from("soup_report.md");
+    while let Some(next) = args.next() {
+        match next.as_str() {
+            "--help" => {
+                print_usage();
+                exit(0);
+            }
+            "-n" => count = parse_arg("-n", args.next()),
+            "-s" => base_seed = parse_arg("-s", args.next()),
+            "-t" => threads = parse_arg::<usize>("-t", args.next()).max(1),
+            "-o" => match args.next() {
+                Some(o) => report_path = o,
+                None => {
+                    eprintln!("ERROR - File expected after flag -o");
+                    exit(1);
+                }
+            },
+            _ => {
+                eprintln!("Unrecognized arg: {}", next);
+                print_usage();
+                exit(1)
+            }
+        }
+    }
+
+    let start = Instant::now();
+    let next_soup = Arc::new(AtomicU64::new(0));
+    let (tx, rx) = mpsc::channel::<SoupResult>();
+    for _ in 0..threads {
+        let next_soup = Arc::clone(&next_soup);
+        let tx = tx.clone();
+        thread::spawn(move || loop {
+            let i = next_soup.fetch_add(1, Ordering::Relaxed);
+            if i >= count {
+                break;
+            }
+            if tx.send(run_soup(base_seed.wrapping_add(i))).is_err() {
+                break;
+            }
+        });
+    }
+    drop(tx);
+
+    let mut census: HashMap<String, usize> = HashMap::new();
+    let mut rare: HashMap<(String, u64), usize> = HashMap::new();
+    let mut unstable: Vec<u64> = Vec::new();
+    let mut escaped = 0;
+    let mut done = 0;
+    for result in rx {
+        for object in result.objects {
+            if !is_common(&object) {
+                *rare.entry((object.clone(), result.seed)).or_insert(0) += 1;
+            }
+            *census.entry(object).or_insert(0) += 1;
+        }
+        if result.stabilised_at.is_none() {
+            unstable.push(result.seed);
+        }
+        escaped += result.escaped;
+        done += 1;
+        if done % 1000 == 0 {
+            eprintln!("{done}/{count} soups");
+        }
+    }
+
+    let mut census: Vec<(String, usize)> = census.into_iter().collect();
+    census.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let mut rare: Vec<((String, u64), usize)> = rare.into_iter().collect();
+    rare.sort();
+    unstable.sort();
+
+    let mut report = File::create(&report_path)?;
+    writeln!(report, "# Soup search report")?;
+    writeln!(report)?;
+    writeln!(report, "- soups: {done} ({SOUP_SIZE}x{SOUP_SIZE})")?;
+    writeln!(report, "- seeds: {base_seed}..{}", base_seed.wrapping_add(count))?;
+    writeln!(report, "- threads: {threads}")?;
+    writeln!(report, "- time: {:.2}s", start.elapsed().as_secs_f64())?;
+    writeln!(report, "- escaped objects: {escaped}")?;
+    writeln!(report)?;
+    writeln!(
+        report,
+        "Reproduce a soup with `cargo run --release --bin soup -- -n 1 -s <seed>`"
+    )?;
+    writeln!(report)?;
+    writeln!(report, "## Census")?;
+    writeln!(report)?;
+    writeln!(report, "|object|count|")?;
+    writeln!(report, "|---|---|")?;
+    for (object, n) in &census {
+        writeln!(report, "|{object}|{n}|")?;
+    }
+    writeln!(report)?;
+    writeln!(report, "## Rare objects")?;
+    writeln!(report)?;
+    writeln!(report, "|object|seed|count|")?;
+    writeln!(report, "|---|---|---|")?;
+    for ((object, seed), n) in &rare {
+        writeln!(report, "|{object}|{seed}|{n}|")?;
+    }
+    if !unstable.is_empty() {
+        writeln!(report)?;
+        writeln!(report, "## Did not stabilise")?;
+        writeln!(report)?;
+        for seed in &unstable {
+            writeln!(report, "- {seed}")?;
+        }
+    }
+
+    println!(
+        "{done} soups, {} rare objects, report written to {report_path}",
+        rare.len()
+    );
+    Ok(())
+}
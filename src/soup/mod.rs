@@ -0,0 +1,420 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use crate::game::step;
+use crate::pattern::Pattern;
+
+// A soup is a SOUP_SIZE x SOUP_SIZE random square placed in the middle of a larger universe. The universe is
+// big enough for most soups to settle down before reaching the edges, and anything that does reach them (mostly
+// gliders) is erased so it doesn't wrap around and crash into the ash.
+pub const SOUP_SIZE: usize = 16;
+pub const UNIVERSE_SIZE: usize = 96;
+pub const MAX_GENERATIONS: usize = 6000;
+pub const MAX_PERIOD: usize = 60;
+
+// Cells closer than BORDER to the edge are killed every generation. Objects that end up closer than
+// BORDER + MARGIN are considered debris of something that escaped and are not classified.
+const BORDER: usize = 2;
+const MARGIN: usize = 2;
+
+// Objects that show up in almost every soup. Anything else is reported as rare.
+pub const COMMON_OBJECTS: [&str; 10] = [
+    "xs4_33",   // block
+    "xp2_7",    // blinker
+    "xs6_696",  // beehive
+    "xs7_2596", // loaf
+    "xs5_253",  // boat
+    "xs8_6996", // pond
+    "xs6_356",  // ship
+    "xs4_252",  // tub
+    "xp2_7e",   // toad
+    "xp2_318c", // beacon
+];
+
+pub fn is_common(apgcode: &str) -> bool {
+    return COMMON_OBJECTS.contains(&apgcode);
+}
+
+/// xorshift64* generator. Good enough for soups and fully determined by its seed, which is what makes a soup
+/// reproducible.
+pub struct SoupRng {
+    state: u64,
+}
+
+impl SoupRng {
+    pub fn new(seed: u64) -> SoupRng {
+        // xorshift gets stuck on 0, so mix the seed first (splitmix64 finalizer)
+        let mut z = seed.wrapping_add(0x9E3779B97F4A7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^= z >> 31;
+        return SoupRng {
+            state: if z == 0 { 1 } else { z },
+        };
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        return self.state.wrapping_mul(0x2545F4914F6CDD1D);
+    }
+}
+
+/// Generates the SOUP_SIZE x SOUP_SIZE soup for `seed`, one byte per cell.
+pub fn generate_soup(seed: u64) -> Vec<u8> {
+    let mut rng = SoupRng::new(seed);
+    let mut soup = vec![0; SOUP_SIZE * SOUP_SIZE];
+    let mut bits = 0;
+    let mut random = 0;
+    for cell in soup.iter_mut() {
+        if bits == 0 {
+            random = rng.next_u64();
+            bits = 64;
+        }
+        *cell = (random & 1) as u8;
+        random >>= 1;
+        bits -= 1;
+    }
+    return soup;
+}
+
+#[derive(Debug)]
+pub struct SoupResult {
+    pub seed: u64,
+    // generation at which the ash became periodic, None if it didn't within MAX_GENERATIONS
+    pub stabilised_at: Option<usize>,
+    pub period: usize,
+    pub objects: Vec<String>,
+    pub escaped: usize,
+}
+
+/// Runs the soup for `seed` until it stabilises and classifies the resulting ash.
+pub fn run_soup(seed: u64) -> SoupResult {
+    let mut cells = vec![0; UNIVERSE_SIZE * UNIVERSE_SIZE];
+    let mut next = vec![0; UNIVERSE_SIZE * UNIVERSE_SIZE];
+    let offset = (UNIVERSE_SIZE - SOUP_SIZE) / 2;
+    for (i, cell) in generate_soup(seed).into_iter().enumerate() {
+        cells[(offset + i % SOUP_SIZE) + UNIVERSE_SIZE * (offset + i / SOUP_SIZE)] = cell;
+    }
+
+    let mut seen: HashMap<u64, usize> = HashMap::new();
+    let mut stabilised_at = None;
+    let mut period = 0;
+    for generation in 0..MAX_GENERATIONS {
+        let hash = hash_cells(&cells);
+        if let Some(previous) = seen.insert(hash, generation) {
+            if generation - previous <= MAX_PERIOD {
+                stabilised_at = Some(previous);
+                period = generation - previous;
+                break;
+            }
+        }
+        step(&cells, &mut next, UNIVERSE_SIZE, UNIVERSE_SIZE);
+        kill_border(&mut next);
+        std::mem::swap(&mut cells, &mut next);
+    }
+
+    // Oscillators can be split in several pieces in some phases (e.g. beacon), so objects are told apart using
+    // every cell that is alive at some point of the period
+    let mut footprint = cells.clone();
+    let mut phase = cells.clone();
+    for _ in 1..period {
+        step(&phase, &mut next, UNIVERSE_SIZE, UNIVERSE_SIZE);
+        kill_border(&mut next);
+        std::mem::swap(&mut phase, &mut next);
+        footprint.iter_mut().zip(phase.iter()).for_each(|(f, c)| *f |= c);
+    }
+
+    let (objects, escaped) = census(&cells, &footprint, UNIVERSE_SIZE, UNIVERSE_SIZE);
+    return SoupResult {
+        seed,
+        stabilised_at,
+        period,
+        objects,
+        escaped,
+    };
+}
+
+fn hash_cells(cells: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    cells.hash(&mut hasher);
+    return hasher.finish();
+}
+
+fn kill_border(cells: &mut [u8]) {
+    for y in 0..UNIVERSE_SIZE {
+        for x in 0..UNIVERSE_SIZE {
+            if x < BORDER || y < BORDER || x >= UNIVERSE_SIZE - BORDER || y >= UNIVERSE_SIZE - BORDER {
+                cells[x + UNIVERSE_SIZE * y] = 0;
+            }
+        }
+    }
+}
+
+/// Splits the board into objects (8-connected areas of `footprint`) and names each one by its apgcode. Returns
+/// the object names and how many objects were too close to the edge to be classified.
+pub fn census(cells: &[u8], footprint: &[u8], width: usize, height: usize) -> (Vec<String>, usize) {
+    let limit = BORDER + MARGIN;
+    let mut labels: Vec<Option<usize>> = vec![None; width * height];
+    let mut components: Vec<Vec<(usize, usize)>> = Vec::new();
+    for start in 0..(width * height) {
+        if footprint[start] == 1 && labels[start].is_none() {
+            let label = components.len();
+            components.push(flood(footprint, width, height, start, 1, |idx| {
+                if labels[idx].is_none() {
+                    labels[idx] = Some(label);
+                    return true;
+                }
+                return false;
+            }));
+        }
+    }
+
+    let mut names: Vec<Option<String>> = components
+        .iter()
+        .map(|component| {
+            let near_edge = component
+                .iter()
+                .any(|&(x, y)| x < limit || y < limit || x >= width - limit || y >= height - limit);
+            if near_edge {
+                return None;
+            }
+            // empty names are dropped at the end, an object can be all dead cells in the current phase
+            let live = alive(cells, width, component);
+            if live.is_empty() {
+                return Some(String::new());
+            }
+            return Some(classify(&live));
+        })
+        .collect();
+    let mut escaped = names.iter().filter(|name| name.is_none()).count();
+
+    // Objects one cell apart still interact, so something that doesn't repeat on its own is probably being
+    // held in place by a neighbour. Merge everything within 2 cells of it and try again as a single object.
+    for label in 0..components.len() {
+        if !names[label].as_ref().is_some_and(|name| name.starts_with("unknown")) {
+            continue;
+        }
+        let mut merged_labels = vec![label];
+        let start = components[label][0].0 + width * components[label][0].1;
+        let mut visited = vec![false; width * height];
+        let merged = flood(footprint, width, height, start, 2, |idx| {
+            if visited[idx] {
+                return false;
+            }
+            visited[idx] = true;
+            if let Some(l) = labels[idx] {
+                if !merged_labels.contains(&l) {
+                    merged_labels.push(l);
+                }
+            }
+            return true;
+        });
+        if merged_labels.len() == 1 {
+            continue;
+        }
+        let near_edge = merged_labels.iter().any(|&l| names[l].is_none());
+        for &l in &merged_labels {
+            if names[l].is_none() {
+                escaped -= 1;
+            }
+            names[l] = Some(String::new());
+        }
+        if near_edge {
+            names[label] = None;
+            escaped += 1;
+        } else {
+            let live = alive(cells, width, &merged);
+            names[label] = Some(if live.is_empty() {
+                String::new()
+            } else {
+                classify(&live)
+            });
+        }
+    }
+
+    let objects = names.into_iter().flatten().filter(|name| !name.is_empty()).collect();
+    return (objects, escaped);
+}
+
+// Collects every footprint cell reachable from `start` jumping at most `radius` cells at a time. `visit` marks
+// the cell and returns false if it was already visited.
+fn flood(
+    footprint: &[u8],
+    width: usize,
+    height: usize,
+    start: usize,
+    radius: usize,
+    mut visit: impl FnMut(usize) -> bool,
+) -> Vec<(usize, usize)> {
+    let mut component = Vec::new();
+    let mut pending = vec![start];
+    visit(start);
+    while let Some(idx) = pending.pop() {
+        let (x, y) = (idx % width, idx / width);
+        component.push((x, y));
+        for ny in y.saturating_sub(radius)..=(y + radius).min(height - 1) {
+            for nx in x.saturating_sub(radius)..=(x + radius).min(width - 1) {
+                let n = nx + width * ny;
+                if footprint[n] == 1 && visit(n) {
+                    pending.push(n);
+                }
+            }
+        }
+    }
+    return component;
+}
+
+fn alive(cells: &[u8], width: usize, component: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    return component
+        .iter()
+        .filter(|&&(x, y)| cells[x + width * y] == 1)
+        .copied()
+        .collect();
+}
+
+/// Names a single object with its apgcode (`xs<population>_<code>` for still lifes, `xp<period>_<code>` for
+/// oscillators). The object is simulated on its own, so anything that doesn't repeat is reported as
+/// `unknown_<population>`.
+pub fn classify(cells: &[(usize, usize)]) -> String {
+    const PADDING: usize = 4;
+    let min_x = cells.iter().map(|c| c.0).min().unwrap_or(0);
+    let min_y = cells.iter().map(|c| c.1).min().unwrap_or(0);
+    let max_x = cells.iter().map(|c| c.0).max().unwrap_or(0);
+    let max_y = cells.iter().map(|c| c.1).max().unwrap_or(0);
+    let width = max_x - min_x + 1 + 2 * PADDING;
+    let height = max_y - min_y + 1 + 2 * PADDING;
+
+    let mut phase = vec![0; width * height];
+    for &(x, y) in cells {
+        phase[(x - min_x + PADDING) + width * (y - min_y + PADDING)] = 1;
+    }
+    let initial = phase.clone();
+    let mut next = vec![0; width * height];
+    let mut codes = vec![canonical_code(&phase, width, height)];
+    let mut period = None;
+    for generation in 1..=MAX_PERIOD {
+        step(&phase, &mut next, width, height);
+        std::mem::swap(&mut phase, &mut next);
+        if phase == initial {
+            period = Some(generation);
+            break;
+        }
+        codes.push(canonical_code(&phase, width, height));
+    }
+
+    return match period {
+        Some(1) => format!("xs{}_{}", cells.len(), codes[0]),
+        Some(p) => format!("xp{}_{}", p, pick_code(codes)),
+        None => format!("unknown_{}", cells.len()),
+    };
+}
+
+// Shortest first, then lexicographically smallest
+fn pick_code(codes: Vec<String>) -> String {
+    return codes
+        .into_iter()
+        .min_by(|a, b| a.len().cmp(&b.len()).then_with(|| a.cmp(b)))
+        .unwrap_or_default();
+}
+
+fn canonical_code(cells: &[u8], width: usize, height: usize) -> String {
+    let pattern = Pattern::from_board(cells, width, height);
+    let codes = pattern.orientations().iter().map(|p| wechsler(&p.crop())).collect();
+    return pick_code(codes);
+}
+
+const WECHSLER_DIGITS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+/// Extended Wechsler format of a cropped pattern: the object is cut in strips of 5 rows, each column of a strip
+/// becomes a base-32 digit, strips are separated by `z` and runs of zero columns are shortened to `w` (2), `x` (3)
+/// and `y?` (4+).
+fn wechsler(pattern: &Pattern) -> String {
+    let mut strips = vec![vec![0u8; pattern.width()]; pattern.height().div_ceil(5)];
+    for &(x, y) in pattern.cells() {
+        let (x, y) = (x as usize, y as usize);
+        strips[y / 5][x] |= 1 << (y % 5);
+    }
+
+    let mut code = String::new();
+    for (i, strip) in strips.iter().enumerate() {
+        if i > 0 {
+            code.push('z');
+        }
+        let used = strip.iter().rposition(|&c| c != 0).map_or(0, |p| p + 1);
+        let mut zeros = 0;
+        for &column in &strip[..used] {
+            if column == 0 {
+                zeros += 1;
+                continue;
+            }
+            push_zeros(&mut code, zeros);
+            zeros = 0;
+            code.push(WECHSLER_DIGITS[column as usize] as char);
+        }
+    }
+    return code;
+}
+
+fn push_zeros(code: &mut String, mut zeros: usize) {
+    while zeros > 0 {
+        match zeros {
+            1 => code.push('0'),
+            2 => code.push('w'),
+            3 => code.push('x'),
+            _ => {
+                let run = zeros.min(39);
+                code.push('y');
+                code.push(WECHSLER_DIGITS[run - 4] as char);
+                zeros -= run;
+                continue;
+            }
+        }
+        return;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::soup::{classify, generate_soup, is_common, run_soup};
+
+    fn cells(rows: &[&str]) -> Vec<(usize, usize)> {
+        let mut cells = Vec::new();
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                if c == 'o' {
+                    cells.push((x + 10, y + 10));
+                }
+            }
+        }
+        return cells;
+    }
+
+    #[test]
+    fn test_classify_common_objects() {
+        assert_eq!(classify(&cells(&["oo", "oo"])), "xs4_33");
+        assert_eq!(classify(&cells(&["ooo"])), "xp2_7");
+        assert_eq!(classify(&cells(&[".oo.", "o..o", ".oo."])), "xs6_696");
+        assert_eq!(classify(&cells(&["oo.", "o.o", ".o."])), "xs5_253");
+        assert_eq!(classify(&cells(&[".o.", "o.o", ".o."])), "xs4_252");
+        assert_eq!(classify(&cells(&[".oo.", "o..o", ".o.o", "..o."])), "xs7_2596");
+        assert_eq!(classify(&cells(&[".oo.", "o..o", "o..o", ".oo."])), "xs8_6996");
+        assert_eq!(classify(&cells(&["oo.", "o.o", ".oo"])), "xs6_356");
+        assert_eq!(classify(&cells(&[".ooo", "ooo."])), "xp2_7e");
+        assert_eq!(classify(&cells(&["oo..", "oo..", "..oo", "..oo"])), "xp2_318c");
+        assert!(is_common(&classify(&cells(&["oo", "oo"]))));
+    }
+
+    #[test]
+    fn test_soup_is_reproducible() {
+        assert_eq!(generate_soup(42), generate_soup(42));
+        assert_ne!(generate_soup(42), generate_soup(43));
+
+        let first = run_soup(7);
+        let second = run_soup(7);
+        assert_eq!(first.objects, second.objects);
+        assert_eq!(first.stabilised_at, second.stabilised_at);
+    }
+}
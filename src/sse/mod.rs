@@ -0,0 +1,138 @@
+use base64::{engine::general_purpose, Engine};
+
+use crate::http::{HttpError, Request};
+use crate::net::{CMD_DELTA_GRID, CMD_LOG_MSG, CMD_NEW_GRID, ENCODINGS};
+use crate::protocol::{Handshake, CAP_DELTA_GRID, PROTOCOL_VERSION};
+
+// Server-Sent Events (https://html.spec.whatwg.org/multipage/server-sent-events.html) on the WebSocket port, for
+// dashboards that can't open a WebSocket. GET /events answers with a text/event-stream that goes on until either
+// side closes the connection, carrying the messages web clients get in their binary frames, base64 encoded:
+//
+//   event: grid
+//   id: 1234
+//   data: AAMBAOCU...
+//
+// New and delta grids are `grid` events with their generation as the id, logs are `log` events without one. A
+// browser that reconnects sends the id of the last event it got as Last-Event-ID, and the server only starts it
+// over with a full grid if it missed a generation.
+//
+// Messages are written for the current protocol version, without CRC-32s, in the smallest encoding of every frame
+// and with delta grids. `?encoding=<name>` forces an encoding and `?deltas=0` asks for full grids only.
+
+pub const SSE_PATH: &str = "/events";
+// How long browsers wait before reconnecting
+pub const SSE_RETRY_MS: u64 = 1000;
+// Comment line sent while there are no events, so proxies don't time the stream out and dead clients are noticed
+pub const SSE_KEEP_ALIVE: &[u8] = b": keep-alive\n\n";
+
+/// Head of the response. It has no length, the connection closing ends it.
+pub fn response_head() -> Vec<u8> {
+    return format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-store\r\nConnection: close\r\n\
+        Access-Control-Allow-Origin: *\r\n\r\nretry: {SSE_RETRY_MS}\n\n"
+    )
+    .into_bytes();
+}
+
+/// The event carrying a whole protocol message, with `id` if it's a grid.
+pub fn event(msg: &[u8], id: Option<u64>) -> Vec<u8> {
+    let name = match msg.first() {
+        Some(&CMD_NEW_GRID | &CMD_DELTA_GRID) => "grid",
+        Some(&CMD_LOG_MSG) => "log",
+        _ => "message",
+    };
+    let mut event = format!("event: {name}\n");
+    if let Some(id) = id {
+        event.push_str(&format!("id: {id}\n"));
+    }
+    event.push_str(&format!("data: {}\n\n", general_purpose::STANDARD.encode(msg)));
+    return event.into_bytes();
+}
+
+/// Generation of the last grid a reconnecting client got, None for a new client or an id that isn't one.
+pub fn last_event_id(request: &Request) -> Option<u64> {
+    return request.header("last-event-id")?.trim().parse().ok();
+}
+
+/// What an event stream client gets, from its query. SSE clients don't say hello.
+pub fn sse_handshake(request: &Request) -> Result<Handshake, HttpError> {
+    let bad_request = |reason| HttpError { status: 400, reason };
+    let encoding = match request.query_param("encoding") {
+        // Encoding::from_str falls back to the default for unknown names
+        Some(name) => Some(
+            *ENCODINGS
+                .iter()
+                .find(|encoding| format!("{encoding:?}") == name)
+                .ok_or(bad_request("unknown encoding"))?,
+        ),
+        None => None,
+    };
+    let capabilities = match request.query_param("deltas") {
+        None | Some("1") => CAP_DELTA_GRID,
+        Some("0") => 0,
+        Some(_) => return Err(bad_request("deltas must be 0 or 1")),
+    };
+    return Ok(Handshake {
+        version: PROTOCOL_VERSION,
+        capabilities,
+        encoding,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose, Engine};
+
+    use crate::http::parse_request;
+    use crate::net::Encoding;
+    use crate::protocol::{Message, CAP_DELTA_GRID, PROTOCOL_VERSION};
+    use crate::sse::{event, last_event_id, sse_handshake};
+
+    #[test]
+    fn test_event() {
+        let delta = Message::DeltaGrid {
+            generation: 1234,
+            timestamp: 0,
+            cells: vec![1, 2],
+        }
+        .encode();
+        let text = String::from_utf8(event(&delta, Some(1234))).unwrap();
+        let data = text
+            .strip_prefix("event: grid\nid: 1234\ndata: ")
+            .unwrap()
+            .strip_suffix("\n\n")
+            .unwrap();
+        assert_eq!(general_purpose::STANDARD.decode(data).unwrap(), delta);
+
+        let log = Message::Log(String::from("hi")).encode();
+        assert!(String::from_utf8(event(&log, None))
+            .unwrap()
+            .starts_with("event: log\ndata: "));
+    }
+
+    #[test]
+    fn test_sse_request() {
+        let request = |target: &str, headers: &str| {
+            parse_request(&format!("GET {target} HTTP/1.1\r\nHost: x\r\n{headers}\r\n")).unwrap()
+        };
+        assert_eq!(last_event_id(&request("/events", "Last-Event-ID: 42\r\n")), Some(42));
+        assert_eq!(last_event_id(&request("/events", "Last-Event-ID: x\r\n")), None);
+        assert_eq!(last_event_id(&request("/events", "")), None);
+
+        let handshake = sse_handshake(&request("/events", "")).unwrap();
+        assert_eq!(
+            (handshake.version, handshake.capabilities, handshake.encoding),
+            (PROTOCOL_VERSION, CAP_DELTA_GRID, None)
+        );
+        let handshake = sse_handshake(&request("/events?encoding=VRLE&deltas=0", "")).unwrap();
+        assert_eq!((handshake.capabilities, handshake.encoding), (0, Some(Encoding::VRLE)));
+        assert_eq!(
+            sse_handshake(&request("/events?encoding=PNG", "")).unwrap_err().status,
+            400
+        );
+        assert_eq!(
+            sse_handshake(&request("/events?deltas=yes", "")).unwrap_err().status,
+            400
+        );
+    }
+}
@@ -106,6 +106,40 @@ pub unsafe fn render() -> Result<()> {
     Ok(())
 }
 
+/// Draws the operator cursor and the selected rectangle (x, y, w, h) on top of the board.
+pub unsafe fn render_selection(cursor: (usize, usize), selection: Option<(usize, usize, usize, usize)>) -> Result<()> {
+    let mut stdout = stdout();
+    if let Some((sx, sy, w, h)) = selection {
+        for y in sy..(sy + h) {
+            for x in sx..(sx + w) {
+                stdout.queue(MoveTo((x * 2) as u16, y as u16))?;
+                let bg = if GRID[x + GRID_WIDTH * y] == 1 { CYAN_BG } else { BLUE_BG };
+                stdout.write_all(bg.as_bytes())?;
+                stdout.write_all(CELL.as_bytes())?;
+                stdout.write_all(RST.as_bytes())?;
+            }
+        }
+    }
+    let (x, y) = cursor;
+    stdout.queue(MoveTo((x * 2) as u16, y as u16))?;
+    let bg = if GRID[x + GRID_WIDTH * y] == 1 { BRIGHT_YELLOW_BG } else { YELLOW_BG };
+    stdout.write_all(bg.as_bytes())?;
+    stdout.write_all(CELL.as_bytes())?;
+    stdout.write_all(RST.as_bytes())?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Draws a line of text right under the board.
+pub fn render_status(status: &str) -> Result<()> {
+    let mut stdout = stdout();
+    stdout.queue(MoveTo(1, (GRID_HEIGHT + 1) as u16))?;
+    stdout.queue(Clear(ClearType::UntilNewLine))?;
+    stdout.write_all(status.as_bytes())?;
+    stdout.flush()?;
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct DebugData {
     pub active_connections: u64,
@@ -146,6 +180,18 @@ pub unsafe fn render_debug_data(active: bool, state: &State, active_connections:
             .as_bytes(),
         )?;
     }
+    if state.full_grid_bytes > 0 {
+        let sent: usize = state.encoded_grid_lengths.iter().sum();
+        stdout.queue(MoveTo(1 as u16, (GRID_HEIGHT + 10) as u16))?;
+        stdout.write_all(
+            format!(
+                "delta_savings: {} B ({:.1}%)",
+                state.full_grid_bytes.saturating_sub(sent),
+                100.0 * (1.0 - sent as f64 / state.full_grid_bytes as f64)
+            )
+            .as_bytes(),
+        )?;
+    }
     eprintln!("encoded_grid_lengths: {:?}", state.encoded_grid_lengths);
     Ok(())
 }